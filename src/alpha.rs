@@ -0,0 +1,81 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use log::debug;
+
+/// Side length of the checkerboard cells, in pixels.
+const CHECKER_SIZE: u32 = 8;
+/// The two shades of the checkerboard.
+const CHECKER_LIGHT: u8 = 0xFF;
+const CHECKER_DARK: u8 = 0xCC;
+
+/// What happens to the alpha channel of thumbnails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlphaPolicy {
+    /// Keep transparency; thumbnails are RGBA.
+    #[default]
+    Preserve,
+    /// Composite onto a solid RGB colour; thumbnails are RGB.
+    Flatten([u8; 3]),
+    /// Composite onto a light grey checkerboard, the usual way of showing
+    /// transparency; thumbnails are RGB.
+    Checkerboard,
+}
+
+impl AlphaPolicy {
+    /// The name used in the cache directory of this policy, or `None` for
+    /// `AlphaPolicy::Preserve`, whose thumbnails follow the spec.
+    pub fn cache_name(&self) -> Option<String> {
+        match self {
+            AlphaPolicy::Preserve => None,
+            AlphaPolicy::Flatten([r, g, b]) => Some(format!("flat{:02x}{:02x}{:02x}", r, g, b)),
+            AlphaPolicy::Checkerboard => Some("checker".to_string()),
+        }
+    }
+}
+
+/// Applies `policy` to `img`. Images without an alpha channel are returned
+/// unchanged, as is everything under `AlphaPolicy::Preserve`.
+pub fn apply_alpha_policy(img: DynamicImage, policy: AlphaPolicy) -> DynamicImage {
+    if policy == AlphaPolicy::Preserve || !img.color().has_alpha() {
+        return img;
+    }
+
+    debug!("Compositing {}x{} image with {:?}", img.width(), img.height(), policy);
+    let src = img.into_rgba8();
+    let out = RgbImage::from_fn(src.width(), src.height(), |x, y| {
+        let background = match policy {
+            AlphaPolicy::Flatten(color) => color,
+            _ if (x / CHECKER_SIZE + y / CHECKER_SIZE) % 2 == 0 => [CHECKER_LIGHT; 3],
+            _ => [CHECKER_DARK; 3],
+        };
+        let p = src.get_pixel(x, y);
+        let alpha = p[3] as u32;
+        let blend = |c: usize| ((p[c] as u32 * alpha + background[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+        Rgb([blend(0), blend(1), blend(2)])
+    });
+    DynamicImage::ImageRgb8(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, Rgba, RgbaImage};
+
+    use super::{apply_alpha_policy, AlphaPolicy};
+
+    #[test]
+    fn test_alpha_policies() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, _| {
+            if x < 8 { Rgba([255, 0, 0, 255]) } else { Rgba([255, 0, 0, 0]) }
+        }));
+
+        let preserved = apply_alpha_policy(img.clone(), AlphaPolicy::Preserve);
+        assert!(preserved.color().has_alpha());
+
+        let flat = apply_alpha_policy(img.clone(), AlphaPolicy::Flatten([10, 20, 30])).into_rgb8();
+        assert_eq!(*flat.get_pixel(0, 0), Rgb([255, 0, 0]));
+        assert_eq!(*flat.get_pixel(12, 0), Rgb([10, 20, 30]));
+
+        let checker = apply_alpha_policy(img, AlphaPolicy::Checkerboard).into_rgb8();
+        assert_eq!(*checker.get_pixel(8, 0), Rgb([0xCC; 3]));
+        assert_eq!(*checker.get_pixel(8, 8), Rgb([0xFF; 3]));
+    }
+}
@@ -0,0 +1,268 @@
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageFormat, Limits, RgbaImage,
+};
+use log::{debug, warn};
+use std::io::BufReader;
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, ReadSeek, Source},
+};
+
+/// Frames beyond this are not decoded, and animations longer than this get
+/// no frame count.
+const MAX_FRAMES: usize = 1000;
+
+/// The composited frames of an animation and its embedded ICC profile.
+type Animation<'a> = (Frames<'a>, Option<Vec<u8>>);
+
+/// Which frame of an animated image becomes the thumbnail.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FramePolicy {
+    /// The first frame.
+    #[default]
+    First,
+    /// The frame at the given position, from 0.0 (first) to 100.0 (last).
+    AtPercent(f32),
+    /// The frame with the highest luminance entropy, which skips blank or
+    /// faded-in opening frames.
+    MostDetailed,
+}
+
+/// Returns true for formats that may contain an animation.
+pub(crate) fn is_animatable(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP)
+}
+
+/// Decodes an animated GIF, APNG or WebP and returns the frame chosen by the
+/// context's `FramePolicy`, composited onto the full canvas.
+///
+/// Returns `None` for PNG and WebP files without an animation, which are
+/// decoded as still images instead. The frame count of animations of up to
+/// `MAX_FRAMES` frames is recorded as the `Thumb::X-Frames` text chunk.
+///
+/// `FramePolicy::AtPercent` needs the frame count up front, so it decodes the
+/// frames once to count them and again up to the selected one.
+pub(crate) fn decode_animated(
+    source: &mut Source<'_>,
+    format: ImageFormat,
+    ctx: &mut GenerateContext,
+) -> Result<Option<DynamicImage>, ThumbnailError> {
+    let limits = ctx.limits().to_image_limits();
+    let policy = ctx.frame_policy();
+
+    let (target, mut total) = match policy {
+        FramePolicy::AtPercent(percent) => {
+            let Some((frames, _)) = open_frames(source.reader()?, format, limits.clone())? else {
+                return Ok(None);
+            };
+            let total = frames.take(MAX_FRAMES + 1).take_while(|f| f.is_ok()).count();
+            (Some(frame_at_percent(total.min(MAX_FRAMES), percent)), Some(total))
+        }
+        _ => (None, None),
+    };
+
+    let Some((mut frames, icc_profile)) = open_frames(source.reader()?, format, limits)? else {
+        return Ok(None);
+    };
+
+    let mut count = 0;
+    let mut selected: Option<(f64, RgbaImage)> = None;
+    for frame in frames.by_ref().take(MAX_FRAMES) {
+        let frame = match frame {
+            Ok(frame) => frame.into_buffer(),
+            Err(e) if count == 0 => return Err(e.into()),
+            Err(e) => {
+                warn!("Stopping at frame {} of animation: {}", count, e);
+                break;
+            }
+        };
+
+        let score = match policy {
+            FramePolicy::First => (count == 0).then_some(0.0),
+            FramePolicy::AtPercent(_) => (Some(count) == target).then_some(0.0),
+            FramePolicy::MostDetailed => Some(luminance_entropy(&frame)),
+        };
+        if let Some(score) = score {
+            if selected.as_ref().is_none_or(|(best, _)| score > *best) {
+                selected = Some((score, frame));
+            }
+        }
+        count += 1;
+        if target.is_some_and(|target| count > target) {
+            break;
+        }
+    }
+    if total.is_none() && (count < MAX_FRAMES || !matches!(frames.next(), Some(Ok(_)))) {
+        total = Some(count);
+    }
+
+    let Some((_, frame)) = selected else {
+        return Err(ThumbnailError::Unsupported("animation has no frames".to_string()));
+    };
+    debug!("Selected frame with {:?} out of {:?} frames", policy, total);
+
+    if let Some(total) = total.filter(|total| (2..=MAX_FRAMES).contains(total)) {
+        ctx.add_text("Thumb::X-Frames", total.to_string());
+    }
+    if let Some(profile) = icc_profile {
+        ctx.set_icc_profile(profile);
+    }
+    Ok(Some(DynamicImage::ImageRgba8(frame)))
+}
+
+/// Opens the frame iterator of an animated image, together with its ICC
+/// profile. The decoders composite each frame according to its disposal and
+/// blend operations.
+fn open_frames<'a>(
+    reader: Box<dyn ReadSeek + 'a>,
+    format: ImageFormat,
+    limits: Limits,
+) -> Result<Option<Animation<'a>>, ThumbnailError> {
+    let reader = BufReader::new(reader);
+    match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(reader)?;
+            decoder.set_limits(limits)?;
+            let icc_profile = decoder.icc_profile()?;
+            Ok(Some((decoder.into_frames(), icc_profile)))
+        }
+        ImageFormat::Png => {
+            let mut decoder = PngDecoder::with_limits(reader, limits)?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            let icc_profile = decoder.icc_profile()?;
+            Ok(Some((decoder.apng()?.into_frames(), icc_profile)))
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(limits)?;
+            let icc_profile = decoder.icc_profile()?;
+            Ok(Some((decoder.into_frames(), icc_profile)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Maps a percentage onto a frame index of an animation with `total` frames.
+fn frame_at_percent(total: usize, percent: f32) -> usize {
+    let last = total.saturating_sub(1);
+    ((percent.clamp(0.0, 100.0) / 100.0 * last as f32).round() as usize).min(last)
+}
+
+/// Shannon entropy of the luminance histogram of the opaque pixels, in bits.
+fn luminance_entropy(frame: &RgbaImage) -> f64 {
+    let mut histogram = [0u64; 256];
+    let mut total = 0u64;
+    for p in frame.pixels().filter(|p| p[3] > 0) {
+        let luma = (p[0] as u32 * 77 + p[1] as u32 * 150 + p[2] as u32 * 29) >> 8;
+        histogram[luma as usize] += 1;
+        total += 1;
+    }
+    if total == 0 {
+        return 0.0;
+    }
+
+    histogram
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        codecs::gif::GifEncoder, Delay, DynamicImage, Frame, GenericImageView, Rgba, RgbaImage,
+    };
+    use std::io::Cursor;
+
+    use super::{frame_at_percent, FramePolicy, MAX_FRAMES};
+    use crate::generators::{image::ImageGenerator, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    fn gif(frames: impl IntoIterator<Item = RgbaImage>) -> Vec<u8> {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for img in frames {
+                encoder
+                    .encode_frame(Frame::from_parts(img, 0, 0, Delay::from_numer_denom_ms(100, 1)))
+                    .unwrap();
+            }
+        }
+        gif
+    }
+
+    fn animated_gif() -> Vec<u8> {
+        let blank = RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 255]));
+        let detailed = RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * 16) as u8, (y * 16) as u8, 128, 255]));
+        let white = RgbaImage::from_pixel(16, 16, Rgba([255, 255, 255, 255]));
+        gif([blank, detailed, white])
+    }
+
+    fn frame_count(ctx: &GenerateContext) -> Option<&str> {
+        ctx.text()
+            .iter()
+            .find(|(keyword, _)| keyword == "Thumb::X-Frames")
+            .map(|(_, text)| text.as_str())
+    }
+
+    fn generate(gif: &[u8], policy: FramePolicy) -> (DynamicImage, GenerateContext) {
+        let mut cursor = Cursor::new(gif.to_vec());
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "image/gif").with_frame_policy(policy);
+        let img = ImageGenerator.generate(Source::Reader(&mut cursor), &mut ctx).unwrap();
+        (img, ctx)
+    }
+
+    #[test]
+    fn test_frame_policies() {
+        let gif = animated_gif();
+
+        let (img, ctx) = generate(&gif, FramePolicy::First);
+        assert_eq!(img.get_pixel(8, 8), Rgba([0, 0, 0, 255]));
+        assert!(ctx.text().contains(&("Thumb::X-Frames".to_string(), "3".to_string())));
+
+        let (img, ctx) = generate(&gif, FramePolicy::AtPercent(100.0));
+        assert_eq!(img.get_pixel(8, 8), Rgba([255, 255, 255, 255]));
+        assert_eq!(frame_count(&ctx), Some("3"));
+
+        let (_, ctx) = generate(&gif, FramePolicy::AtPercent(0.0));
+        assert_eq!(frame_count(&ctx), Some("3"));
+
+        let (img, _) = generate(&gif, FramePolicy::MostDetailed);
+        assert_ne!(img.get_pixel(0, 0), img.get_pixel(15, 15));
+    }
+
+    #[test]
+    fn test_long_animation_frame_count() {
+        let frame = |i: usize| RgbaImage::from_pixel(1, 1, Rgba([(i % 2 * 255) as u8, 0, 0, 255]));
+
+        let exact = gif((0..MAX_FRAMES).map(frame));
+        let (_, ctx) = generate(&exact, FramePolicy::First);
+        assert_eq!(frame_count(&ctx), Some("1000"));
+
+        // Longer animations are not reported as having exactly the cap.
+        let long = gif((0..MAX_FRAMES + 1).map(frame));
+        for policy in [FramePolicy::First, FramePolicy::AtPercent(50.0)] {
+            let (_, ctx) = generate(&long, policy);
+            assert_eq!(frame_count(&ctx), None);
+        }
+    }
+
+    #[test]
+    fn test_frame_at_percent() {
+        assert_eq!(frame_at_percent(10, 0.0), 0);
+        assert_eq!(frame_at_percent(10, 50.0), 5);
+        assert_eq!(frame_at_percent(10, 150.0), 9);
+        assert_eq!(frame_at_percent(0, 50.0), 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! Reader for the preview image Blender stores in the `TEST` block of
+//! `.blend` files, which may be uncompressed, gzip or zstd compressed.
+
+use flate2::read::MultiGzDecoder;
+use image::{imageops, RgbaImage};
+use ruzstd::decoding::{
+    errors::{FrameDecoderError, ReadFrameHeaderError},
+    BlockDecodingStrategy, FrameDecoder,
+};
+use std::io::{self, BufRead, Read};
+
+/// The block holding the preview, written right after the render settings.
+const TEST_CODE: &[u8; 4] = b"TEST";
+
+/// The block marking the end of the file.
+const END_CODE: &[u8; 4] = b"ENDB";
+
+/// The preview comes first, so only this many blocks are looked at.
+const MAX_BLOCKS: usize = 1024;
+
+/// Previews with a side longer than this are rejected.
+const MAX_PREVIEW_SIZE: u32 = 2048;
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+
+/// How block headers are laid out, as given by the file header.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    pointer_size: usize,
+    big_endian: bool,
+    /// Blender 5 files use 64 bit lengths and a different field order.
+    large_headers: bool,
+}
+
+/// Reads the preview of a `.blend` file, flipped into top-down row order.
+/// Returns `None` when the file has no preview.
+pub fn read_preview<R: BufRead>(mut reader: R) -> io::Result<Option<RgbaImage>> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        read_blocks(MultiGzDecoder::new(reader))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        read_blocks(ZstdFrames::new(reader))
+    } else {
+        read_blocks(reader)
+    }
+}
+
+fn read_blocks<R: Read>(mut reader: R) -> io::Result<Option<RgbaImage>> {
+    let layout = read_header(&mut reader)?;
+    let header_len = if layout.large_headers { 32 } else { 16 + layout.pointer_size };
+    let mut header = [0u8; 32];
+
+    for _ in 0..MAX_BLOCKS {
+        reader.read_exact(&mut header[..header_len])?;
+        let code = &header[..4];
+        let len = if layout.large_headers {
+            // code, SDNA index, old pointer, length, count.
+            read_int(&header[16..24], false)
+        } else {
+            read_int(&header[4..8], layout.big_endian)
+        };
+        let len = u64::try_from(len).map_err(|_| invalid("Negative block length"))?;
+
+        if code == END_CODE {
+            break;
+        }
+        if code == TEST_CODE {
+            return read_test_block(&mut reader, len, layout.big_endian).map(Some);
+        }
+        io::copy(&mut (&mut reader).take(len), &mut io::sink())?;
+    }
+    Ok(None)
+}
+
+/// Parses the file header: `BLENDER_v293` style headers of Blender 4 and
+/// older, and the `BLENDER17-01v0500` style of Blender 5.
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Layout> {
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..7] != b"BLENDER" {
+        return Err(invalid("Not a .blend file"));
+    }
+
+    match (header[7], header[8]) {
+        (b'_' | b'-', b'v' | b'V') => Ok(Layout {
+            pointer_size: if header[7] == b'_' { 4 } else { 8 },
+            big_endian: header[8] == b'V',
+            large_headers: false,
+        }),
+        (b'1', b'7') if &header[9..12] == b"-01" => {
+            let mut rest = [0u8; 5];
+            reader.read_exact(&mut rest)?;
+            if rest[0] != b'v' {
+                return Err(invalid("Unsupported .blend byte order"));
+            }
+            Ok(Layout {
+                pointer_size: 8,
+                big_endian: false,
+                large_headers: true,
+            })
+        }
+        _ => Err(invalid("Unsupported .blend header")),
+    }
+}
+
+/// Reads a `TEST` block: the width and height followed by bottom-up RGBA rows.
+fn read_test_block<R: Read>(reader: &mut R, len: u64, big_endian: bool) -> io::Result<RgbaImage> {
+    let mut size = [0u8; 8];
+    reader.read_exact(&mut size)?;
+    let width = read_int(&size[..4], big_endian);
+    let height = read_int(&size[4..], big_endian);
+    let valid = 1..=MAX_PREVIEW_SIZE as i64;
+    if !valid.contains(&width) || !valid.contains(&height) {
+        return Err(invalid("Invalid .blend preview size"));
+    }
+    let (width, height) = (width as u32, height as u32);
+    let pixels_len = width as u64 * height as u64 * 4;
+    if len < 8 + pixels_len {
+        return Err(invalid("Truncated .blend preview"));
+    }
+
+    let mut pixels = vec![0u8; pixels_len as usize];
+    reader.read_exact(&mut pixels)?;
+    let mut image = RgbaImage::from_raw(width, height, pixels).ok_or_else(|| invalid("Invalid .blend preview"))?;
+    imageops::flip_vertical_in_place(&mut image);
+    Ok(image)
+}
+
+/// Reads a signed 4 or 8 byte integer.
+fn read_int(bytes: &[u8], big_endian: bool) -> i64 {
+    match (bytes.len(), big_endian) {
+        (4, false) => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+        (4, true) => i32::from_be_bytes(bytes.try_into().unwrap()) as i64,
+        (_, false) => i64::from_le_bytes(bytes.try_into().unwrap()),
+        (_, true) => i64::from_be_bytes(bytes.try_into().unwrap()),
+    }
+}
+
+/// Decompresses every frame of a zstd stream. Blender writes the seekable
+/// format, which splits the file into many frames and ends with a skippable
+/// frame holding the seek table.
+struct ZstdFrames<R: Read> {
+    source: R,
+    decoder: FrameDecoder,
+    in_frame: bool,
+}
+
+impl<R: Read> ZstdFrames<R> {
+    fn new(source: R) -> Self {
+        ZstdFrames {
+            source,
+            decoder: FrameDecoder::new(),
+            in_frame: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ZstdFrames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if !self.in_frame {
+                match self.decoder.init(&mut self.source) {
+                    Ok(()) => self.in_frame = true,
+                    Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame { length, .. })) => {
+                        io::copy(&mut (&mut self.source).take(length as u64), &mut io::sink())?;
+                        continue;
+                    }
+                    Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::MagicNumberReadError(e)))
+                        if e.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        return Ok(0);
+                    }
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+
+            while self.decoder.can_collect() < buf.len() && !self.decoder.is_finished() {
+                let needed = buf.len() - self.decoder.can_collect();
+                self.decoder
+                    .decode_blocks(&mut self.source, BlockDecodingStrategy::UptoBytes(needed))
+                    .map_err(io::Error::other)?;
+            }
+            let read = self.decoder.read(buf)?;
+            if read > 0 {
+                return Ok(read);
+            }
+            if self.decoder.is_finished() {
+                self.in_frame = false;
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::{Cursor, Write};
+
+    use super::read_preview;
+
+    /// A little endian, 8 byte pointer file with a REND and a 2x2 TEST block.
+    fn blend_file() -> Vec<u8> {
+        let block = |code: &[u8; 4], payload: &[u8]| {
+            let mut out = code.to_vec();
+            out.extend((payload.len() as i32).to_le_bytes());
+            out.extend([0u8; 8]);
+            out.extend(0i32.to_le_bytes());
+            out.extend(1i32.to_le_bytes());
+            out.extend_from_slice(payload);
+            out
+        };
+        let mut test = 2i32.to_le_bytes().to_vec();
+        test.extend(2i32.to_le_bytes());
+        test.extend([255, 0, 0, 255, 255, 0, 0, 255, 0, 0, 255, 255, 0, 0, 255, 255]);
+
+        let mut file = b"BLENDER-v405".to_vec();
+        file.extend(block(b"REND", &[0; 72]));
+        file.extend(block(b"TEST", &test));
+        file.extend(block(b"ENDB", &[]));
+        file
+    }
+
+    #[test]
+    fn test_read_preview() {
+        let file = blend_file();
+        let preview = read_preview(Cursor::new(&file)).unwrap().unwrap();
+        assert_eq!(preview.dimensions(), (2, 2));
+        // The bottom-up rows are flipped, so the blue row ends up on top.
+        assert_eq!(preview.get_pixel(0, 0).0, [0, 0, 255, 255]);
+        assert_eq!(preview.get_pixel(1, 1).0, [255, 0, 0, 255]);
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::fast());
+        gzip.write_all(&file).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(read_preview(Cursor::new(gzip)).unwrap().unwrap(), preview);
+
+        // Two single raw-block zstd frames with a skippable frame in between.
+        let frame = |data: &[u8]| {
+            let mut out = vec![0x28, 0xB5, 0x2F, 0xFD, 0x20, data.len() as u8];
+            out.extend(&((((data.len() as u32) << 3) | 1).to_le_bytes()[..3]));
+            out.extend_from_slice(data);
+            out
+        };
+        let mut zstd = frame(&file[..100]);
+        zstd.extend([0x50, 0x2A, 0x4D, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+        zstd.extend(frame(&file[100..]));
+        assert_eq!(read_preview(Cursor::new(zstd)).unwrap().unwrap(), preview);
+
+        let mut empty = b"BLENDER_v279".to_vec();
+        empty.extend(b"ENDB");
+        empty.extend([0u8; 16]);
+        assert!(read_preview(Cursor::new(empty)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,202 @@
+//! Colour management for in-process thumbnails.
+//!
+//! With the `icc` cargo feature, pixels carrying an embedded ICC profile are
+//! converted to sRGB with the pure-Rust `qcms` CMS before encoding, because
+//! thumbnails are written without a profile and viewers assume sRGB.
+
+use image::DynamicImage;
+use log::debug;
+
+/// Converts `img`, whose pixels are in the colour space described by the ICC
+/// profile `profile`, to sRGB.
+///
+/// Profiles that cannot be parsed or transformed, or that describe neither an
+/// RGB nor a grey colour space, leave the image untouched. Grey profiles are
+/// applied to the luma of the image and expanded to RGB. The result is 8 bits
+/// per channel; alpha is preserved.
+#[cfg(feature = "icc")]
+pub fn convert_to_srgb(img: DynamicImage, profile: &[u8]) -> DynamicImage {
+    use image::{RgbImage, RgbaImage};
+    use log::warn;
+    use qcms::{DataType, Intent, Profile, Transform};
+
+    let Some(input) = Profile::new_from_slice(profile, false) else {
+        warn!("Ignoring unparseable ICC profile ({} bytes)", profile.len());
+        return img;
+    };
+    if input.is_sRGB() {
+        return img;
+    }
+
+    // qcms reads grey profiles too, but applies them to whatever pixel layout
+    // it is given, so the layout has to follow the profile's colour space.
+    let grey = match profile.get(16..20) {
+        Some(b"GRAY") => true,
+        Some(b"RGB ") => false,
+        color_space => {
+            debug!("Ignoring ICC profile for colour space {:?}", color_space);
+            return img;
+        }
+    };
+
+    let mut output = Profile::new_sRGB();
+    output.precache_output_transform();
+
+    let has_alpha = img.color().has_alpha();
+    let (input_type, output_type) = match (grey, has_alpha) {
+        (true, true) => (DataType::GrayA8, DataType::RGBA8),
+        (true, false) => (DataType::Gray8, DataType::RGB8),
+        (false, true) => (DataType::RGBA8, DataType::RGBA8),
+        (false, false) => (DataType::RGB8, DataType::RGB8),
+    };
+    let Some(transform) = Transform::new_to(&input, &output, input_type, output_type, Intent::Perceptual) else {
+        warn!("Could not create a transform from the embedded ICC profile to sRGB");
+        return img;
+    };
+
+    debug!("Converting {:?} image from its ICC profile to sRGB", img.color());
+    let (width, height) = (img.width(), img.height());
+    match (grey, has_alpha) {
+        (true, true) => {
+            let mut rgba = RgbaImage::new(width, height);
+            transform.convert(&img.to_luma_alpha8(), &mut rgba);
+            DynamicImage::ImageRgba8(rgba)
+        }
+        (true, false) => {
+            let mut rgb = RgbImage::new(width, height);
+            transform.convert(&img.to_luma8(), &mut rgb);
+            DynamicImage::ImageRgb8(rgb)
+        }
+        (false, true) => {
+            let mut rgba: RgbaImage = img.to_rgba8();
+            transform.apply(&mut rgba);
+            DynamicImage::ImageRgba8(rgba)
+        }
+        (false, false) => {
+            let mut rgb: RgbImage = img.to_rgb8();
+            transform.apply(&mut rgb);
+            DynamicImage::ImageRgb8(rgb)
+        }
+    }
+}
+
+/// Without the `icc` feature, embedded profiles are ignored.
+#[cfg(not(feature = "icc"))]
+pub fn convert_to_srgb(img: DynamicImage, profile: &[u8]) -> DynamicImage {
+    debug!(
+        "Ignoring {} byte ICC profile: built without the `icc` feature",
+        profile.len()
+    );
+    img
+}
+
+#[cfg(all(test, feature = "icc"))]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::convert_to_srgb;
+
+    /// sRGB primaries adapted to D50, as stored in display profiles.
+    const PRIMARIES: [(&[u8; 4], [f64; 3]); 3] = [
+        (b"rXYZ", [0.4361, 0.2225, 0.0139]),
+        (b"gXYZ", [0.3851, 0.7169, 0.0971]),
+        (b"bXYZ", [0.1431, 0.0606, 0.7141]),
+    ];
+
+    fn s15_fixed16(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    /// A display profile for `color_space` holding `tags`.
+    fn profile(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data: Vec<u8> = Vec::new();
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in tags {
+            table.extend_from_slice(*signature);
+            table.extend(((data_start + data.len()) as u32).to_be_bytes());
+            table.extend((tag.len() as u32).to_be_bytes());
+            data.extend(tag);
+        }
+
+        let mut out = vec![0u8; 128];
+        out[0..4].copy_from_slice(&((128 + table.len() + data.len()) as u32).to_be_bytes());
+        out[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        out[12..16].copy_from_slice(b"mntr");
+        out[16..20].copy_from_slice(color_space);
+        out[20..24].copy_from_slice(b"XYZ ");
+        out[36..40].copy_from_slice(b"acsp");
+        out.extend(table);
+        out.extend(data);
+        out
+    }
+
+    fn xyz(values: [f64; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        values.iter().for_each(|v| tag.extend(s15_fixed16(*v)));
+        tag
+    }
+
+    fn gamma(value: f64) -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend(1u32.to_be_bytes());
+        tag.extend(((value * 256.0).round() as u16).to_be_bytes());
+        tag
+    }
+
+    fn rgb_profile(curve: Vec<u8>) -> Vec<u8> {
+        let mut tags: Vec<_> = PRIMARIES.iter().map(|(signature, values)| (*signature, xyz(*values))).collect();
+        for signature in [b"rTRC", b"gTRC", b"bTRC"] {
+            tags.push((signature, curve.clone()));
+        }
+        profile(b"RGB ", &tags)
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(2, 1, vec![200, 200, 200, 255, 10, 10, 10, 128]).unwrap())
+    }
+
+    fn convert(profile: &[u8]) -> Vec<u8> {
+        convert_to_srgb(image(), profile).into_rgba8().into_raw()
+    }
+
+    #[test]
+    fn test_rgb_profile() {
+        // Linear light: mid tones get brighter in sRGB.
+        let pixels = convert(&rgb_profile(gamma(1.0)));
+        assert!(pixels[0] > 220, "{:?}", pixels);
+        assert_eq!(pixels[0], pixels[1]);
+        assert_eq!((pixels[3], pixels[7]), (255, 128));
+    }
+
+    #[test]
+    fn test_srgb_profile() {
+        let mut curve = b"para\0\0\0\0".to_vec();
+        curve.extend(3u16.to_be_bytes());
+        curve.extend([0, 0]);
+        for value in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            curve.extend(s15_fixed16(value));
+        }
+        let pixels = convert(&rgb_profile(curve));
+        let original = image().into_rgba8().into_raw();
+        for (converted, original) in pixels.iter().zip(&original) {
+            assert!(converted.abs_diff(*original) <= 1, "{:?}", pixels);
+        }
+    }
+
+    #[test]
+    fn test_grey_profile() {
+        let pixels = convert(&profile(b"GRAY", &[(b"kTRC", gamma(2.2))]));
+        assert_eq!(pixels.len(), 8);
+        assert!(pixels[0].abs_diff(200) <= 5, "{:?}", pixels);
+        assert!(pixels[4] < 30, "{:?}", pixels);
+        assert_eq!(&pixels[0..3], &[pixels[0]; 3]);
+        assert_eq!(&pixels[4..7], &[pixels[4]; 3]);
+        assert_eq!((pixels[3], pixels[7]), (255, 128));
+    }
+
+    #[test]
+    fn test_unparseable_profile() {
+        assert_eq!(convert(b"not an ICC profile"), image().into_rgba8().into_raw());
+    }
+}
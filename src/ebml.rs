@@ -0,0 +1,188 @@
+//! Minimal reader for EBML elements, the binary structure of Matroska and
+//! WebM files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Upper bound on the number of sibling elements read from one parent.
+const MAX_CHILDREN: usize = 4096;
+
+/// Element IDs used when looking for attachments.
+pub mod ids {
+    pub const EBML: u32 = 0x1A45_DFA3;
+    pub const SEGMENT: u32 = 0x1853_8067;
+    pub const SEEK_HEAD: u32 = 0x114D_9B74;
+    pub const SEEK: u32 = 0x4DBB;
+    pub const SEEK_ID: u32 = 0x53AB;
+    pub const SEEK_POSITION: u32 = 0x53AC;
+    pub const ATTACHMENTS: u32 = 0x1941_A469;
+    pub const ATTACHED_FILE: u32 = 0x61A7;
+    pub const FILE_NAME: u32 = 0x466E;
+    pub const FILE_MIME_TYPE: u32 = 0x4660;
+    pub const FILE_DATA: u32 = 0x465C;
+}
+
+/// The position and ID of an element in the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Element {
+    /// The element ID, including its length marker bits.
+    pub id: u32,
+    /// Offset of the element header.
+    pub start: u64,
+    /// Offset of the element payload.
+    pub data_start: u64,
+    /// Offset just past the end of the element. Elements of unknown size
+    /// extend to the end of their parent.
+    pub end: u64,
+    /// Whether the element was written with an unknown size, as live
+    /// recordings do for segments and clusters.
+    pub unknown_size: bool,
+}
+
+impl Element {
+    /// Length of the payload in bytes.
+    pub fn data_len(&self) -> u64 {
+        self.end - self.data_start
+    }
+}
+
+/// Reads a variable length integer, returning its value with the length
+/// marker kept (for IDs) or removed (for sizes) and its length in bytes.
+fn read_vint<R: Read>(reader: &mut R, max_len: u32, keep_marker: bool) -> io::Result<(u64, u32)> {
+    let mut first = [0u8; 1];
+    reader.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() + 1;
+    if len > max_len {
+        return Err(invalid("Invalid variable length integer"));
+    }
+
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        first[0] as u64 & (0xFF >> len)
+    };
+    let mut rest = [0u8; 7];
+    reader.read_exact(&mut rest[..len as usize - 1])?;
+    for byte in &rest[..len as usize - 1] {
+        value = (value << 8) | *byte as u64;
+    }
+    Ok((value, len))
+}
+
+/// Reads the header of the element at `pos`, which must end by `parent_end`.
+/// Returns `None` when no element header fits before `parent_end`.
+pub fn read_element_header<R: Read + Seek>(reader: &mut R, pos: u64, parent_end: u64) -> io::Result<Option<Element>> {
+    if pos.saturating_add(2) > parent_end {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(pos))?;
+    let (id, id_len) = read_vint(reader, 4, true)?;
+    let (size, size_len) = read_vint(reader, 8, false)?;
+    let data_start = pos + (id_len + size_len) as u64;
+
+    // A size with all value bits set means the size is unknown.
+    let unknown_size = size == (1 << (7 * size_len)) - 1;
+    let end = if unknown_size {
+        parent_end
+    } else {
+        data_start.checked_add(size).ok_or_else(|| invalid("Element size overflows"))?
+    };
+    if data_start > end || end > parent_end {
+        return Err(invalid("Element exceeds its parent"));
+    }
+    Ok(Some(Element {
+        id: id as u32,
+        start: pos,
+        data_start,
+        end,
+        unknown_size,
+    }))
+}
+
+/// Reads the headers of the elements laid out back to back in `start..end`.
+/// Stops after an element of unknown size, whose end cannot be found without
+/// parsing its contents.
+pub fn children<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<Vec<Element>> {
+    let mut elements = Vec::new();
+    let mut pos = start;
+    while let Some(element) = read_element_header(reader, pos, end)? {
+        pos = element.end;
+        elements.push(element);
+        if elements.len() >= MAX_CHILDREN || element.unknown_size {
+            break;
+        }
+    }
+    Ok(elements)
+}
+
+/// Finds the first element with ID `id` in `start..end`.
+pub fn find_element<R: Read + Seek>(reader: &mut R, start: u64, end: u64, id: u32) -> io::Result<Option<Element>> {
+    let mut pos = start;
+    for _ in 0..MAX_CHILDREN {
+        let Some(element) = read_element_header(reader, pos, end)? else {
+            break;
+        };
+        if element.id == id {
+            return Ok(Some(element));
+        }
+        if element.unknown_size {
+            break;
+        }
+        pos = element.end;
+    }
+    Ok(None)
+}
+
+/// Reads the payload of `element`, refusing payloads larger than `max_len`.
+pub fn read_data<R: Read + Seek>(reader: &mut R, element: &Element, max_len: u64) -> io::Result<Vec<u8>> {
+    if element.data_len() > max_len {
+        return Err(invalid("Element payload too large"));
+    }
+    reader.seek(SeekFrom::Start(element.data_start))?;
+    let mut data = Vec::new();
+    reader.take(element.data_len()).read_to_end(&mut data)?;
+    if data.len() as u64 != element.data_len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated element"));
+    }
+    Ok(data)
+}
+
+/// Interprets the payload of an unsigned integer element.
+pub fn read_uint(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+    Some(data.iter().fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{children, find_element, read_data, read_uint};
+
+    #[test]
+    fn test_elements() {
+        // An EBML header holding a one byte DocTypeVersion, followed by a
+        // segment of unknown size holding an eight byte size encoded element.
+        let mut file = vec![0x1A, 0x45, 0xDF, 0xA3, 0x84, 0x42, 0x87, 0x81, 0x04];
+        file.extend([0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        file.extend([0x46, 0x6E, 0x01, 0, 0, 0, 0, 0, 0, 0x03, b'a', b'b', b'c']);
+        let len = file.len() as u64;
+        let mut cursor = Cursor::new(file);
+
+        let top = children(&mut cursor, 0, len).unwrap();
+        assert_eq!(top.len(), 2);
+        assert!(top[1].unknown_size);
+        assert_eq!(top[1].end, len);
+
+        let version = find_element(&mut cursor, top[0].data_start, top[0].end, 0x4287).unwrap().unwrap();
+        assert_eq!(read_uint(&read_data(&mut cursor, &version, 8).unwrap()), Some(4));
+        let name = find_element(&mut cursor, top[1].data_start, top[1].end, 0x466E).unwrap().unwrap();
+        assert_eq!(read_data(&mut cursor, &name, 8).unwrap(), b"abc");
+        assert!(read_data(&mut cursor, &name, 2).is_err());
+    }
+}
@@ -1,11 +1,14 @@
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::trust::TrustError;
+
 /// A unified error type for the thumbnail library.
 #[derive(Error, Debug)]
 pub enum ThumbnailError {
     /// Wraps errors originating from the `image` crate.
     #[error("Image crate error: {0}")]
-    Image(#[from] image::ImageError),
+    Image(image::ImageError),
 
     /// Wraps standard I/O errors.
     #[error("I/O error: {0}")]
@@ -24,5 +27,57 @@ pub enum ThumbnailError {
     PngEncoding(#[from] png::EncodingError),
 
     #[error("PNG decoding error: {0}")]
-    PngDecoding(#[from] png::DecodingError),
+    PngDecoding(png::DecodingError),
+
+    #[error("ZIP archive error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    /// A thumbnailer definition or executable failed the trust checks.
+    #[error("Untrusted thumbnailer: {0}")]
+    Untrusted(#[from] TrustError),
+
+    /// The thumbnailer was suspended by the circuit breaker after repeated failures.
+    #[error("Thumbnailer {thumbnailer} is suspended for another {remaining:?}")]
+    ThumbnailerSuspended { thumbnailer: String, remaining: Duration },
+
+    /// The thumbnailer ran longer than the configured timeout and was killed.
+    #[error("Thumbnailer {0} timed out")]
+    ThumbnailerTimedOut(String),
+
+    /// The thumbnailer was killed by a signal or failed to start.
+    #[error("Thumbnailer {0} crashed")]
+    ThumbnailerCrashed(String),
+
+    /// A generator declined the file; the next candidate is tried instead.
+    #[error("Unsupported input: {0}")]
+    Unsupported(String),
+
+    /// A media file that may carry a picture, such as an audio file, has none.
+    /// Unlike a broken file this does not produce a fail marker.
+    #[error("No embedded art")]
+    NoEmbeddedArt,
+
+    /// An image exceeded the configured `DecodeLimits`.
+    #[error("Decode limits exceeded: {0}")]
+    LimitsExceeded(String),
+}
+
+impl From<image::ImageError> for ThumbnailError {
+    fn from(error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Limits(e) => ThumbnailError::LimitsExceeded(e.to_string()),
+            e => ThumbnailError::Image(e),
+        }
+    }
+}
+
+impl From<png::DecodingError> for ThumbnailError {
+    fn from(error: png::DecodingError) -> Self {
+        match error {
+            png::DecodingError::LimitsExceeded => {
+                ThumbnailError::LimitsExceeded("PNG allocation limit exceeded".to_string())
+            }
+            e => ThumbnailError::PngDecoding(e),
+        }
+    }
 }
@@ -10,7 +10,14 @@ use image::{DynamicImage, Rgba, RgbaImage};
 use png::{Decoder, Encoder};
 use url::Url;
 
-use crate::{error::ThumbnailError, sizes::ThumbnailSize};
+use crate::{
+    alpha::AlphaPolicy,
+    error::ThumbnailError,
+    limits::DecodeLimits,
+    shape::ThumbnailShape,
+    sizes::ThumbnailSize,
+    tonemap::{to_rgba8, ToneMapOptions},
+};
 
 fn get_base_cache_dir() -> PathBuf {
     // Determine the base cache directory using the `dirs` crate.
@@ -38,6 +45,38 @@ pub fn get_thumbnail_hash_output(hash: &str, size: ThumbnailSize) -> PathBuf {
     path
 }
 
+/// Gets the output path for a thumbnail of the given shape and alpha policy.
+/// Standard fit thumbnails that keep their alpha channel use
+/// `get_thumbnail_hash_output`; anything else is kept apart in
+/// `{cache_dir}/thumbnails/thumbnailify/{size}-{shape}/{md5_hash}.png`, with
+/// `-{alpha}` appended to the directory name for flattened thumbnails.
+pub fn get_shaped_thumbnail_output(
+    hash: &str,
+    size: ThumbnailSize,
+    shape: ThumbnailShape,
+    alpha: AlphaPolicy,
+) -> PathBuf {
+    let (shape_name, alpha_name) = (shape.cache_name(), alpha.cache_name());
+    if shape_name.is_none() && alpha_name.is_none() {
+        return get_thumbnail_hash_output(hash, size);
+    }
+    let mut variant = format!("{}-{}", size, shape_name.unwrap_or("fit"));
+    if let Some(alpha_name) = alpha_name {
+        variant = format!("{}-{}", variant, alpha_name);
+    }
+    let output_dir = get_base_cache_dir()
+        .join("thumbnails")
+        .join("thumbnailify")
+        .join(variant);
+    let path = output_dir.join(format!("{}.png", hash));
+
+    debug!(
+        "Constructed {:?} {:?} thumbnail output path for hash={} size={:?}: {:?}",
+        shape, alpha, hash, size, path
+    );
+    path
+}
+
 /// Returns the output path for a failed thumbnail marker.
 /// This uses the fails folder under the thumbnails cache.
 pub fn get_failed_thumbnail_output(hash: &str) -> PathBuf {
@@ -84,14 +123,24 @@ pub fn get_file_uri(input: &Path) -> Result<String, ThumbnailError> {
     Ok(url.to_string())
 }
 
-/// Writes out the thumbnail as a PNG, embedding:
-/// - `Thumb::URI`
-/// - `Thumb::Size`
-/// - `Thumb::MTime`
+/// Writes out the thumbnail as an 8-bit RGBA PNG, or RGB if `img` has no alpha
+/// channel. The `Thumb::*` metadata describing the source is embedded
+/// afterwards by `add_thumbnail_metadata`.
 pub fn write_out_thumbnail(
     image_path: &Path,
     img: DynamicImage,
     source_image_path: &Path,
+) -> Result<(), ThumbnailError> {
+    write_out_thumbnail_with_text(image_path, img, source_image_path, &[])
+}
+
+/// Same as `write_out_thumbnail`, additionally storing `text` as PNG text chunks
+/// (for example `Thumb::Image::Width`).
+pub fn write_out_thumbnail_with_text(
+    image_path: &Path,
+    img: DynamicImage,
+    source_image_path: &Path,
+    text: &[(String, String)],
 ) -> Result<(), ThumbnailError> {
     info!(
         "Writing out thumbnail to {:?} from source {:?}",
@@ -100,14 +149,25 @@ pub fn write_out_thumbnail(
 
     let file = File::create(image_path)?;
 
-    let rgba_image: RgbaImage = img.to_rgba8();
+    // Images without an alpha channel, such as flattened ones, are stored as
+    // RGB to save space.
+    let has_alpha = img.color().has_alpha();
+    let rgba_image: RgbaImage = to_rgba8(img, &ToneMapOptions::default());
     let (width, height) = rgba_image.dimensions();
-    let buffer = rgba_image.into_raw();
+    let (color_type, buffer) = if has_alpha {
+        (png::ColorType::Rgba, rgba_image.into_raw())
+    } else {
+        (png::ColorType::Rgb, DynamicImage::ImageRgba8(rgba_image).into_rgb8().into_raw())
+    };
 
     let mut encoder = Encoder::new(BufWriter::new(file), width, height);
-    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_color(color_type);
     encoder.set_depth(png::BitDepth::Eight);
 
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.clone(), value.clone())?;
+    }
+
     let mut writer = encoder.write_header()?;
     writer.write_image_data(&buffer)?;
 
@@ -116,14 +176,26 @@ pub fn write_out_thumbnail(
 }
 
 pub fn add_thumbnail_metadata(thumb_path: &Path, source_image_path: &Path) -> Result<(), ThumbnailError> {
+    add_thumbnail_metadata_with_limits(thumb_path, source_image_path, &DecodeLimits::default())
+}
+
+/// Same as `add_thumbnail_metadata`, rejecting thumbnails that exceed `limits`
+/// before their pixels are read.
+pub fn add_thumbnail_metadata_with_limits(
+    thumb_path: &Path,
+    source_image_path: &Path,
+    limits: &DecodeLimits,
+) -> Result<(), ThumbnailError> {
     debug!("Adding thumbnail metadata to {:?}", thumb_path);
     
     let file_in = File::open(thumb_path)?;
     let reader = BufReader::new(file_in);
 
     // Decode the PNG
-    let decoder = Decoder::new(reader);
+    let decoder = Decoder::new_with_limits(reader, limits.to_png_limits());
     let mut reader = decoder.read_info()?;
+    // Allocation is already bounded by the PNG decoder, so only check dimensions.
+    limits.check(reader.info().width, reader.info().height, 0)?;
 
     // Extract existing metadata 
     let info = reader.info();
@@ -172,4 +244,39 @@ pub fn add_thumbnail_metadata(thumb_path: &Path, source_image_path: &Path) -> Re
     );
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{get_shaped_thumbnail_output, get_thumbnail_hash_output};
+    use crate::{AlphaPolicy, ThumbnailShape, ThumbnailSize};
+
+    #[test]
+    fn test_cache_path_per_variant() {
+        let hash = "0123456789abcdef0123456789abcdef";
+        let size = ThumbnailSize::Normal;
+        let standard = get_thumbnail_hash_output(hash, size);
+        assert_eq!(
+            get_shaped_thumbnail_output(hash, size, ThumbnailShape::Fit, AlphaPolicy::Preserve),
+            standard
+        );
+
+        let mut paths = HashSet::new();
+        for shape in [ThumbnailShape::Fit, ThumbnailShape::CenterCrop] {
+            for alpha in [
+                AlphaPolicy::Preserve,
+                AlphaPolicy::Flatten([255, 255, 255]),
+                AlphaPolicy::Flatten([0, 0, 0]),
+                AlphaPolicy::Checkerboard,
+            ] {
+                let path = get_shaped_thumbnail_output(hash, size, shape, alpha);
+                assert!(paths.insert(path.clone()), "{:?} is shared", path);
+                if alpha != AlphaPolicy::Preserve {
+                    assert_ne!(path, standard);
+                    assert!(path.to_string_lossy().contains("thumbnailify"), "{:?}", path);
+                }
+            }
+        }
+    }
+}
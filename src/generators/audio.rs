@@ -0,0 +1,467 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::DynamicImage;
+use log::debug;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{decode_embedded_image, GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// Audio MIME types that can carry cover art.
+pub const MIME_TYPES: &[&str] = &[
+    "audio/mpeg",
+    "audio/flac",
+    "audio/x-flac",
+    "audio/ogg",
+    "audio/x-vorbis+ogg",
+    "audio/vorbis",
+    "audio/opus",
+    "audio/x-opus+ogg",
+];
+
+/// Tags and comment packets larger than this are not read.
+const MAX_TAG_BYTES: u64 = 32 * 1024 * 1024;
+
+/// The ID3v2 / FLAC picture type of the front cover.
+const FRONT_COVER: u32 = 3;
+
+/// FLAC metadata block type of pictures.
+const FLAC_PICTURE: u8 = 6;
+
+/// Extracts cover art from audio files: ID3v2 `APIC`/`PIC` frames, FLAC
+/// `PICTURE` blocks and the `METADATA_BLOCK_PICTURE` comment of Ogg Vorbis and
+/// Opus streams. The front cover is preferred over other pictures.
+///
+/// Files without a picture fail with `ThumbnailError::NoEmbeddedArt`, which
+/// does not produce a fail marker.
+#[derive(Debug, Default)]
+pub struct CoverArtGenerator;
+
+/// A picture found in a tag.
+#[derive(Debug)]
+struct Picture {
+    picture_type: u32,
+    data: Vec<u8>,
+}
+
+impl ThumbnailGenerator for CoverArtGenerator {
+    fn name(&self) -> &str {
+        "cover-art"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut reader = BufReader::new(source.reader()?);
+        let pictures = find_pictures(&mut reader).unwrap_or_else(|e| {
+            debug!("Could not read audio tags: {}", e);
+            Vec::new()
+        });
+
+        let picture = pictures
+            .iter()
+            .find(|p| p.picture_type == FRONT_COVER)
+            .or_else(|| pictures.first())
+            .ok_or(ThumbnailError::NoEmbeddedArt)?;
+        debug!(
+            "Using {} byte cover picture of type {}",
+            picture.data.len(),
+            picture.picture_type
+        );
+        decode_embedded_image(picture.data.clone(), ctx.limits())
+    }
+}
+
+/// Identifies the container by its magic bytes and collects its pictures.
+fn find_pictures<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Picture>> {
+    let mut magic = [0u8; 4];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+
+    let mut pictures = Vec::new();
+    if magic.starts_with(b"ID3") {
+        reader.seek(SeekFrom::Start(0))?;
+        let tag_end = read_id3(reader, &mut pictures)?;
+        // FLAC files sometimes start with an ID3v2 tag.
+        reader.seek(SeekFrom::Start(tag_end))?;
+        if reader.read_exact(&mut magic).is_err() {
+            return Ok(pictures);
+        }
+    }
+
+    match &magic {
+        b"fLaC" => read_flac(reader, &mut pictures)?,
+        b"OggS" => {
+            reader.seek(SeekFrom::Start(0))?;
+            read_ogg(reader, &mut pictures)?;
+        }
+        _ => {}
+    }
+    Ok(pictures)
+}
+
+fn read_exact_vec<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    if len > MAX_TAG_BYTES {
+        return Err(invalid("Tag too large"));
+    }
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated tag"));
+    }
+    Ok(data)
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7F) as u32)
+}
+
+/// Removes the zero bytes inserted after 0xFF by ID3v2 unsynchronisation.
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &b in data {
+        if !(previous == 0xFF && b == 0) {
+            out.push(b);
+        }
+        previous = b;
+    }
+    out
+}
+
+/// Reads the ID3v2 tag at the start of the stream and returns the offset just
+/// past it.
+fn read_id3<R: Read + Seek>(reader: &mut R, pictures: &mut Vec<Picture>) -> io::Result<u64> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    let version = header[3];
+    let flags = header[5];
+    let size = syncsafe(&header[6..10]) as u64;
+    let tag_end = 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+
+    let mut tag = read_exact_vec(reader, size)?;
+    if version < 4 && flags & 0x80 != 0 {
+        tag = remove_unsync(&tag);
+    }
+
+    let mut pos = 0;
+    if version >= 3 && flags & 0x40 != 0 && tag.len() >= 4 {
+        // Skip the extended header; its size excludes itself in v2.3 only.
+        pos = match version {
+            3 => 4 + u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]) as usize,
+            _ => syncsafe(&tag[0..4]) as usize,
+        };
+    }
+
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    while pos + header_len <= tag.len() {
+        let frame = &tag[pos..pos + header_len];
+        if frame[0] == 0 {
+            // Padding.
+            break;
+        }
+        let size = match version {
+            2 => u32::from_be_bytes([0, frame[3], frame[4], frame[5]]) as usize,
+            3 => u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]) as usize,
+            _ => syncsafe(&frame[4..8]) as usize,
+        };
+        let start = pos + header_len;
+        let end = start.saturating_add(size).min(tag.len());
+        let id = &frame[..id_len];
+        let format_flags = if version == 2 { 0 } else { frame[9] };
+        pos = end;
+
+        if id != b"APIC" && id != b"PIC" {
+            continue;
+        }
+        let mut body = tag[start..end].to_vec();
+        match version {
+            // Compressed or encrypted frames are skipped.
+            3 if format_flags & 0xC0 != 0 => continue,
+            4 if format_flags & 0x0C != 0 => continue,
+            4 => {
+                if format_flags & 0x02 != 0 {
+                    body = remove_unsync(&body);
+                }
+                if format_flags & 0x01 != 0 && body.len() >= 4 {
+                    body.drain(..4);
+                }
+            }
+            _ => {}
+        }
+        if let Some(picture) = parse_apic(&body, version == 2) {
+            pictures.push(picture);
+        }
+    }
+    Ok(tag_end)
+}
+
+/// Parses the body of an `APIC` (or, in ID3v2.2, `PIC`) frame.
+fn parse_apic(body: &[u8], v22: bool) -> Option<Picture> {
+    let encoding = *body.first()?;
+    let mut pos = 1;
+    if v22 {
+        // Three-character image format.
+        pos += 3;
+    } else {
+        let mime_len = body.get(pos..)?.iter().position(|&b| b == 0)?;
+        if &body[pos..pos + mime_len] == b"-->" {
+            // The picture is a URL, not data.
+            return None;
+        }
+        pos += mime_len + 1;
+    }
+    let picture_type = *body.get(pos)? as u32;
+    pos += 1;
+
+    // Skip the description, terminated according to its text encoding.
+    let rest = body.get(pos..)?;
+    let description_len = match encoding {
+        1 | 2 => rest.chunks_exact(2).position(|c| c == [0, 0])? * 2 + 2,
+        _ => rest.iter().position(|&b| b == 0)? + 1,
+    };
+    let data = rest.get(description_len..)?;
+    (!data.is_empty()).then(|| Picture {
+        picture_type,
+        data: data.to_vec(),
+    })
+}
+
+/// Reads the metadata blocks following the `fLaC` marker.
+fn read_flac<R: Read + Seek>(reader: &mut R, pictures: &mut Vec<Picture>) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+
+        if header[0] & 0x7F == FLAC_PICTURE {
+            let block = read_exact_vec(reader, len)?;
+            pictures.extend(parse_flac_picture(&block));
+        } else {
+            reader.seek(SeekFrom::Current(len as i64))?;
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses a FLAC `PICTURE` block, which is also the payload of the Vorbis
+/// comment `METADATA_BLOCK_PICTURE`.
+fn parse_flac_picture(block: &[u8]) -> Option<Picture> {
+    let u32_at = |pos: usize| {
+        block
+            .get(pos..pos + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let picture_type = u32_at(0)?;
+    let mime_len = u32_at(4)? as usize;
+    let description_pos = 8usize.checked_add(mime_len)?;
+    let description_len = u32_at(description_pos)? as usize;
+    // Width, height, colour depth and palette size follow the description.
+    let data_len_pos = description_pos.checked_add(4 + description_len + 16)?;
+    let data_len = u32_at(data_len_pos)? as usize;
+    let data = block.get(data_len_pos + 4..(data_len_pos + 4).checked_add(data_len)?)?;
+    (!data.is_empty()).then(|| Picture {
+        picture_type,
+        data: data.to_vec(),
+    })
+}
+
+/// Reassembles the comment header, the second packet of the first logical
+/// stream, from Ogg pages and reads the pictures in it.
+fn read_ogg<R: Read>(reader: &mut R, pictures: &mut Vec<Picture>) -> io::Result<()> {
+    let mut serial = None;
+    let mut packets = 0;
+    let mut packet = Vec::new();
+
+    loop {
+        let mut header = [0u8; 27];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"OggS" {
+            return Err(invalid("Lost Ogg page sync"));
+        }
+        let page_serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        let body = read_exact_vec(reader, lacing.iter().map(|&l| l as u64).sum())?;
+
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut pos = 0;
+        for &lace in &lacing {
+            let lace = lace as usize;
+            packet.extend_from_slice(&body[pos..pos + lace]);
+            pos += lace;
+            if packet.len() as u64 > MAX_TAG_BYTES {
+                return Err(invalid("Ogg comment header too large"));
+            }
+            if lace < 255 {
+                packets += 1;
+                if packets == 2 {
+                    read_vorbis_comments(&packet, pictures);
+                    return Ok(());
+                }
+                packet.clear();
+            }
+        }
+    }
+}
+
+/// Reads the pictures from a Vorbis or Opus comment header.
+fn read_vorbis_comments(packet: &[u8], pictures: &mut Vec<Picture>) {
+    let comments = if let Some(rest) = packet.strip_prefix(b"\x03vorbis") {
+        rest
+    } else if let Some(rest) = packet.strip_prefix(b"OpusTags") {
+        rest
+    } else {
+        return;
+    };
+
+    let u32_at = |pos: usize| {
+        comments
+            .get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let Some(vendor_len) = u32_at(0) else {
+        return;
+    };
+    let mut pos = 4 + vendor_len;
+    let Some(count) = u32_at(pos) else {
+        return;
+    };
+    pos += 4;
+
+    for _ in 0..count {
+        let Some(len) = u32_at(pos) else {
+            return;
+        };
+        let Some(comment) = comments.get(pos + 4..pos + 4 + len) else {
+            return;
+        };
+        pos += 4 + len;
+
+        let Some(split) = comment.iter().position(|&b| b == b'=') else {
+            continue;
+        };
+        let (key, value) = (&comment[..split], &comment[split + 1..]);
+        if key.eq_ignore_ascii_case(b"METADATA_BLOCK_PICTURE") {
+            if let Some(picture) = STANDARD.decode(value).ok().and_then(|b| parse_flac_picture(&b)) {
+                pictures.push(picture);
+            }
+        } else if key.eq_ignore_ascii_case(b"COVERART") {
+            // The legacy comment holds the bare image.
+            if let Ok(data) = STANDARD.decode(value) {
+                pictures.push(Picture {
+                    picture_type: FRONT_COVER,
+                    data,
+                });
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::CoverArtGenerator;
+    use crate::error::ThumbnailError;
+    use crate::generators::test_util::{self, png};
+
+    fn generate(data: Vec<u8>) -> Result<(u32, u32), ThumbnailError> {
+        test_util::generate(&CoverArtGenerator, data, "audio/mpeg")
+    }
+
+    fn flac_picture(picture_type: u32, data: &[u8]) -> Vec<u8> {
+        let mut block = picture_type.to_be_bytes().to_vec();
+        block.extend_from_slice(&9u32.to_be_bytes());
+        block.extend_from_slice(b"image/png");
+        block.extend_from_slice(&0u32.to_be_bytes());
+        block.extend_from_slice(&[0; 16]);
+        block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        block.extend_from_slice(data);
+        block
+    }
+
+    #[test]
+    fn test_id3_front_cover() {
+        let apic = |picture_type: u8, data: &[u8]| {
+            let mut body = vec![0u8];
+            body.extend_from_slice(b"image/png\0");
+            body.push(picture_type);
+            body.extend_from_slice(b"cover\0");
+            body.extend_from_slice(data);
+            let mut frame = b"APIC".to_vec();
+            frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend(body);
+            frame
+        };
+
+        let mut frames = apic(4, &png(10, 10));
+        frames.extend(apic(3, &png(30, 20)));
+        frames.extend_from_slice(&[0; 16]);
+        let size = frames.len() as u32;
+        let mut tag = b"ID3\x03\0\0".to_vec();
+        tag.extend([21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7F) as u8));
+        tag.extend(frames);
+        tag.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+
+        assert_eq!(generate(tag).unwrap(), (30, 20));
+    }
+
+    #[test]
+    fn test_flac_and_ogg_pictures() {
+        let block = flac_picture(3, &png(12, 8));
+        let mut flac = b"fLaC".to_vec();
+        flac.extend_from_slice(&[0, 0, 0, 34]);
+        flac.extend_from_slice(&[0; 34]);
+        flac.push(0x80 | 6);
+        flac.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        flac.extend_from_slice(&block);
+        assert_eq!(generate(flac).unwrap(), (12, 8));
+
+        // An Opus stream whose comment header spans several Ogg pages.
+        let comment = format!("METADATA_BLOCK_PICTURE={}", STANDARD.encode(&block));
+        let mut tags = b"OpusTags".to_vec();
+        let vendor = "v".repeat(600);
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&1u32.to_le_bytes());
+        tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        tags.extend_from_slice(comment.as_bytes());
+
+        let page = |body: &[u8], last_lace: bool| {
+            let mut page = b"OggS\0\0".to_vec();
+            page.extend_from_slice(&[0; 8]);
+            page.extend_from_slice(&7u32.to_le_bytes());
+            page.extend_from_slice(&[0; 8]);
+            let mut lacing = vec![255u8; body.len() / 255];
+            if last_lace {
+                lacing.push((body.len() % 255) as u8);
+            }
+            page.push(lacing.len() as u8);
+            page.extend(lacing);
+            page.extend_from_slice(body);
+            page
+        };
+        let mut ogg = page(b"OpusHead\x01\x02\0\0\x80\xbb\0\0\0\0\0", true);
+        let split = 255 * 2;
+        ogg.extend(page(&tags[..split], false));
+        ogg.extend(page(&tags[split..], true));
+        assert_eq!(generate(ogg).unwrap(), (12, 8));
+    }
+
+    #[test]
+    fn test_no_embedded_art() {
+        let mp3 = vec![0xFF, 0xFB, 0x90, 0x00, 0, 0, 0, 0];
+        assert!(matches!(generate(mp3), Err(ThumbnailError::NoEmbeddedArt)));
+    }
+}
@@ -0,0 +1,31 @@
+use image::DynamicImage;
+use log::debug;
+use std::io::BufReader;
+
+use crate::{
+    blend::read_preview,
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// MIME types of Blender files.
+pub const MIME_TYPES: &[&str] = &["application/x-blender"];
+
+/// Uses the preview Blender stores in `.blend` files when saving, so no
+/// Blender installation is needed. Files saved without a preview fail with
+/// `ThumbnailError::NoEmbeddedArt`.
+#[derive(Debug, Default)]
+pub struct BlendPreviewGenerator;
+
+impl ThumbnailGenerator for BlendPreviewGenerator {
+    fn name(&self) -> &str {
+        "blend-preview"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let preview = read_preview(BufReader::new(source.reader()?))?.ok_or(ThumbnailError::NoEmbeddedArt)?;
+        ctx.limits().check(preview.width(), preview.height(), 4)?;
+        debug!("Using {}x{} .blend preview", preview.width(), preview.height());
+        Ok(DynamicImage::ImageRgba8(preview))
+    }
+}
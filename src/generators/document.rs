@@ -0,0 +1,389 @@
+use image::{DynamicImage, ImageFormat};
+use log::debug;
+use quick_xml::{events::Event, Reader};
+use std::cmp::Ordering;
+use std::io::{BufReader, Read, Seek};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{decode_embedded_image, GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// MIME types of ZIP containers with a preview or cover inside.
+pub const MIME_TYPES: &[&str] = &[
+    "application/epub+zip",
+    "application/vnd.comicbook+zip",
+    "application/x-cbz",
+    "application/vnd.oasis.opendocument.text",
+    "application/vnd.oasis.opendocument.text-template",
+    "application/vnd.oasis.opendocument.spreadsheet",
+    "application/vnd.oasis.opendocument.spreadsheet-template",
+    "application/vnd.oasis.opendocument.presentation",
+    "application/vnd.oasis.opendocument.presentation-template",
+    "application/vnd.oasis.opendocument.graphics",
+    "application/vnd.oasis.opendocument.graphics-template",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+];
+
+/// Archives with more entries than this are rejected.
+const MAX_ENTRIES: usize = 65_536;
+
+/// Entries that decompress to more than this are not read.
+const MAX_ENTRY_BYTES: u64 = 32 * 1024 * 1024;
+
+/// The preview OpenDocument files carry.
+const ODF_THUMBNAIL: &str = "Thumbnails/thumbnail.png";
+
+/// The usual location of the OOXML preview when `_rels/.rels` names none.
+const OOXML_THUMBNAIL: &str = "docProps/thumbnail.jpeg";
+
+/// The relationship type of the OOXML preview.
+const OOXML_THUMBNAIL_REL: &str = "http://schemas.openxmlformats.org/package/2006/relationships/metadata/thumbnail";
+
+/// Uses the preview or cover stored inside ZIP-based documents: the cover
+/// declared in the OPF manifest of EPUB books, the first page of CBZ comics,
+/// and the thumbnails OpenDocument and Office Open XML files carry.
+///
+/// Every entry read is capped at `MAX_ENTRY_BYTES`, and archives with more
+/// than `MAX_ENTRIES` entries are rejected. Documents without a preview fail
+/// with `ThumbnailError::NoEmbeddedArt`.
+#[derive(Debug, Default)]
+pub struct ZipDocumentGenerator;
+
+impl ThumbnailGenerator for ZipDocumentGenerator {
+    fn name(&self) -> &str {
+        "zip-document"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut archive = open_archive(BufReader::new(source.reader()?))?;
+        let mime_type = ctx.mime_type();
+        let entry = if mime_type == "application/epub+zip" {
+            epub_cover(&mut archive)?
+        } else if mime_type == "application/vnd.comicbook+zip" || mime_type == "application/x-cbz" {
+            first_page(&archive)
+        } else if mime_type.starts_with("application/vnd.oasis.opendocument.") {
+            Some(ODF_THUMBNAIL.to_string())
+        } else if mime_type.starts_with("application/vnd.openxmlformats-officedocument.") {
+            ooxml_thumbnail(&mut archive)?
+        } else {
+            return Err(ThumbnailError::Unsupported(format!("{} is not a known ZIP document", mime_type)));
+        };
+
+        // OOXML previews may be WMF or EMF metafiles, which cannot be decoded.
+        let entry = entry
+            .filter(|name| ImageFormat::from_path(name).is_ok())
+            .ok_or(ThumbnailError::NoEmbeddedArt)?;
+        let data = read_entry(&mut archive, &entry)?.ok_or(ThumbnailError::NoEmbeddedArt)?;
+        debug!("Using {} byte preview {:?} from ZIP document", data.len(), entry);
+        decode_embedded_image(data, ctx.limits())
+    }
+}
+
+/// Opens a ZIP archive, rejecting archives with more than `MAX_ENTRIES` entries.
+pub(crate) fn open_archive<R: Read + Seek>(reader: R) -> Result<ZipArchive<R>, ThumbnailError> {
+    let archive = ZipArchive::new(reader)?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(ThumbnailError::LimitsExceeded(format!(
+            "{} archive entries exceeds the maximum of {}",
+            archive.len(),
+            MAX_ENTRIES
+        )));
+    }
+    Ok(archive)
+}
+
+/// Reads the entry `name`, returning `None` when the archive has no such entry.
+/// Entries larger than `MAX_ENTRY_BYTES` are rejected.
+pub(crate) fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Option<Vec<u8>>, ThumbnailError> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if file.size() > MAX_ENTRY_BYTES {
+        return Err(ThumbnailError::LimitsExceeded(format!(
+            "{} bytes in {:?} exceeds the maximum of {}",
+            file.size(),
+            name,
+            MAX_ENTRY_BYTES
+        )));
+    }
+
+    // The declared size may be wrong, so the limit is enforced while reading too.
+    let mut data = Vec::new();
+    file.take(MAX_ENTRY_BYTES + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_ENTRY_BYTES {
+        return Err(ThumbnailError::LimitsExceeded(format!(
+            "{:?} decompresses to more than {} bytes",
+            name, MAX_ENTRY_BYTES
+        )));
+    }
+    Ok(Some(data))
+}
+
+/// Reads the entry `name` as UTF-8 text.
+fn read_text<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Option<String>, ThumbnailError> {
+    Ok(read_entry(archive, name)?.map(|data| String::from_utf8_lossy(&data).into_owned()))
+}
+
+/// Finds the EPUB cover image: the manifest item with the EPUB 3
+/// `cover-image` property, the item named by the EPUB 2 `cover` meta, or an
+/// image item whose id mentions a cover.
+fn epub_cover<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Option<String>, ThumbnailError> {
+    let Some(container) = read_text(archive, "META-INF/container.xml")? else {
+        return Ok(None);
+    };
+    let Some(opf_path) = xml_elements(&container)
+        .into_iter()
+        .find(|e| e.name == "rootfile")
+        .and_then(|e| e.attr("full-path").map(str::to_string))
+    else {
+        return Ok(None);
+    };
+    let Some(opf) = read_text(archive, &opf_path)? else {
+        return Ok(None);
+    };
+
+    let elements = xml_elements(&opf);
+    let items: Vec<&XmlElement> = elements.iter().filter(|e| e.name == "item").collect();
+    let cover_id = elements
+        .iter()
+        .find(|e| e.name == "meta" && e.attr("name") == Some("cover"))
+        .and_then(|e| e.attr("content"));
+    let is_image = |item: &&&XmlElement| item.attr("media-type").is_some_and(|t| t.starts_with("image/"));
+
+    let cover = items
+        .iter()
+        .find(|item| {
+            item.attr("properties")
+                .is_some_and(|p| p.split_whitespace().any(|p| p == "cover-image"))
+        })
+        .or_else(|| items.iter().find(|item| cover_id.is_some() && item.attr("id") == cover_id))
+        .or_else(|| {
+            items
+                .iter()
+                .filter(is_image)
+                .find(|item| item.attr("id").is_some_and(|id| id.to_ascii_lowercase().contains("cover")))
+        });
+    Ok(cover.and_then(|item| item.attr("href")).map(|href| resolve_href(&opf_path, href)))
+}
+
+/// Finds the first image of a comic book archive in natural sort order,
+/// skipping directories, hidden files and macOS resource forks.
+fn first_page<R: Read + Seek>(archive: &ZipArchive<R>) -> Option<String> {
+    archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .filter(|name| !name.rsplit('/').next().is_some_and(|file| file.starts_with('.')))
+        .filter(|name| ImageFormat::from_path(name).is_ok())
+        .min_by(|a, b| natural_cmp(a, b))
+        .map(str::to_string)
+}
+
+/// Finds the OOXML preview through the package relationships.
+fn ooxml_thumbnail<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Option<String>, ThumbnailError> {
+    let target = read_text(archive, "_rels/.rels")?.and_then(|rels| {
+        xml_elements(&rels)
+            .into_iter()
+            .find(|e| e.name == "Relationship" && e.attr("Type") == Some(OOXML_THUMBNAIL_REL))
+            .and_then(|e| e.attr("Target").map(|t| t.trim_start_matches('/').to_string()))
+    });
+    Ok(Some(target.unwrap_or_else(|| OOXML_THUMBNAIL.to_string())))
+}
+
+/// Resolves an href from the manifest at `base` to an archive entry name.
+fn resolve_href(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    let href = percent_decode(href);
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Compares names case-insensitively, treating runs of digits as numbers so
+/// that `page2` sorts before `page10`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let ordering = x
+                    .trim_start_matches('0')
+                    .len()
+                    .cmp(&y.trim_start_matches('0').len())
+                    .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0')));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+/// An XML element's local name and attributes.
+#[derive(Debug)]
+struct XmlElement {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl XmlElement {
+    fn attr(&self, key: &str) -> Option<&str> {
+        self.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Lists the elements of a small XML document in order, ignoring namespaces.
+/// Parsing stops at the first syntax error.
+fn xml_elements(xml: &str) -> Vec<XmlElement> {
+    let mut reader = Reader::from_str(xml);
+    let mut elements = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                let attributes = e
+                    .attributes()
+                    .flatten()
+                    .filter_map(|a| {
+                        let key = String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned();
+                        a.unescape_value().ok().map(|v| (key, v.into_owned()))
+                    })
+                    .collect();
+                elements.push(XmlElement {
+                    name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+                    attributes,
+                });
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                debug!("Stopped parsing XML: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::io::{Cursor, Write};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::{natural_cmp, ZipDocumentGenerator};
+    use crate::error::ThumbnailError;
+    use crate::generators::test_util::{self, png};
+
+    fn generate(data: Vec<u8>, mime_type: &str) -> Result<(u32, u32), ThumbnailError> {
+        test_util::generate(&ZipDocumentGenerator, data, mime_type)
+    }
+
+    fn make_zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_epub_cover() {
+        let container = br#"<?xml version="1.0"?>
+            <container xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+              <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+            </container>"#;
+        let opf = br#"<?xml version="1.0"?>
+            <package xmlns="http://www.idpf.org/2007/opf" xmlns:opf="http://www.idpf.org/2007/opf">
+              <metadata><meta name="cover" content="cover-img"/></metadata>
+              <manifest>
+                <item id="title" href="images/title.png" media-type="image/png"/>
+                <item id="cover-img" href="../images/cover%20art.png" media-type="image/png"/>
+              </manifest>
+            </package>"#;
+        let epub = make_zip(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", container),
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/images/title.png", &png(10, 10)),
+            ("images/cover art.png", &png(12, 18)),
+        ]);
+        assert_eq!(generate(epub, "application/epub+zip").unwrap(), (12, 18));
+
+        let odt = make_zip(&[("mimetype", b"application/vnd.oasis.opendocument.text")]);
+        assert!(matches!(
+            generate(odt, "application/vnd.oasis.opendocument.text"),
+            Err(ThumbnailError::NoEmbeddedArt)
+        ));
+    }
+
+    #[test]
+    fn test_comic_book_first_page() {
+        let cbz = make_zip(&[
+            ("__MACOSX/._page1.png", b"resource fork"),
+            ("Comic/page10.png", &png(10, 20)),
+            ("Comic/page2.png", &png(20, 10)),
+            ("Comic/info.txt", b"not an image"),
+        ]);
+        assert_eq!(generate(cbz, "application/vnd.comicbook+zip").unwrap(), (20, 10));
+
+        assert_eq!(natural_cmp("Page2", "page10"), Ordering::Less);
+        assert_eq!(natural_cmp("a01b", "a1c"), Ordering::Less);
+        assert_eq!(natural_cmp("b", "a100"), Ordering::Greater);
+    }
+}
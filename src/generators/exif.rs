@@ -0,0 +1,193 @@
+use image::{DynamicImage, ImageFormat, ImageReader};
+use log::debug;
+use std::io::{self, BufReader, Cursor, Read, Seek};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+    sizes::ThumbnailSize,
+    tiff::{find_jpeg_exif, tags, TiffReader},
+};
+
+/// MIME types that can carry an EXIF thumbnail.
+pub const MIME_TYPES: &[&str] = &["image/jpeg", "image/tiff"];
+
+/// Registered above external thumbnailers, so the fast path is tried first.
+pub const PRIORITY: i32 = 10;
+
+/// Maximum relative difference between the thumbnail's and the main image's
+/// aspect ratio. Thumbnails letterboxed to 160x120 fail this check.
+const ASPECT_TOLERANCE: f64 = 0.02;
+
+/// Embedded thumbnails larger than this are ignored.
+const MAX_THUMBNAIL_BYTES: u32 = 4 * 1024 * 1024;
+
+/// Uses the JPEG thumbnail stored in IFD1 of the EXIF data, which most cameras
+/// write, instead of decoding the full image.
+///
+/// Only `ThumbnailSize::Small` and `ThumbnailSize::Normal` are served, and only
+/// when the embedded image is large enough and has the same aspect ratio as
+/// the main image. Otherwise the generator declines and the next candidate runs.
+#[derive(Debug, Default)]
+pub struct ExifThumbnailGenerator;
+
+impl ThumbnailGenerator for ExifThumbnailGenerator {
+    fn name(&self) -> &str {
+        "exif"
+    }
+
+    fn uses_source_orientation(&self) -> bool {
+        true
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        if !matches!(ctx.size(), ThumbnailSize::Small | ThumbnailSize::Normal) {
+            return Err(ThumbnailError::Unsupported(format!(
+                "EXIF thumbnails are not used for {} thumbnails",
+                ctx.size()
+            )));
+        }
+
+        let mut reader = source.reader()?;
+        let base = if ctx.mime_type() == "image/jpeg" {
+            find_jpeg_exif(&mut reader).ok().flatten()
+        } else {
+            Some(0)
+        };
+        // Malformed EXIF data is not an error for the image itself.
+        let data = base
+            .and_then(|base| read_exif_thumbnail(reader, base).ok().flatten())
+            .ok_or_else(|| ThumbnailError::Unsupported("No embedded EXIF thumbnail".to_string()))?;
+
+        // A broken embedded thumbnail says nothing about the main image.
+        let mut thumb_reader = ImageReader::with_format(Cursor::new(data), ImageFormat::Jpeg);
+        thumb_reader.limits(ctx.limits().to_image_limits());
+        let thumb = thumb_reader
+            .decode()
+            .map_err(|e| ThumbnailError::Unsupported(format!("Unreadable EXIF thumbnail: {}", e)))?;
+
+        let (width, height) = ImageReader::new(BufReader::new(source.reader()?))
+            .with_guessed_format()?
+            .into_dimensions()?;
+
+        if !is_usable(thumb.width(), thumb.height(), width, height, ctx.size().to_dimension()) {
+            return Err(ThumbnailError::Unsupported(format!(
+                "EXIF thumbnail {}x{} is not usable for a {}x{} image",
+                thumb.width(),
+                thumb.height(),
+                width,
+                height
+            )));
+        }
+
+        debug!("Using {}x{} EXIF thumbnail", thumb.width(), thumb.height());
+        ctx.add_text("Thumb::Image::Width", width.to_string());
+        ctx.add_text("Thumb::Image::Height", height.to_string());
+        Ok(thumb)
+    }
+}
+
+/// Reads the JPEG stored in IFD1 of the TIFF structure at `base`.
+fn read_exif_thumbnail<R: Read + Seek>(reader: R, base: u64) -> io::Result<Option<Vec<u8>>> {
+    let (mut tiff, first_ifd) = TiffReader::new(reader, base)?;
+    let ifds = tiff.read_ifd_chain(first_ifd)?;
+    let Some(ifd1) = ifds.get(1) else {
+        return Ok(None);
+    };
+
+    let offset = ifd1
+        .get(tags::JPEG_INTERCHANGE_FORMAT)
+        .and_then(|e| tiff.value_u32(e));
+    let length = ifd1
+        .get(tags::JPEG_INTERCHANGE_FORMAT_LENGTH)
+        .and_then(|e| tiff.value_u32(e));
+
+    match (offset, length) {
+        (Some(offset), Some(length)) if length > 0 && length <= MAX_THUMBNAIL_BYTES => {
+            Ok(Some(tiff.read_at(offset as u64, length as usize)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Checks that a `thumb_w`x`thumb_h` thumbnail can stand in for a
+/// `main_w`x`main_h` image scaled to fit within `dimension`.
+fn is_usable(thumb_w: u32, thumb_h: u32, main_w: u32, main_h: u32, dimension: u32) -> bool {
+    if thumb_w == 0 || thumb_h == 0 || main_w == 0 || main_h == 0 {
+        return false;
+    }
+
+    let needed = dimension.min(main_w.max(main_h));
+    if thumb_w.max(thumb_h) < needed {
+        return false;
+    }
+
+    let thumb_aspect = thumb_w as f64 / thumb_h as f64;
+    let main_aspect = main_w as f64 / main_h as f64;
+    ((thumb_aspect - main_aspect) / main_aspect).abs() <= ASPECT_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{is_usable, ExifThumbnailGenerator};
+    use crate::generators::{test_util::jpeg, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    /// Builds a JPEG whose EXIF IFD1 holds `thumb`.
+    fn jpeg_with_exif_thumbnail(width: u32, height: u32, thumb: &[u8]) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0: no entries, IFD1 follows at 14.
+        tiff.extend_from_slice(&0u16.to_le_bytes());
+        tiff.extend_from_slice(&14u32.to_le_bytes());
+        // IFD1: JPEGInterchangeFormat and its length, thumbnail data at 44.
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        for (tag, value) in [(0x0201u16, 44u32), (0x0202, thumb.len() as u32)] {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&4u16.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(thumb);
+
+        let main = jpeg(width, height);
+        let mut out = main[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        out.extend_from_slice(b"Exif\0\0");
+        out.extend_from_slice(&tiff);
+        out.extend_from_slice(&main[2..]);
+        out
+    }
+
+    #[test]
+    fn test_uses_embedded_thumbnail() {
+        let data = jpeg_with_exif_thumbnail(800, 600, &jpeg(160, 120));
+        let mut cursor = Cursor::new(data);
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "image/jpeg");
+        let thumb = ExifThumbnailGenerator
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .expect("EXIF thumbnail should be used");
+        assert_eq!((thumb.width(), thumb.height()), (160, 120));
+
+        let mut ctx = GenerateContext::new(ThumbnailSize::Large, "image/jpeg");
+        assert!(ExifThumbnailGenerator
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .is_err());
+    }
+
+    #[test]
+    fn test_is_usable() {
+        // 4:3 camera image with a matching 160x120 thumbnail.
+        assert!(is_usable(160, 120, 4000, 3000, 128));
+        // Too small for a normal thumbnail.
+        assert!(!is_usable(96, 72, 4000, 3000, 128));
+        // Letterboxed 4:3 thumbnail of a 3:2 image.
+        assert!(!is_usable(160, 120, 6000, 4000, 128));
+        // Small main images need no more pixels than they have.
+        assert!(is_usable(100, 75, 100, 75, 128));
+    }
+}
@@ -0,0 +1,356 @@
+use ab_glyph::{point, Font, FontVec, Glyph, PxScale, Rect, ScaleFont};
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, Rgb, RgbImage};
+use log::debug;
+use std::io::{self, Read};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// Font MIME types, under both their current and legacy names.
+pub const MIME_TYPES: &[&str] = &[
+    "font/ttf",
+    "font/otf",
+    "font/sfnt",
+    "font/collection",
+    "font/woff",
+    "application/x-font-ttf",
+    "application/x-font-otf",
+    "application/x-font-ttc",
+    "application/font-sfnt",
+    "application/vnd.ms-opentype",
+    "application/font-woff",
+];
+
+/// Font files larger than this are not read.
+const MAX_FONT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The specimen shown for fonts covering it.
+const SPECIMEN: &str = "Aa";
+
+/// How many representative glyphs are shown for fonts without Latin letters.
+const FALLBACK_GLYPHS: usize = 2;
+
+/// Fraction of the thumbnail the specimen may cover.
+const FILL: f32 = 0.8;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const INK: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// Renders a specimen of TrueType, OpenType and WOFF fonts: "Aa", or for
+/// fonts without Latin letters the first letters (or symbols) their cmap
+/// maps. The first font of a collection is used.
+///
+/// WOFF2 files are declined, as unpacking them needs Brotli and the
+/// reconstruction of transformed glyph tables.
+#[derive(Debug, Default)]
+pub struct FontSpecimenGenerator;
+
+impl ThumbnailGenerator for FontSpecimenGenerator {
+    fn name(&self) -> &str {
+        "font-specimen"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut data = Vec::new();
+        source.reader()?.take(MAX_FONT_BYTES + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_FONT_BYTES {
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "Font file exceeds {} bytes",
+                MAX_FONT_BYTES
+            )));
+        }
+
+        let data = match data.get(..4) {
+            Some(b"wOFF") => woff_to_sfnt(&data)?,
+            Some(b"wOF2") => return Err(ThumbnailError::Unsupported("WOFF2 fonts".to_string())),
+            _ => data,
+        };
+        let font = FontVec::try_from_vec_and_index(data, 0)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let text = specimen_text(&font).ok_or_else(|| ThumbnailError::Unsupported("Font has no drawable glyphs".to_string()))?;
+        debug!("Rendering font specimen {:?}", text);
+
+        // Rendered at twice the thumbnail size so the downscale smooths the glyphs.
+        let dimension = ctx.size().to_dimension() * 2;
+        Ok(DynamicImage::ImageRgb8(render_specimen(&font, &text, dimension)))
+    }
+}
+
+/// Picks the specimen: "Aa" when the font has both letters, otherwise the
+/// first letters it maps, otherwise the first glyphs with an outline.
+fn specimen_text(font: &FontVec) -> Option<String> {
+    if SPECIMEN.chars().all(|c| has_outline(font, c)) {
+        return Some(SPECIMEN.to_string());
+    }
+
+    let mut chars: Vec<char> = font
+        .codepoint_ids()
+        .map(|(_, c)| c)
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect();
+    chars.sort_unstable();
+    chars.dedup();
+
+    let pick = |filter: &dyn Fn(char) -> bool| -> String {
+        chars
+            .iter()
+            .copied()
+            .filter(|&c| filter(c) && has_outline(font, c))
+            .take(FALLBACK_GLYPHS)
+            .collect()
+    };
+    Some(pick(&|c| c.is_alphabetic()))
+        .filter(|s| !s.is_empty())
+        .or_else(|| Some(pick(&|_| true)))
+        .filter(|s| !s.is_empty())
+}
+
+fn has_outline(font: &FontVec, c: char) -> bool {
+    let id = font.glyph_id(c);
+    id.0 != 0 && font.outline(id).is_some()
+}
+
+/// Lays out `text` on one line at `scale`, returning its glyphs and the
+/// union of their pixel bounds.
+fn layout(font: &FontVec, text: &str, scale: PxScale) -> (Vec<Glyph>, Option<Rect>) {
+    let scaled = font.as_scaled(scale);
+    let mut glyphs = Vec::new();
+    let mut bounds: Option<Rect> = None;
+    let mut x = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            x += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(x, 0.0));
+        x += scaled.h_advance(id);
+        previous = Some(id);
+
+        if let Some(outline) = font.outline_glyph(glyph.clone()) {
+            let b = outline.px_bounds();
+            bounds = Some(match bounds {
+                Some(u) => Rect {
+                    min: point(u.min.x.min(b.min.x), u.min.y.min(b.min.y)),
+                    max: point(u.max.x.max(b.max.x), u.max.y.max(b.max.y)),
+                },
+                None => b,
+            });
+        }
+        glyphs.push(glyph);
+    }
+    (glyphs, bounds)
+}
+
+/// Draws `text` centred on a square canvas, scaled to cover `FILL` of it.
+fn render_specimen(font: &FontVec, text: &str, dimension: u32) -> RgbImage {
+    let mut canvas = RgbImage::from_pixel(dimension, dimension, BACKGROUND);
+    let reference = PxScale::from(100.0);
+    let Some(bounds) = layout(font, text, reference).1 else {
+        return canvas;
+    };
+
+    let target = dimension as f32 * FILL;
+    let factor = (target / bounds.width().max(1.0)).min(target / bounds.height().max(1.0));
+    let (glyphs, Some(bounds)) = layout(font, text, PxScale::from(100.0 * factor)) else {
+        return canvas;
+    };
+    let offset_x = (dimension as f32 - bounds.width()) / 2.0 - bounds.min.x;
+    let offset_y = (dimension as f32 - bounds.height()) / 2.0 - bounds.min.y;
+
+    for mut glyph in glyphs {
+        glyph.position = point(glyph.position.x + offset_x, glyph.position.y + offset_y);
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let b = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let x = b.min.x as i64 + x as i64;
+            let y = b.min.y as i64 + y as i64;
+            if x < 0 || y < 0 || x >= dimension as i64 || y >= dimension as i64 {
+                return;
+            }
+            let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+            for i in 0..3 {
+                let blended = pixel[i] as f32 + (INK[i] as f32 - pixel[i] as f32) * coverage.min(1.0);
+                pixel[i] = blended.round() as u8;
+            }
+        });
+    }
+    canvas
+}
+
+/// Unpacks a WOFF 1.0 file into the sfnt font it wraps.
+fn woff_to_sfnt(woff: &[u8]) -> Result<Vec<u8>, ThumbnailError> {
+    let invalid = |msg: &str| ThumbnailError::Io(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()));
+    let u32_at = |pos: usize| {
+        woff.get(pos..pos + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| invalid("Truncated WOFF file"))
+    };
+
+    let flavor = u32_at(4)?;
+    let num_tables = woff.get(12..14).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(|| invalid("Truncated WOFF file"))?;
+    if num_tables > 4095 {
+        return Err(invalid("Too many WOFF tables"));
+    }
+    let total_size = u32_at(16)? as u64;
+    if total_size > MAX_FONT_BYTES {
+        return Err(ThumbnailError::LimitsExceeded(format!("WOFF font unpacks to {} bytes", total_size)));
+    }
+
+    // The sfnt offset table, with the binary search fields derived from the table count.
+    let entry_selector = (num_tables.max(1)).ilog2() as u16;
+    let search_range = (1u16 << entry_selector) * 16;
+    let mut sfnt = Vec::with_capacity(total_size as usize);
+    sfnt.extend(flavor.to_be_bytes());
+    sfnt.extend(num_tables.to_be_bytes());
+    sfnt.extend(search_range.to_be_bytes());
+    sfnt.extend(entry_selector.to_be_bytes());
+    sfnt.extend((num_tables * 16).saturating_sub(search_range).to_be_bytes());
+
+    let mut offset = 12 + num_tables as usize * 16;
+    let mut tables = Vec::with_capacity(num_tables as usize);
+    for i in 0..num_tables as usize {
+        let entry = 44 + i * 20;
+        let tag = u32_at(entry)?;
+        let data_offset = u32_at(entry + 4)? as usize;
+        let compressed_len = u32_at(entry + 8)? as usize;
+        let original_len = u32_at(entry + 12)? as usize;
+        let checksum = u32_at(entry + 16)?;
+        // Tables may share compressed data, so the unpacked total is bounded
+        // before each one is inflated.
+        if (offset + original_len) as u64 > total_size {
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "WOFF tables unpack to more than the declared {} bytes",
+                total_size
+            )));
+        }
+
+        let stored = woff
+            .get(data_offset..data_offset.saturating_add(compressed_len))
+            .ok_or_else(|| invalid("WOFF table outside the file"))?;
+        let table = if compressed_len < original_len {
+            let mut table = Vec::with_capacity(original_len);
+            ZlibDecoder::new(stored).take(original_len as u64).read_to_end(&mut table)?;
+            table
+        } else {
+            stored.to_vec()
+        };
+        if table.len() != original_len {
+            return Err(invalid("WOFF table has the wrong length"));
+        }
+
+        sfnt.extend(tag.to_be_bytes());
+        sfnt.extend(checksum.to_be_bytes());
+        sfnt.extend((offset as u32).to_be_bytes());
+        sfnt.extend((original_len as u32).to_be_bytes());
+        offset += original_len.next_multiple_of(4);
+        tables.push(table);
+    }
+    for table in tables {
+        sfnt.extend(table);
+        sfnt.resize(sfnt.len().next_multiple_of(4), 0);
+    }
+    Ok(sfnt)
+}
+
+#[cfg(test)]
+mod tests {
+    use ab_glyph::FontVec;
+    use std::io::{Cursor, Write};
+
+    use super::{specimen_text, woff_to_sfnt, FontSpecimenGenerator};
+    use crate::error::ThumbnailError;
+    use crate::generators::{text::FONT, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    /// Wraps an sfnt font in WOFF, compressing the tables that shrink.
+    fn to_woff(sfnt: &[u8]) -> Vec<u8> {
+        let num_tables = u16::from_be_bytes([sfnt[4], sfnt[5]]) as usize;
+        let mut directory = Vec::new();
+        let mut data = Vec::new();
+        let data_start = 44 + num_tables * 20;
+        for i in 0..num_tables {
+            let record = &sfnt[12 + i * 16..28 + i * 16];
+            let offset = u32::from_be_bytes(record[8..12].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(record[12..16].try_into().unwrap()) as usize;
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&sfnt[offset..offset + len]).unwrap();
+            let mut compressed = encoder.finish().unwrap();
+            if compressed.len() >= len {
+                compressed = sfnt[offset..offset + len].to_vec();
+            }
+
+            directory.extend(&record[..4]);
+            directory.extend(((data_start + data.len()) as u32).to_be_bytes());
+            directory.extend((compressed.len() as u32).to_be_bytes());
+            directory.extend((len as u32).to_be_bytes());
+            directory.extend(&record[4..8]);
+            data.extend(compressed);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+
+        let mut woff = b"wOFF".to_vec();
+        woff.extend(&sfnt[..4]);
+        woff.extend(((data_start + data.len()) as u32).to_be_bytes());
+        woff.extend((num_tables as u16).to_be_bytes());
+        woff.extend([0, 0]);
+        woff.extend((sfnt.len() as u32).to_be_bytes());
+        woff.extend([0u8; 24]);
+        woff.extend(directory);
+        woff.extend(data);
+        woff
+    }
+
+    #[test]
+    fn test_font_specimen() {
+        let font = FontVec::try_from_vec(FONT.to_vec()).unwrap();
+        assert_eq!(specimen_text(&font).unwrap(), "Aa");
+
+        let sfnt = woff_to_sfnt(&to_woff(FONT)).unwrap();
+        assert!(FontVec::try_from_vec(sfnt).is_ok());
+
+        let mut cursor = Cursor::new(to_woff(FONT));
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "font/woff");
+        let img = FontSpecimenGenerator
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(img.dimensions(), (256, 256));
+        let inked = img.pixels().filter(|p| p.0[0] < 128).count();
+        assert!(inked > 256 * 256 / 20, "only {} dark pixels", inked);
+        assert_eq!(img.get_pixel(2, 2).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_woff_repeated_tables() {
+        // Every directory entry points at the same stream of 1 MiB of zeros.
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&vec![0; 1 << 20]).unwrap();
+        let stream = encoder.finish().unwrap();
+
+        let num_tables = 64u16;
+        let data_start = 44 + num_tables as usize * 20;
+        let mut woff = b"wOFF\0\x01\0\0".to_vec();
+        woff.extend(((data_start + stream.len()) as u32).to_be_bytes());
+        woff.extend(num_tables.to_be_bytes());
+        woff.extend([0, 0]);
+        woff.extend((4u32 << 20).to_be_bytes());
+        woff.extend([0u8; 24]);
+        for i in 0..num_tables as u32 {
+            woff.extend(i.to_be_bytes());
+            woff.extend((data_start as u32).to_be_bytes());
+            woff.extend((stream.len() as u32).to_be_bytes());
+            woff.extend((1u32 << 20).to_be_bytes());
+            woff.extend(0u32.to_be_bytes());
+        }
+        woff.extend(stream);
+
+        assert!(matches!(woff_to_sfnt(&woff), Err(ThumbnailError::LimitsExceeded(_))));
+    }
+}
@@ -0,0 +1,142 @@
+use image::DynamicImage;
+use log::debug;
+use std::{cmp::Reverse, io::Read};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{decode_embedded_image, GenerateContext, Source, ThumbnailGenerator},
+    icon::{icns_entries, ico_entries, EntryData, IconEntry},
+};
+
+/// MIME types of Windows icons and cursors and Apple icons.
+pub const MIME_TYPES: &[&str] = &[
+    "image/x-icon",
+    "image/vnd.microsoft.icon",
+    "image/x-win-bitmap",
+    "image/x-icns",
+];
+
+/// Icon files larger than this are rejected.
+const MAX_ICON_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Picks one image out of ICO, CUR and ICNS files instead of the first or
+/// largest one: the smallest entry covering the requested size, or the
+/// largest entry when none does, preferring higher bit depths among entries
+/// of the same size. PNG and BMP entries are supported, as are the packed
+/// RGB and ARGB entries of ICNS files; JPEG 2000 entries are skipped.
+#[derive(Debug, Default)]
+pub struct IconGenerator;
+
+impl ThumbnailGenerator for IconGenerator {
+    fn name(&self) -> &str {
+        "icon"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut data = Vec::new();
+        source.reader()?.take(MAX_ICON_BYTES + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_ICON_BYTES {
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "Icon file exceeds {} bytes",
+                MAX_ICON_BYTES
+            )));
+        }
+
+        let mut entries = if data.starts_with(b"icns") {
+            icns_entries(&data)?
+        } else {
+            ico_entries(&data)?
+        };
+        order_entries(&mut entries, ctx.size().to_dimension());
+
+        for entry in entries {
+            let (width, height, bit_depth) = (entry.width, entry.height, entry.bit_depth);
+            match decode_entry(entry, ctx) {
+                Ok(img) => {
+                    debug!("Using {}x{} {}-bit icon entry", width, height, bit_depth);
+                    return Ok(img);
+                }
+                Err(e) => debug!("Skipping unreadable {}x{} icon entry: {}", width, height, e),
+            }
+        }
+
+        Err(ThumbnailError::Unsupported("No usable icon entry".to_string()))
+    }
+}
+
+/// Sorts entries by preference: the smallest one covering `dimension` first,
+/// then the remaining ones from largest to smallest, the deepest first among
+/// entries of the same size.
+fn order_entries(entries: &mut [IconEntry<'_>], dimension: u32) {
+    entries.sort_by_key(|e| {
+        let area = e.width as u64 * e.height as u64;
+        let covers = e.width.max(e.height) >= dimension;
+        (!covers, if covers { area } else { u64::MAX - area }, Reverse(e.bit_depth))
+    });
+}
+
+fn decode_entry(entry: IconEntry<'_>, ctx: &GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+    ctx.limits().check(entry.width, entry.height, 4)?;
+    match entry.data {
+        EntryData::Png(data) => decode_embedded_image(data.to_vec(), ctx.limits()),
+        EntryData::Ico(data) => decode_embedded_image(data, ctx.limits()),
+        _ => entry
+            .unpack()?
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| ThumbnailError::Unsupported("Invalid ICNS entry".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::IconGenerator;
+    use crate::generators::{test_util::png, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    /// A white 32-bit or black 24-bit BMP entry with an all-opaque mask.
+    fn bmp(size: u32, bit_depth: u16) -> Vec<u8> {
+        let fill = if bit_depth == 32 { 0xFF } else { 0 };
+        let mut out = 40u32.to_le_bytes().to_vec();
+        out.extend((size as i32).to_le_bytes());
+        out.extend((size as i32 * 2).to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(bit_depth.to_le_bytes());
+        out.extend([0u8; 24]);
+        out.extend(vec![fill; (size * size * bit_depth as u32 / 8) as usize]);
+        out.extend(vec![0; (size * size.div_ceil(32) * 4) as usize]);
+        out
+    }
+
+    fn ico(images: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![0, 0, 1, 0];
+        out.extend((images.len() as u16).to_le_bytes());
+        let mut offset = 6 + 16 * images.len() as u32;
+        for image in images {
+            out.extend([0; 8]);
+            out.extend((image.len() as u32).to_le_bytes());
+            out.extend(offset.to_le_bytes());
+            offset += image.len() as u32;
+        }
+        for image in images {
+            out.extend(image);
+        }
+        out
+    }
+
+    #[test]
+    fn test_best_entry() {
+        let file = ico(&[bmp(16, 32), bmp(128, 24), png(256, 256), bmp(128, 32), png(48, 48)]);
+        let generate = |size| {
+            let mut cursor = Cursor::new(file.clone());
+            let mut ctx = GenerateContext::new(size, "image/x-icon");
+            let img = IconGenerator.generate(Source::Reader(&mut cursor), &mut ctx).unwrap();
+            (img.width(), img.to_rgba8().get_pixel(0, 0).0[0])
+        };
+        // 128 covers the normal size; the 32-bit BMP wins over the 24-bit one.
+        assert_eq!(generate(ThumbnailSize::Normal), (128, 255));
+        assert_eq!(generate(ThumbnailSize::Large), (256, 0));
+        assert_eq!(generate(ThumbnailSize::XLarge), (256, 0));
+    }
+}
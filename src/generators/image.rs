@@ -0,0 +1,74 @@
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use log::debug;
+use std::io::BufReader;
+
+use crate::{
+    animation::{decode_animated, is_animatable},
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// MIME types the built-in decoder is registered for.
+pub const MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/apng",
+    "image/webp",
+    "image/tiff",
+    "image/bmp",
+    "image/avif",
+    "image/x-tga",
+    "image/x-targa",
+    "image/vnd.radiance",
+    "image/x-exr",
+    "image/x-portable-bitmap",
+    "image/x-portable-graymap",
+    "image/x-portable-pixmap",
+    "image/x-portable-anymap",
+    "image/x-qoi",
+];
+
+/// Decodes raster images with the `image` crate.
+///
+/// The full-size image is returned; the pipeline scales it to the requested
+/// size while preserving the aspect ratio. For animated GIF, APNG and WebP
+/// files a single frame is chosen according to the context's `FramePolicy`.
+#[derive(Debug, Default)]
+pub struct ImageGenerator;
+
+impl ThumbnailGenerator for ImageGenerator {
+    fn name(&self) -> &str {
+        "image"
+    }
+
+    fn uses_source_orientation(&self) -> bool {
+        true
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        debug!("Decoding {:?} with the built-in image thumbnailer", source.path());
+        let format = ImageReader::new(BufReader::new(source.reader()?))
+            .with_guessed_format()?
+            .format();
+        if let Some(format) = format.filter(|f| is_animatable(*f)) {
+            if let Some(img) = decode_animated(&mut source, format, ctx)? {
+                ctx.add_text("Thumb::Image::Width", img.width().to_string());
+                ctx.add_text("Thumb::Image::Height", img.height().to_string());
+                return Ok(img);
+            }
+        }
+
+        let mut reader = ImageReader::new(BufReader::new(source.reader()?)).with_guessed_format()?;
+        reader.limits(ctx.limits().to_image_limits());
+        let mut decoder = reader.into_decoder()?;
+        if let Some(profile) = decoder.icc_profile()? {
+            ctx.set_icc_profile(profile);
+        }
+        let img = DynamicImage::from_decoder(decoder)?;
+
+        ctx.add_text("Thumb::Image::Width", img.width().to_string());
+        ctx.add_text("Thumb::Image::Height", img.height().to_string());
+        Ok(img)
+    }
+}
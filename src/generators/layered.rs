@@ -0,0 +1,160 @@
+use image::DynamicImage;
+use log::debug;
+use std::io::{BufReader, Read, Seek};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{
+        decode_embedded_image,
+        document::{open_archive, read_entry},
+        GenerateContext, Source, ThumbnailGenerator,
+    },
+    limits::DecodeLimits,
+    psd::Psd,
+    xcf,
+};
+
+const PSD: &[&str] = &["image/vnd.adobe.photoshop", "image/x-psd", "application/x-photoshop"];
+
+const XCF: &str = "image/x-xcf";
+
+const KRITA: &str = "application/x-krita";
+
+const OPENRASTER: &str = "image/openraster";
+
+/// MIME types of the layered image formats handled.
+pub const MIME_TYPES: &[&str] = &[
+    "image/vnd.adobe.photoshop",
+    "image/x-psd",
+    "application/x-photoshop",
+    "image/x-xcf",
+    "application/x-krita",
+    "image/openraster",
+];
+
+/// The full size composite Krita and OpenRaster files carry.
+const MERGED_IMAGE: &str = "mergedimage.png";
+
+/// Thumbnails images of layered formats without layer rendering: the merged
+/// composite of PSD files (or their JPEG thumbnail resource when it covers
+/// the requested size), the stored previews of Krita and OpenRaster files,
+/// and a flattening of the visible layers of GIMP XCF files.
+#[derive(Debug, Default)]
+pub struct LayeredImageGenerator;
+
+impl ThumbnailGenerator for LayeredImageGenerator {
+    fn name(&self) -> &str {
+        "layered-image"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut reader = BufReader::new(source.reader()?);
+        let dimension = ctx.size().to_dimension();
+        let mime_type = ctx.mime_type();
+
+        if PSD.contains(&mime_type) {
+            psd_image(&mut reader, dimension, ctx.limits())
+        } else if mime_type == XCF {
+            xcf::flatten(&mut reader, ctx.limits())
+        } else if mime_type == KRITA {
+            zip_preview(reader, "preview.png", dimension, ctx.limits())
+        } else if mime_type == OPENRASTER {
+            zip_preview(reader, "Thumbnails/thumbnail.png", dimension, ctx.limits())
+        } else {
+            Err(ThumbnailError::Unsupported(format!("{} is not a layered image format", mime_type)))
+        }
+    }
+}
+
+/// Uses the thumbnail resource when it covers `dimension`, the composite
+/// otherwise, falling back to the thumbnail if the composite cannot be read.
+fn psd_image<R: Read + Seek>(reader: &mut R, dimension: u32, limits: &DecodeLimits) -> Result<DynamicImage, ThumbnailError> {
+    let psd = Psd::open(reader)?;
+    let thumbnail = psd
+        .thumbnail(reader)
+        .ok()
+        .flatten()
+        .and_then(|data| decode_embedded_image(data, limits).ok());
+    if let Some(thumbnail) = thumbnail.as_ref().filter(|t| t.width().max(t.height()) >= dimension) {
+        debug!("Using {}x{} PSD thumbnail resource", thumbnail.width(), thumbnail.height());
+        return Ok(thumbnail.clone());
+    }
+
+    match psd.composite(reader, limits) {
+        Ok(composite) => Ok(composite),
+        Err(e) => {
+            debug!("Could not read PSD composite: {}", e);
+            thumbnail.ok_or(e)
+        }
+    }
+}
+
+/// Uses the small preview of a Krita or OpenRaster file when it covers
+/// `dimension`, the full size `mergedimage.png` otherwise or when the
+/// preview cannot be decoded.
+fn zip_preview<R: Read + Seek>(
+    reader: R,
+    preview: &str,
+    dimension: u32,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, ThumbnailError> {
+    let mut archive = open_archive(reader)?;
+    let preview = read_entry(&mut archive, preview)?.and_then(|data| match decode_embedded_image(data, limits) {
+        Ok(img) => Some(img),
+        Err(e) => {
+            debug!("Could not decode stored preview: {}", e);
+            None
+        }
+    });
+    if let Some(preview) = preview.as_ref().filter(|p| p.width().max(p.height()) >= dimension) {
+        return Ok(preview.clone());
+    }
+
+    match read_entry(&mut archive, MERGED_IMAGE)? {
+        Some(data) => decode_embedded_image(data, limits),
+        None => preview.ok_or(ThumbnailError::NoEmbeddedArt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::LayeredImageGenerator;
+    use crate::generators::{test_util::png, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    fn ora(thumbnail: Vec<u8>) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in [
+            ("mimetype", b"image/openraster".to_vec()),
+            ("Thumbnails/thumbnail.png", thumbnail),
+            ("mergedimage.png", png(1024, 512)),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn generate(ora: &[u8], size: ThumbnailSize) -> u32 {
+        let mut cursor = Cursor::new(ora);
+        let mut ctx = GenerateContext::new(size, "image/openraster");
+        let img = LayeredImageGenerator
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .unwrap();
+        img.width()
+    }
+
+    #[test]
+    fn test_openraster_preview() {
+        let file = ora(png(256, 128));
+        assert_eq!(generate(&file, ThumbnailSize::Large), 256);
+        assert_eq!(generate(&file, ThumbnailSize::XLarge), 1024);
+
+        // A corrupt preview falls back to the merged image.
+        let corrupt = ora(b"\x89PNG\r\n\x1a\nbroken".to_vec());
+        assert_eq!(generate(&corrupt, ThumbnailSize::Large), 1024);
+    }
+}
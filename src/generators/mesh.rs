@@ -0,0 +1,299 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use log::debug;
+use std::io::BufReader;
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+    mesh::{read_mesh, Mesh, MeshFormat},
+};
+
+const STL: &[&str] = &[
+    "model/stl",
+    "model/x.stl-ascii",
+    "model/x.stl-binary",
+    "application/sla",
+    "application/vnd.ms-pki.stl",
+];
+
+const OBJ: &str = "model/obj";
+
+const PLY: &[&str] = &["model/x-ply", "application/x-ply"];
+
+/// MIME types of the mesh formats handled.
+pub const MIME_TYPES: &[&str] = &[
+    "model/stl",
+    "model/x.stl-ascii",
+    "model/x.stl-binary",
+    "application/sla",
+    "application/vnd.ms-pki.stl",
+    "model/obj",
+    "model/x-ply",
+    "application/x-ply",
+];
+
+/// The color of the lit surface.
+const SURFACE: [f32; 3] = [0.63, 0.71, 0.82];
+
+/// Light reaching faces turned away from the light.
+const AMBIENT: f32 = 0.25;
+
+/// Share of the canvas the model's projection fills.
+const FILL: f32 = 0.9;
+
+/// Renders 3D meshes on the CPU: the model is framed by an isometric camera
+/// looking down from the front right, lit with Lambert shading, and drawn
+/// with a depth buffer onto a transparent square canvas at twice the
+/// requested dimension.
+///
+/// STL and PLY models are taken to be Z-up as is usual for 3D printing, OBJ
+/// models Y-up. Meshes with more than `max_triangles` triangles, or whose
+/// triangles' bounding boxes on the canvas add up to more than
+/// `max_fill_pixels` pixels, fail with `ThumbnailError::LimitsExceeded`.
+#[derive(Debug)]
+pub struct MeshPreviewGenerator {
+    pub max_triangles: usize,
+    pub max_fill_pixels: u64,
+}
+
+impl Default for MeshPreviewGenerator {
+    fn default() -> Self {
+        MeshPreviewGenerator {
+            max_triangles: 2_000_000,
+            max_fill_pixels: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl ThumbnailGenerator for MeshPreviewGenerator {
+    fn name(&self) -> &str {
+        "mesh-preview"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mime_type = ctx.mime_type();
+        let format = if STL.contains(&mime_type) {
+            MeshFormat::Stl
+        } else if mime_type == OBJ {
+            MeshFormat::Obj
+        } else if PLY.contains(&mime_type) {
+            MeshFormat::Ply
+        } else {
+            return Err(ThumbnailError::Unsupported(format!("{} is not a mesh format", mime_type)));
+        };
+
+        let mesh = read_mesh(BufReader::new(source.reader()?), format, self.max_triangles)?;
+        if mesh.triangles.is_empty() {
+            return Err(ThumbnailError::Unsupported("Mesh has no triangles".to_string()));
+        }
+        let size = ctx.size().to_dimension() * 2;
+        ctx.limits().check(size, size, 4)?;
+        debug!("Rendering mesh of {} triangles", mesh.triangles.len());
+        let image = render(&mesh, format != MeshFormat::Obj, size, self.max_fill_pixels)?;
+        Ok(DynamicImage::ImageRgba8(image))
+    }
+}
+
+type Vec3 = [f32; 3];
+
+fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn normalize(a: Vec3) -> Vec3 {
+    let len = dot(a, a).sqrt();
+    [a[0] / len, a[1] / len, a[2] / len]
+}
+
+/// Draws `mesh` onto a `size` square canvas, unless that means testing more
+/// than `max_fill_pixels` pixels.
+fn render(mesh: &Mesh, z_up: bool, size: u32, max_fill_pixels: u64) -> Result<RgbaImage, ThumbnailError> {
+    // The isometric view direction and screen axes in a Z-up world.
+    let toward_camera = normalize([1.0, -1.0, 1.0]);
+    let right = normalize([1.0, 1.0, 0.0]);
+    let up = cross(right, [-toward_camera[0], -toward_camera[1], -toward_camera[2]]);
+    let light = normalize([0.4, -0.9, 1.2]);
+
+    let world = |v: Vec3| if z_up { v } else { [v[0], -v[2], v[1]] };
+    let triangles: Vec<[Vec3; 3]> = mesh
+        .triangles
+        .iter()
+        .filter_map(|t| {
+            let corner = |i: usize| mesh.vertices.get(t[i] as usize).map(|&v| world(v));
+            let corners = [corner(0)?, corner(1)?, corner(2)?];
+            corners.iter().flatten().all(|c| c.is_finite()).then_some(corners)
+        })
+        .collect();
+
+    // Projects to (screen x, screen y, depth toward the camera).
+    let project = |v: Vec3| [dot(v, right), dot(v, up), dot(v, toward_camera)];
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for point in triangles.iter().flatten().map(|&v| project(v)) {
+        for axis in 0..2 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+    let extent = (max[0] - min[0]).max(max[1] - min[1]).max(f32::EPSILON);
+    let scale = size as f32 * FILL / extent;
+    let center = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0];
+    let to_canvas = |v: Vec3| {
+        let p = project(v);
+        [
+            size as f32 / 2.0 + (p[0] - center[0]) * scale,
+            size as f32 / 2.0 - (p[1] - center[1]) * scale,
+            p[2],
+        ]
+    };
+
+    // Every pixel of each triangle's bounding box is tested, so a few
+    // million canvas-sized triangles would take hours.
+    let fill_pixels: u64 = triangles
+        .iter()
+        .map(|corners| {
+            let (x0, x1, y0, y1) = bounds(corners.map(to_canvas), size, size);
+            (x1 - x0) as u64 * (y1 - y0) as u64
+        })
+        .sum();
+    if fill_pixels > max_fill_pixels {
+        return Err(ThumbnailError::LimitsExceeded(format!(
+            "Mesh covers {} pixels, more than {}",
+            fill_pixels, max_fill_pixels
+        )));
+    }
+
+    let mut image = RgbaImage::new(size, size);
+    let mut depth = vec![f32::MIN; size as usize * size as usize];
+    for corners in &triangles {
+        let normal = cross(sub(corners[1], corners[0]), sub(corners[2], corners[0]));
+        if dot(normal, normal) == 0.0 {
+            continue;
+        }
+        // Shade both sides alike, as mesh winding is often inconsistent.
+        let mut normal = normalize(normal);
+        if dot(normal, toward_camera) < 0.0 {
+            normal = [-normal[0], -normal[1], -normal[2]];
+        }
+        let shade = AMBIENT + (1.0 - AMBIENT) * dot(normal, light).max(0.0);
+        let color = Rgba(
+            [SURFACE[0] * shade, SURFACE[1] * shade, SURFACE[2] * shade, 1.0].map(|c| (c * 255.0).round() as u8),
+        );
+
+        let [a, b, c] = corners.map(to_canvas);
+        fill_triangle(&mut image, &mut depth, [a, b, c], color);
+    }
+    Ok(image)
+}
+
+/// The pixel range `(x0, x1, y0, y1)` covered by the bounding box of a
+/// triangle, clipped to a `width`x`height` canvas.
+fn bounds([a, b, c]: [Vec3; 3], width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let clamp = |v: f32, max: u32| (v.max(0.0) as u32).min(max);
+    let (x0, x1) = (clamp(a[0].min(b[0]).min(c[0]), width), clamp(a[0].max(b[0]).max(c[0]).ceil(), width));
+    let (y0, y1) = (clamp(a[1].min(b[1]).min(c[1]), height), clamp(a[1].max(b[1]).max(c[1]).ceil(), height));
+    (x0, x1, y0, y1)
+}
+
+/// Fills the pixels whose centers lie inside the triangle and in front of
+/// what is already drawn.
+fn fill_triangle(image: &mut RgbaImage, depth: &mut [f32], [a, b, c]: [Vec3; 3], color: Rgba<u8>) {
+    let edge = |p: Vec3, q: Vec3, x: f32, y: f32| (q[0] - p[0]) * (y - p[1]) - (q[1] - p[1]) * (x - p[0]);
+    let area = edge(a, b, c[0], c[1]);
+    if area == 0.0 {
+        return;
+    }
+
+    let (width, height) = image.dimensions();
+    let (x0, x1, y0, y1) = bounds([a, b, c], width, height);
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let wa = edge(b, c, px, py) / area;
+            let wb = edge(c, a, px, py) / area;
+            let wc = edge(a, b, px, py) / area;
+            if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                continue;
+            }
+            let z = wa * a[2] + wb * b[2] + wc * c[2];
+            let index = (y * width + x) as usize;
+            if z > depth[index] {
+                depth[index] = z;
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::MeshPreviewGenerator;
+    use crate::error::ThumbnailError;
+    use crate::generators::{GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    /// A unit cube as an OBJ file.
+    const CUBE: &[u8] = b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+        f 1 2 3 4\nf 5 6 7 8\nf 1 2 6 5\nf 2 3 7 6\nf 3 4 8 7\nf 4 1 5 8\n";
+
+    #[test]
+    fn test_render_cube() {
+        let mut cursor = Cursor::new(CUBE);
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "model/obj");
+        let img = MeshPreviewGenerator::default()
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(img.dimensions(), (256, 256));
+        // The corners stay transparent and the three visible faces are
+        // shaded differently.
+        assert_eq!(img.get_pixel(2, 2).0[3], 0);
+        let top = img.get_pixel(128, 70).0;
+        let left = img.get_pixel(80, 160).0;
+        let right = img.get_pixel(176, 160).0;
+        assert!([top, left, right].iter().all(|p| p[3] == 255));
+        assert!(top != left && left != right && top != right);
+
+        let mut cursor = Cursor::new(CUBE);
+        let capped = MeshPreviewGenerator {
+            max_triangles: 4,
+            ..Default::default()
+        }
+        .generate(Source::Reader(&mut cursor), &mut ctx);
+        assert!(matches!(capped, Err(ThumbnailError::LimitsExceeded(_))));
+    }
+
+    #[test]
+    fn test_fill_limit() {
+        // Canvas-sized triangles stacked on top of each other, as a hostile
+        // STL would repeat them a few million times.
+        let mut stl = b"solid stack\n".to_vec();
+        for _ in 0..100 {
+            stl.extend_from_slice(b"facet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\n");
+        }
+        stl.extend_from_slice(b"endsolid\n");
+
+        let generate = |generator: MeshPreviewGenerator| {
+            let mut cursor = Cursor::new(stl.clone());
+            let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "model/stl");
+            generator.generate(Source::Reader(&mut cursor), &mut ctx)
+        };
+        assert!(generate(MeshPreviewGenerator::default()).is_ok());
+        let capped = generate(MeshPreviewGenerator {
+            max_fill_pixels: 1_000_000,
+            ..Default::default()
+        });
+        assert!(matches!(capped, Err(ThumbnailError::LimitsExceeded(_))));
+    }
+}
@@ -0,0 +1,234 @@
+use image::DynamicImage;
+use log::debug;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+use crate::{
+    ebml::{children, find_element, ids, read_data, read_element_header, read_uint, Element},
+    error::ThumbnailError,
+    generators::{decode_embedded_image, GenerateContext, Source, ThumbnailGenerator},
+    isobmff::stream_len,
+};
+
+/// Matroska and WebM MIME types that can carry attachments.
+pub const MIME_TYPES: &[&str] = &["video/x-matroska", "audio/x-matroska", "video/webm", "audio/webm"];
+
+/// Attachments larger than this are not read.
+const MAX_COVER_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Attachment names suggested by the Matroska specification for cover art,
+/// best first: the portrait and landscape covers, then their small variants.
+const COVER_NAMES: &[&str] = &["cover", "cover_land", "small_cover", "small_cover_land"];
+
+/// Extensions of image attachments that may be used when none is named as
+/// a cover.
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+/// Uses the cover art attached to Matroska and WebM files, such as the
+/// `cover.jpg` attachments written by mkvmerge.
+///
+/// Attachments named after the specification's cover names are preferred,
+/// then any other image attachment. Files without one fail with
+/// `ThumbnailError::NoEmbeddedArt`.
+#[derive(Debug, Default)]
+pub struct MatroskaCoverGenerator;
+
+/// An attachment whose data has not been read yet.
+#[derive(Debug)]
+struct Attachment {
+    name: String,
+    mime_type: String,
+    data: Element,
+}
+
+impl ThumbnailGenerator for MatroskaCoverGenerator {
+    fn name(&self) -> &str {
+        "matroska-cover"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut reader = BufReader::new(source.reader()?);
+        let attachments = find_attachments(&mut reader).unwrap_or_else(|e| {
+            debug!("Could not read Matroska elements: {}", e);
+            Vec::new()
+        });
+
+        let cover = attachments
+            .iter()
+            .filter_map(|a| cover_rank(a).map(|rank| (rank, a)))
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, a)| a)
+            .ok_or(ThumbnailError::NoEmbeddedArt)?;
+        debug!("Using Matroska attachment {:?} ({})", cover.name, cover.mime_type);
+
+        let data = read_data(&mut reader, &cover.data, MAX_COVER_BYTES)?;
+        decode_embedded_image(data, ctx.limits())
+    }
+}
+
+/// Ranks an attachment as a cover, lower being better. Attachments that are
+/// not images are not ranked.
+fn cover_rank(attachment: &Attachment) -> Option<usize> {
+    let path = Path::new(&attachment.name);
+    let stem = path.file_stem()?.to_str()?.to_ascii_lowercase();
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let is_image = attachment.mime_type.starts_with("image/") || IMAGE_EXTENSIONS.contains(&extension.as_str());
+    if !is_image {
+        return None;
+    }
+    Some(COVER_NAMES.iter().position(|n| *n == stem).unwrap_or(COVER_NAMES.len()))
+}
+
+/// Locates the `Attachments` element of the first segment and lists the
+/// files it holds.
+fn find_attachments<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Attachment>> {
+    let len = stream_len(reader)?;
+    match read_element_header(reader, 0, len)? {
+        Some(header) if header.id == ids::EBML => {}
+        _ => return Ok(Vec::new()),
+    }
+    let Some(segment) = find_element(reader, 0, len, ids::SEGMENT)? else {
+        return Ok(Vec::new());
+    };
+    let Some(attachments) = find_attachments_element(reader, &segment)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut files = Vec::new();
+    for file in children(reader, attachments.data_start, attachments.end)? {
+        if file.id != ids::ATTACHED_FILE {
+            continue;
+        }
+        let mut name = String::new();
+        let mut mime_type = String::new();
+        let mut data = None;
+        for field in children(reader, file.data_start, file.end)? {
+            match field.id {
+                ids::FILE_NAME => name = read_string(reader, &field)?,
+                ids::FILE_MIME_TYPE => mime_type = read_string(reader, &field)?,
+                ids::FILE_DATA => data = Some(field),
+                _ => {}
+            }
+        }
+        if let Some(data) = data {
+            files.push(Attachment { name, mime_type, data });
+        }
+    }
+    Ok(files)
+}
+
+/// Finds the `Attachments` element through the segment's seek head, which
+/// usually points past the clusters, or by scanning the segment's children.
+fn find_attachments_element<R: Read + Seek>(reader: &mut R, segment: &Element) -> io::Result<Option<Element>> {
+    let mut pos = segment.data_start;
+    while let Some(element) = read_element_header(reader, pos, segment.end)? {
+        match element.id {
+            ids::ATTACHMENTS => return Ok(Some(element)),
+            ids::SEEK_HEAD => {
+                if let Some(offset) = seek_position(reader, &element, ids::ATTACHMENTS)? {
+                    let target = segment.data_start.saturating_add(offset);
+                    let found = read_element_header(reader, target, segment.end)?;
+                    if found.is_some_and(|found| found.id == ids::ATTACHMENTS) {
+                        return Ok(found);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if element.unknown_size {
+            break;
+        }
+        pos = element.end;
+    }
+    Ok(None)
+}
+
+/// Returns the segment-relative position the seek head gives for `id`.
+fn seek_position<R: Read + Seek>(reader: &mut R, seek_head: &Element, id: u32) -> io::Result<Option<u64>> {
+    for seek in children(reader, seek_head.data_start, seek_head.end)? {
+        if seek.id != ids::SEEK {
+            continue;
+        }
+        let mut seek_id = None;
+        let mut position = None;
+        for field in children(reader, seek.data_start, seek.end)? {
+            match field.id {
+                ids::SEEK_ID => seek_id = read_uint(&read_data(reader, &field, 8)?),
+                ids::SEEK_POSITION => position = read_uint(&read_data(reader, &field, 8)?),
+                _ => {}
+            }
+        }
+        if seek_id == Some(id as u64) {
+            return Ok(position);
+        }
+    }
+    Ok(None)
+}
+
+fn read_string<R: Read + Seek>(reader: &mut R, element: &Element) -> io::Result<String> {
+    let data = read_data(reader, element, 4096)?;
+    Ok(String::from_utf8_lossy(&data).trim_end_matches('\0').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MatroskaCoverGenerator;
+    use crate::error::ThumbnailError;
+    use crate::generators::test_util::{self, png};
+
+    fn generate(data: Vec<u8>) -> Result<(u32, u32), ThumbnailError> {
+        test_util::generate(&MatroskaCoverGenerator, data, "video/x-matroska")
+    }
+
+    /// Encodes an element with an eight byte size.
+    fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let skip = id_bytes.iter().position(|b| *b != 0).unwrap();
+        let mut out = id_bytes[skip..].to_vec();
+        out.push(0x01);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn attachment(name: &str, mime_type: &str, data: &[u8]) -> Vec<u8> {
+        let mut fields = element(0x466E, name.as_bytes());
+        fields.extend(element(0x4660, mime_type.as_bytes()));
+        fields.extend(element(0x465C, data));
+        element(0x61A7, &fields)
+    }
+
+    #[test]
+    fn test_attachment_cover() {
+        let mut attachments = attachment("font.ttf", "font/ttf", b"not an image");
+        attachments.extend(attachment("cover_land.png", "image/png", &png(30, 20)));
+        attachments.extend(attachment("cover.png", "image/png", &png(20, 30)));
+        let attachments = element(0x1941A469, &attachments);
+        let cluster = element(0x1F43B675, &[0; 64]);
+
+        // The seek head holds a 42 byte Seek with a 14 byte SeekID and an 18
+        // byte SeekPosition; the attachments follow the cluster.
+        let seek_head_len = 12 + 42;
+        let position = (seek_head_len + cluster.len()) as u64;
+        let mut seek = element(0x53AB, &[0x19, 0x41, 0xA4, 0x69]);
+        seek.extend(element(0x53AC, &position.to_be_bytes()));
+        let seek_head = element(0x114D9B74, &element(0x4DBB, &seek));
+        assert_eq!(seek_head.len(), seek_head_len);
+
+        let mut segment = seek_head;
+        segment.extend(cluster);
+        segment.extend(attachments);
+        let mut file = element(0x1A45DFA3, &element(0x4282, b"matroska"));
+        file.extend(element(0x18538067, &segment));
+        assert_eq!(generate(file).unwrap(), (20, 30));
+
+        let mut bare = element(0x1A45DFA3, &element(0x4282, b"webm"));
+        bare.extend(element(0x18538067, &element(0x1F43B675, &[0; 16])));
+        assert!(matches!(generate(bare), Err(ThumbnailError::NoEmbeddedArt)));
+    }
+}
@@ -0,0 +1,347 @@
+//! In-process thumbnail generators that run without spawning an external
+//! thumbnailer.
+//!
+//! Generators implement [`ThumbnailGenerator`] and are registered per MIME type
+//! in a [`GeneratorRegistry`]. `generate_thumbnail_with_options` tries every
+//! generator registered above [`EXTERNAL_PRIORITY`] first, then the external
+//! `.thumbnailer` (if one matches), then the remaining generators.
+
+pub mod exif;
+pub mod image;
+pub mod raw;
+pub mod audio;
+pub mod mp4;
+pub mod mkv;
+pub mod document;
+pub mod layered;
+pub mod blend;
+pub mod text;
+pub mod font;
+pub mod icon;
+pub mod mesh;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "svg")]
+pub mod svg;
+
+use ::image::{metadata::Orientation, DynamicImage};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{animation::FramePolicy, error::ThumbnailError, limits::DecodeLimits, sizes::ThumbnailSize};
+
+/// The priority at which external `.thumbnailer` programs are tried.
+///
+/// Generators registered with a higher priority run before them, generators
+/// with an equal or lower priority only run as fallbacks.
+pub const EXTERNAL_PRIORITY: i32 = 0;
+
+/// The priority used for the crate's built-in fallback generators.
+pub const FALLBACK_PRIORITY: i32 = -10;
+
+/// Anything that can be read and seeked, used for reader-based sources.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The input handed to a [`ThumbnailGenerator`].
+pub enum Source<'a> {
+    Path(&'a Path),
+    Reader(&'a mut dyn ReadSeek),
+}
+
+impl Source<'_> {
+    /// Returns the path of the source, if it is backed by a file.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Source::Path(path) => Some(path),
+            Source::Reader(_) => None,
+        }
+    }
+
+    /// Returns a reader positioned at the start of the source.
+    pub fn reader(&mut self) -> io::Result<Box<dyn ReadSeek + '_>> {
+        match self {
+            Source::Path(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+            Source::Reader(reader) => {
+                reader.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(&mut **reader))
+            }
+        }
+    }
+}
+
+/// Per-request information passed to a generator, which the generator can also
+/// use to attach extra PNG text chunks to the thumbnail.
+#[derive(Debug, Clone)]
+pub struct GenerateContext {
+    size: ThumbnailSize,
+    mime_type: String,
+    text: Vec<(String, String)>,
+    oriented: bool,
+    orientation: Option<Orientation>,
+    limits: DecodeLimits,
+    icc_profile: Option<Vec<u8>>,
+    frame_policy: FramePolicy,
+}
+
+impl GenerateContext {
+    pub fn new(size: ThumbnailSize, mime_type: &str) -> Self {
+        GenerateContext {
+            size,
+            mime_type: mime_type.to_string(),
+            text: Vec::new(),
+            oriented: false,
+            orientation: None,
+            limits: DecodeLimits::default(),
+            icc_profile: None,
+            frame_policy: FramePolicy::default(),
+        }
+    }
+
+    /// Replaces the decode limits generators must honour.
+    pub fn with_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Replaces the policy for picking the frame of animated images.
+    pub fn with_frame_policy(mut self, policy: FramePolicy) -> Self {
+        self.frame_policy = policy;
+        self
+    }
+
+    /// The requested thumbnail size.
+    pub fn size(&self) -> ThumbnailSize {
+        self.size
+    }
+
+    /// Which frame of an animated image to thumbnail.
+    pub fn frame_policy(&self) -> FramePolicy {
+        self.frame_policy
+    }
+
+    /// The limits to apply to every image the generator decodes.
+    pub fn limits(&self) -> &DecodeLimits {
+        &self.limits
+    }
+
+    /// The detected MIME type of the source.
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    /// Adds a text chunk (e.g. `Thumb::Image::Width`) to the written thumbnail.
+    pub fn add_text(&mut self, keyword: impl Into<String>, text: impl Into<String>) {
+        self.text.push((keyword.into(), text.into()));
+    }
+
+    /// The text chunks added so far.
+    pub fn text(&self) -> &[(String, String)] {
+        &self.text
+    }
+
+    /// Records that the returned image is already in display orientation, so
+    /// the pipeline must not apply the source's EXIF orientation again.
+    pub fn mark_oriented(&mut self) {
+        self.oriented = true;
+    }
+
+    /// Whether the generator applied the orientation itself.
+    pub fn is_oriented(&self) -> bool {
+        self.oriented
+    }
+
+    /// Records the orientation of the source when the generator found it in a
+    /// place the pipeline does not look, such as a RAW container. The pipeline
+    /// applies it instead of reading the source's EXIF data.
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = Some(orientation);
+    }
+
+    /// The orientation recorded by the generator, if any.
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.orientation
+    }
+
+    /// Records the ICC profile describing the returned pixels, so the pipeline
+    /// can convert them to sRGB.
+    pub fn set_icc_profile(&mut self, profile: Vec<u8>) {
+        self.icc_profile = Some(profile);
+    }
+
+    /// The ICC profile recorded by the generator, if any.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_deref()
+    }
+}
+
+/// Decodes a picture embedded in another file, such as cover art, guessing its
+/// format from its contents.
+pub(crate) fn decode_embedded_image(data: Vec<u8>, limits: &DecodeLimits) -> Result<DynamicImage, ThumbnailError> {
+    let mut reader = ::image::ImageReader::new(io::Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits.to_image_limits());
+    Ok(reader.decode()?)
+}
+
+/// A producer of thumbnail images that runs inside the calling process.
+pub trait ThumbnailGenerator: Send + Sync {
+    /// A short name used in logs.
+    fn name(&self) -> &str;
+
+    /// Produces the thumbnail image for `source`.
+    ///
+    /// The image may be larger than `ctx.size()`; it is scaled down to fit
+    /// before being written. Return `ThumbnailError::Unsupported` to let the
+    /// next candidate handle a file without recording a failure.
+    fn generate(&self, source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError>;
+
+    /// Whether the EXIF orientation of the source file applies to the
+    /// returned image, so the pipeline should read it when the generator did
+    /// not call `GenerateContext::set_orientation`. Only generators returning
+    /// the source's own pixels or an embedded preview of them should say so;
+    /// the default is no.
+    fn uses_source_orientation(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone)]
+struct Registration {
+    mime_type: String,
+    priority: i32,
+    generator: Arc<dyn ThumbnailGenerator>,
+}
+
+/// The set of in-process generators, keyed by MIME type.
+///
+/// A MIME type may be registered as `type/*` to match every subtype.
+/// `GeneratorRegistry::default()` contains the crate's built-in generators,
+/// `GeneratorRegistry::new()` is empty.
+#[derive(Clone)]
+pub struct GeneratorRegistry {
+    entries: Vec<Registration>,
+}
+
+impl GeneratorRegistry {
+    /// Creates a registry with no generators.
+    pub fn new() -> Self {
+        GeneratorRegistry { entries: Vec::new() }
+    }
+
+    /// Registers `generator` for `mime_type` at the given priority.
+    pub fn register(&mut self, mime_type: impl Into<String>, priority: i32, generator: Arc<dyn ThumbnailGenerator>) {
+        self.entries.push(Registration {
+            mime_type: mime_type.into(),
+            priority,
+            generator,
+        });
+    }
+
+    /// Registers `generator` for each of `mime_types` at the given priority.
+    pub fn register_all(&mut self, mime_types: &[&str], priority: i32, generator: Arc<dyn ThumbnailGenerator>) {
+        for mime_type in mime_types {
+            self.register(*mime_type, priority, generator.clone());
+        }
+    }
+
+    /// Registers the EXIF thumbnail fast path for JPEG and TIFF files.
+    pub fn enable_exif_thumbnails(&mut self) {
+        self.register_all(exif::MIME_TYPES, exif::PRIORITY, Arc::new(exif::ExifThumbnailGenerator));
+    }
+
+    /// Returns the generators registered for `mime_type`, highest priority first.
+    /// Generators with equal priority keep their registration order.
+    pub fn lookup(&self, mime_type: &str) -> Vec<(i32, Arc<dyn ThumbnailGenerator>)> {
+        let mut matches: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| mime_matches(&entry.mime_type, mime_type))
+            .map(|entry| (entry.priority, entry.generator.clone()))
+            .collect();
+        matches.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+        matches
+    }
+}
+
+impl Default for GeneratorRegistry {
+    fn default() -> Self {
+        let mut registry = GeneratorRegistry::new();
+        registry.register_all(image::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(image::ImageGenerator));
+        registry.register_all(raw::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(raw::RawPreviewGenerator));
+        registry.register_all(audio::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(audio::CoverArtGenerator));
+        registry.register_all(mp4::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(mp4::Mp4CoverGenerator));
+        registry.register_all(mkv::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(mkv::MatroskaCoverGenerator));
+        registry.register_all(document::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(document::ZipDocumentGenerator));
+        registry.register_all(layered::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(layered::LayeredImageGenerator));
+        registry.register_all(blend::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(blend::BlendPreviewGenerator));
+        registry.register_all(text::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(text::TextPreviewGenerator::default()));
+        registry.register_all(font::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(font::FontSpecimenGenerator));
+        registry.register_all(icon::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(icon::IconGenerator));
+        registry.register_all(mesh::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(mesh::MeshPreviewGenerator::default()));
+        #[cfg(feature = "svg")]
+        registry.register_all(svg::MIME_TYPES, FALLBACK_PRIORITY, Arc::new(svg::SvgGenerator::default()));
+        registry
+    }
+}
+
+impl fmt::Debug for GeneratorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| {
+                (&entry.mime_type, entry.priority, entry.generator.name())
+            }))
+            .finish()
+    }
+}
+
+fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime_type
+            .split_once('/')
+            .is_some_and(|(top, _)| top == prefix),
+        None => pattern == mime_type,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+    use std::sync::Arc;
+
+    use super::{GenerateContext, GeneratorRegistry, Source, ThumbnailGenerator};
+    use crate::error::ThumbnailError;
+
+    struct Named(&'static str);
+
+    impl ThumbnailGenerator for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn generate(&self, _: Source<'_>, _: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+            Err(ThumbnailError::Unsupported(self.0.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_lookup_orders_by_priority() {
+        let mut registry = GeneratorRegistry::new();
+        registry.register("image/png", -5, Arc::new(Named("low")));
+        registry.register("image/*", 10, Arc::new(Named("wildcard")));
+        registry.register("image/png", 10, Arc::new(Named("exact")));
+        registry.register("text/plain", 100, Arc::new(Named("other")));
+
+        let names: Vec<_> = registry
+            .lookup("image/png")
+            .iter()
+            .map(|(_, g)| g.name().to_string())
+            .collect();
+        assert_eq!(names, ["wildcard", "exact", "low"]);
+    }
+}
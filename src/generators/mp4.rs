@@ -0,0 +1,120 @@
+use image::DynamicImage;
+use log::debug;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{decode_embedded_image, GenerateContext, Source, ThumbnailGenerator},
+    isobmff::{find_box, read_data, stream_len, BoxHeader},
+};
+
+/// MP4 MIME types that can carry iTunes-style cover art.
+pub const MIME_TYPES: &[&str] = &["audio/mp4", "audio/x-m4a", "audio/x-m4b", "video/mp4"];
+
+/// Cover images larger than this are not read.
+const MAX_COVER_BYTES: u64 = 32 * 1024 * 1024;
+
+/// The path from the top level to the iTunes metadata item list.
+const ILST_PATH: [&[u8; 4]; 4] = [b"moov", b"udta", b"meta", b"ilst"];
+
+/// Extracts the cover art stored in the `moov/udta/meta/ilst/covr` atom of
+/// MP4 files, as written by iTunes and most podcast and audiobook tools.
+///
+/// Files without a `covr` atom fail with `ThumbnailError::NoEmbeddedArt`.
+#[derive(Debug, Default)]
+pub struct Mp4CoverGenerator;
+
+impl ThumbnailGenerator for Mp4CoverGenerator {
+    fn name(&self) -> &str {
+        "mp4-cover"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut reader = BufReader::new(source.reader()?);
+        let cover = find_cover(&mut reader)
+            .unwrap_or_else(|e| {
+                debug!("Could not read MP4 boxes: {}", e);
+                None
+            })
+            .ok_or(ThumbnailError::NoEmbeddedArt)?;
+
+        debug!("Using {} byte MP4 cover", cover.len());
+        decode_embedded_image(cover, ctx.limits())
+    }
+}
+
+/// Walks down to `covr` and returns the image in its first `data` box.
+fn find_cover<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut parent = BoxHeader {
+        kind: *b"root",
+        uuid: None,
+        start: 0,
+        data_start: 0,
+        end: stream_len(reader)?,
+    };
+
+    for kind in ILST_PATH {
+        let mut start = parent.data_start;
+        if &parent.kind == b"meta" && is_full_box(reader, &parent)? {
+            start += 4;
+        }
+        match find_box(reader, start, parent.end, kind)? {
+            Some(header) => parent = header,
+            None => return Ok(None),
+        }
+    }
+
+    let Some(covr) = find_box(reader, parent.data_start, parent.end, b"covr")? else {
+        return Ok(None);
+    };
+    let Some(data) = find_box(reader, covr.data_start, covr.end, b"data")? else {
+        return Ok(None);
+    };
+    // Four bytes of type indicator (13 = JPEG, 14 = PNG, 27 = BMP) and four
+    // of locale precede the image; its format is detected from the bytes.
+    let payload = read_data(reader, &data, MAX_COVER_BYTES)?;
+    Ok(payload.get(8..).filter(|image| !image.is_empty()).map(<[u8]>::to_vec))
+}
+
+/// ISO files give `meta` a version and flags field before its children;
+/// QuickTime files do not. The field is all zeros where present.
+fn is_full_box<R: Read + Seek>(reader: &mut R, meta: &BoxHeader) -> io::Result<bool> {
+    if meta.data_len() < 4 {
+        return Ok(false);
+    }
+    let mut version = [0u8; 4];
+    reader.seek(SeekFrom::Start(meta.data_start))?;
+    reader.read_exact(&mut version)?;
+    Ok(version == [0; 4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mp4CoverGenerator;
+    use crate::error::ThumbnailError;
+    use crate::generators::test_util::{self, make_box, png};
+
+    fn generate(data: Vec<u8>) -> Result<(u32, u32), ThumbnailError> {
+        test_util::generate(&Mp4CoverGenerator, data, "audio/x-m4b")
+    }
+
+    #[test]
+    fn test_covr_atom() {
+        let mut data = vec![0, 0, 0, 14, 0, 0, 0, 0];
+        data.extend(png(20, 10));
+        let ilst = make_box(b"ilst", &make_box(b"covr", &make_box(b"data", &data)));
+        let mut meta = vec![0u8; 4];
+        meta.extend(make_box(b"hdlr", &[0; 25]));
+        meta.extend(ilst);
+        let moov = make_box(b"moov", &make_box(b"udta", &make_box(b"meta", &meta)));
+
+        let mut file = make_box(b"ftyp", b"M4B \0\0\0\0");
+        file.extend(&moov);
+        file.extend(make_box(b"mdat", &[0; 32]));
+        assert_eq!(generate(file).unwrap(), (20, 10));
+
+        let mut bare = make_box(b"ftyp", b"M4A \0\0\0\0");
+        bare.extend(make_box(b"moov", &[]));
+        assert!(matches!(generate(bare), Err(ThumbnailError::NoEmbeddedArt)));
+    }
+}
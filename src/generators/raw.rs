@@ -0,0 +1,476 @@
+use image::{metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use log::debug;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+    isobmff::{children, find_box, find_uuid_box, stream_len, BoxHeader},
+    limits::DecodeLimits,
+    tiff::{tags, Entry, Ifd, TiffReader},
+};
+
+/// Camera RAW MIME types with embedded JPEG previews.
+pub const MIME_TYPES: &[&str] = &[
+    "image/x-canon-cr2",
+    "image/x-canon-cr3",
+    "image/x-nikon-nef",
+    "image/x-sony-arw",
+    "image/x-olympus-orf",
+    "image/x-fuji-raf",
+    "image/x-adobe-dng",
+];
+
+/// Embedded previews larger than this are ignored.
+const MAX_PREVIEW_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Bytes read from the start of each preview to find its dimensions.
+const PROBE_BYTES: u64 = 128 * 1024;
+
+/// Upper bound on the SubIFDs followed per IFD.
+const MAX_SUB_IFDS: usize = 8;
+
+/// The Canon metadata box inside `moov` of CR3 files, holding `CMT1` (TIFF
+/// IFD0) and `THMB` (a 160x120 JPEG).
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xC0, 0xB6, 0x87, 0x82, 0x0F, 0x11, 0xE0, 0x81, 0x11, 0xF4, 0xCE, 0x46, 0x2B, 0x6A, 0x48,
+];
+
+/// The top-level CR3 box holding the `PRVW` preview JPEG.
+const CANON_PREVIEW_UUID: [u8; 16] = [
+    0xEA, 0xF4, 0x2B, 0x5E, 0x1C, 0x98, 0x4B, 0x88, 0xB9, 0xFB, 0xB7, 0xDC, 0x40, 0x6E, 0x4D, 0x16,
+];
+
+/// Olympus maker note tags.
+const OLYMPUS_CAMERA_SETTINGS: u16 = 0x2020;
+const OLYMPUS_PREVIEW_START: u16 = 0x0101;
+const OLYMPUS_PREVIEW_LENGTH: u16 = 0x0102;
+
+/// Uses the JPEG previews that cameras embed in their RAW files.
+///
+/// TIFF-based formats (CR2, NEF, ARW, DNG, ORF) are searched through their IFD
+/// chain, SubIFDs and, for ORF, the Olympus maker note; CR3 through its
+/// ISOBMFF boxes; RAF through its fixed header. Of all previews found, the
+/// smallest that is at least as large as the requested size is decoded, or
+/// the largest one if none is. The RAW's orientation is passed on to the
+/// pipeline.
+#[derive(Debug, Default)]
+pub struct RawPreviewGenerator;
+
+/// A JPEG embedded at `offset` in the RAW file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Preview {
+    offset: u64,
+    length: u64,
+    width: u32,
+    height: u32,
+}
+
+/// Everything found in the RAW container.
+#[derive(Debug, Default)]
+struct Found {
+    /// `(offset, length)` of each candidate JPEG.
+    candidates: Vec<(u64, u64)>,
+    orientation: Option<Orientation>,
+}
+
+impl ThumbnailGenerator for RawPreviewGenerator {
+    fn name(&self) -> &str {
+        "raw"
+    }
+
+    fn uses_source_orientation(&self) -> bool {
+        true
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut reader = BufReader::new(source.reader()?);
+        // A RAW container we cannot parse is left to other thumbnailers.
+        let found = find_previews(&mut reader).unwrap_or_else(|e| {
+            debug!("Could not parse RAW container: {}", e);
+            Found::default()
+        });
+
+        let mut previews: Vec<Preview> = found
+            .candidates
+            .iter()
+            .filter_map(|&(offset, length)| probe(&mut reader, offset, length).ok().flatten())
+            .collect();
+        order_previews(&mut previews, ctx.size().to_dimension());
+
+        for preview in previews {
+            match decode_preview(&mut reader, &preview, ctx.limits()) {
+                Ok((img, embedded_orientation)) => {
+                    debug!("Using {}x{} RAW preview at offset {}", preview.width, preview.height, preview.offset);
+                    if let Some(orientation) = found.orientation.or(embedded_orientation) {
+                        ctx.set_orientation(orientation);
+                    }
+                    return Ok(img);
+                }
+                Err(e) => debug!("Skipping unreadable RAW preview at offset {}: {}", preview.offset, e),
+            }
+        }
+
+        Err(ThumbnailError::Unsupported("No usable embedded RAW preview".to_string()))
+    }
+}
+
+/// Sorts previews by preference: the smallest one covering `dimension` first,
+/// then the remaining ones from largest to smallest.
+fn order_previews(previews: &mut Vec<Preview>, dimension: u32) {
+    previews.sort_by_key(|p| p.offset);
+    previews.dedup_by_key(|p| p.offset);
+    previews.sort_by_key(|p| {
+        let area = p.width as u64 * p.height as u64;
+        let covers = p.width.max(p.height) >= dimension;
+        (!covers, if covers { area } else { u64::MAX - area })
+    });
+}
+
+/// Identifies the container by its magic bytes and collects its previews.
+fn find_previews<R: Read + Seek>(reader: &mut R) -> io::Result<Found> {
+    let mut magic = [0u8; 16];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+
+    if magic.starts_with(b"FUJIFILMCCD-RAW") {
+        raf_previews(reader)
+    } else if &magic[4..8] == b"ftyp" {
+        cr3_previews(reader)
+    } else if magic.starts_with(b"II") || magic.starts_with(b"MM") {
+        tiff_previews(reader)
+    } else {
+        Ok(Found::default())
+    }
+}
+
+/// RAF files start with a fixed header giving the location of the preview JPEG.
+fn raf_previews<R: Read + Seek>(reader: &mut R) -> io::Result<Found> {
+    let mut pointer = [0u8; 8];
+    reader.seek(SeekFrom::Start(84))?;
+    reader.read_exact(&mut pointer)?;
+    let offset = u32::from_be_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+    let length = u32::from_be_bytes([pointer[4], pointer[5], pointer[6], pointer[7]]);
+    Ok(Found {
+        candidates: vec![(offset as u64, length as u64)],
+        orientation: None,
+    })
+}
+
+fn tiff_previews<R: Read + Seek>(reader: &mut R) -> io::Result<Found> {
+    let (mut tiff, first_ifd) = TiffReader::new(reader, 0)?;
+    let mut ifds = tiff.read_ifd_chain(first_ifd)?;
+    let orientation = ifds.first().and_then(|ifd| ifd_orientation(&tiff, ifd));
+
+    let mut sub_ifds = Vec::new();
+    for ifd in &ifds {
+        let Some(entry) = ifd.get(tags::SUB_IFDS) else {
+            continue;
+        };
+        for offset in tiff.values_u32(entry)?.into_iter().take(MAX_SUB_IFDS) {
+            if let Ok(sub_ifd) = tiff.read_ifd(offset) {
+                sub_ifds.push(sub_ifd);
+            }
+        }
+    }
+    ifds.extend(sub_ifds);
+
+    let mut candidates: Vec<(u64, u64)> = ifds.iter().filter_map(|ifd| ifd_preview(&tiff, ifd)).collect();
+
+    let maker_note = ifds
+        .first()
+        .and_then(|ifd| ifd.get(tags::EXIF_IFD))
+        .and_then(|e| tiff.value_u32(e))
+        .and_then(|offset| tiff.read_ifd(offset).ok())
+        .and_then(|exif| exif.get(tags::MAKER_NOTE).cloned());
+    if let Some(entry) = maker_note {
+        if let Some(candidate) = olympus_preview(tiff, &entry).ok().flatten() {
+            candidates.push(candidate);
+        }
+    }
+
+    Ok(Found {
+        candidates,
+        orientation,
+    })
+}
+
+/// Returns the JPEG stored in an IFD, either through the JPEGInterchangeFormat
+/// tags or as a single JPEG-compressed strip.
+fn ifd_preview<R: Read + Seek>(tiff: &TiffReader<R>, ifd: &Ifd) -> Option<(u64, u64)> {
+    let pair = |offset_tag, length_tag| {
+        let offset = tiff.value_u32(ifd.get(offset_tag)?)?;
+        let length = tiff.value_u32(ifd.get(length_tag)?)?;
+        Some((offset as u64, length as u64))
+    };
+
+    if let Some(preview) = pair(tags::JPEG_INTERCHANGE_FORMAT, tags::JPEG_INTERCHANGE_FORMAT_LENGTH) {
+        return Some(preview);
+    }
+
+    let compression = tiff.value_u32(ifd.get(tags::COMPRESSION)?)?;
+    let single_strip = ifd.get(tags::STRIP_OFFSETS)?.count == 1;
+    if matches!(compression, 6 | 7) && single_strip {
+        pair(tags::STRIP_OFFSETS, tags::STRIP_BYTE_COUNTS)
+    } else {
+        None
+    }
+}
+
+/// Reads the preview location from an Olympus maker note, whose offsets are
+/// relative to the start of the note.
+fn olympus_preview<R: Read + Seek>(mut tiff: TiffReader<R>, maker_note: &Entry) -> io::Result<Option<(u64, u64)>> {
+    let note_offset = tiff.value_offset(maker_note) as u64;
+    if tiff.read_at(note_offset, 8)? != b"OLYMPUS\0" {
+        return Ok(None);
+    }
+
+    let note_start = tiff.base() + note_offset;
+    let mut note = tiff.rebase(note_start);
+    let ifd = note.read_ifd(12)?;
+    let Some(settings) = ifd.get(OLYMPUS_CAMERA_SETTINGS).and_then(|e| note.value_u32(e)) else {
+        return Ok(None);
+    };
+    let settings = note.read_ifd(settings)?;
+
+    let start = settings.get(OLYMPUS_PREVIEW_START).and_then(|e| note.value_u32(e));
+    let length = settings.get(OLYMPUS_PREVIEW_LENGTH).and_then(|e| note.value_u32(e));
+    Ok(match (start, length) {
+        (Some(start), Some(length)) if length > 0 => Some((note_start + start as u64, length as u64)),
+        _ => None,
+    })
+}
+
+fn ifd_orientation<R: Read + Seek>(tiff: &TiffReader<R>, ifd: &Ifd) -> Option<Orientation> {
+    let value = tiff.value_u32(ifd.get(tags::ORIENTATION)?)?;
+    Orientation::from_exif(u8::try_from(value).ok()?)
+}
+
+/// CR3 files keep a small `THMB` JPEG and the TIFF-structured `CMT1` metadata
+/// in a Canon box inside `moov`, and a larger `PRVW` JPEG in a top-level box.
+fn cr3_previews<R: Read + Seek>(reader: &mut R) -> io::Result<Found> {
+    let len = stream_len(reader)?;
+    let top = children(reader, 0, len)?;
+    let mut found = Found::default();
+
+    if let Some(moov) = top.iter().find(|b| &b.kind == b"moov") {
+        if let Some(canon) = find_uuid_box(reader, moov.data_start, moov.end, &CANON_UUID)? {
+            for child in children(reader, canon.data_start, canon.end)? {
+                match &child.kind {
+                    b"THMB" => found.candidates.extend(find_jpeg_in_box(reader, &child)?),
+                    b"CMT1" => {
+                        let (mut tiff, first_ifd) = TiffReader::new(&mut *reader, child.data_start)?;
+                        let ifd0 = tiff.read_ifd(first_ifd)?;
+                        found.orientation = ifd_orientation(&tiff, &ifd0);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if let Some(preview) = top.iter().find(|b| b.uuid == Some(CANON_PREVIEW_UUID)) {
+        // Eight bytes of unknown purpose precede the PRVW box.
+        if let Some(prvw) = find_box(reader, preview.data_start + 8, preview.end, b"PRVW")? {
+            found.candidates.extend(find_jpeg_in_box(reader, &prvw)?);
+        }
+    }
+    Ok(found)
+}
+
+/// Locates a JPEG following a short header at the start of a box payload.
+fn find_jpeg_in_box<R: Read + Seek>(reader: &mut R, header: &BoxHeader) -> io::Result<Option<(u64, u64)>> {
+    let mut head = Vec::new();
+    reader.seek(SeekFrom::Start(header.data_start))?;
+    reader.take(header.data_len().min(32)).read_to_end(&mut head)?;
+    Ok(head.windows(3).position(|w| w == [0xFF, 0xD8, 0xFF]).map(|pos| {
+        let offset = header.data_start + pos as u64;
+        (offset, header.end - offset)
+    }))
+}
+
+/// Reads the header of the JPEG at `offset` and returns it as a preview if it
+/// is a JPEG the decoder can handle.
+fn probe<R: Read + Seek>(reader: &mut R, offset: u64, length: u64) -> io::Result<Option<Preview>> {
+    if length == 0 || length > MAX_PREVIEW_BYTES {
+        return Ok(None);
+    }
+    let mut head = Vec::new();
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.take(length.min(PROBE_BYTES)).read_to_end(&mut head)?;
+
+    Ok(jpeg_dimensions(&head).map(|(width, height)| Preview {
+        offset,
+        length,
+        width,
+        height,
+    }))
+}
+
+/// Returns the dimensions from the start-of-frame segment of a baseline or
+/// progressive JPEG. Lossless JPEGs, which hold the raw sensor data in CR2 and
+/// DNG files, and other variants yield `None`.
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        match marker {
+            // Fill byte before a marker.
+            0xFF => i += 1,
+            // Markers without a length.
+            0x01 | 0xD0..=0xD8 => i += 2,
+            0xC0..=0xC2 => {
+                let sof = data.get(i + 5..i + 9)?;
+                let height = u16::from_be_bytes([sof[0], sof[1]]) as u32;
+                let width = u16::from_be_bytes([sof[2], sof[3]]) as u32;
+                return (width > 0 && height > 0).then_some((width, height));
+            }
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xD9 | 0xDA => return None,
+            _ => i += 2 + u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize,
+        }
+    }
+    None
+}
+
+/// Decodes a preview, returning it with the orientation from its own EXIF data.
+fn decode_preview<R: Read + Seek>(
+    reader: &mut R,
+    preview: &Preview,
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Option<Orientation>), ThumbnailError> {
+    limits.check(preview.width, preview.height, 3)?;
+    let mut data = Vec::new();
+    reader.seek(SeekFrom::Start(preview.offset))?;
+    reader.take(preview.length).read_to_end(&mut data)?;
+
+    let mut jpeg_reader = ImageReader::with_format(Cursor::new(data), ImageFormat::Jpeg);
+    jpeg_reader.limits(limits.to_image_limits());
+    let mut decoder = jpeg_reader.into_decoder()?;
+    let orientation = decoder.orientation().ok().filter(|o| *o != Orientation::NoTransforms);
+    Ok((DynamicImage::from_decoder(decoder)?, orientation))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::metadata::Orientation;
+    use std::io::Cursor;
+
+    use super::{jpeg_dimensions, RawPreviewGenerator, CANON_PREVIEW_UUID, CANON_UUID};
+    use crate::generators::{
+        test_util::{jpeg, make_box},
+        GenerateContext, Source, ThumbnailGenerator,
+    };
+    use crate::ThumbnailSize;
+
+    fn generate(data: Vec<u8>, mime_type: &str, size: ThumbnailSize) -> (u32, u32, Option<Orientation>) {
+        let mut cursor = Cursor::new(data);
+        let mut ctx = GenerateContext::new(size, mime_type);
+        let img = RawPreviewGenerator
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .expect("RAW preview should be found");
+        (img.width(), img.height(), ctx.orientation())
+    }
+
+    /// Builds a NEF-like TIFF: IFD0 with an orientation and two SubIFDs, each
+    /// pointing at one JPEG preview.
+    fn tiff_with_previews(previews: &[Vec<u8>]) -> Vec<u8> {
+        let entry = |tag: u16, field_type: u16, count: u32, value: u32| {
+            let mut e = tag.to_le_bytes().to_vec();
+            e.extend_from_slice(&field_type.to_le_bytes());
+            e.extend_from_slice(&count.to_le_bytes());
+            e.extend_from_slice(&value.to_le_bytes());
+            e
+        };
+
+        // Header, IFD0 (2 entries) and the SubIFD offset array.
+        let ifd0_len = 2 + 2 * 12 + 4;
+        let sub_array = 8 + ifd0_len;
+        let first_sub = sub_array + 4 * previews.len();
+        let sub_len = 2 + 2 * 12 + 4;
+        let mut data_offset = first_sub + sub_len * previews.len();
+
+        let mut out = b"II*\0".to_vec();
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend(entry(0x0112, 3, 1, 6));
+        out.extend(entry(0x014A, 4, previews.len() as u32, sub_array as u32));
+        out.extend_from_slice(&0u32.to_le_bytes());
+        for i in 0..previews.len() {
+            out.extend_from_slice(&((first_sub + i * sub_len) as u32).to_le_bytes());
+        }
+        for preview in previews {
+            out.extend_from_slice(&2u16.to_le_bytes());
+            out.extend(entry(0x0201, 4, 1, data_offset as u32));
+            out.extend(entry(0x0202, 4, 1, preview.len() as u32));
+            out.extend_from_slice(&0u32.to_le_bytes());
+            data_offset += preview.len();
+        }
+        for preview in previews {
+            out.extend_from_slice(preview);
+        }
+        out
+    }
+
+    fn uuid_box(uuid: &[u8; 16], payload: &[u8]) -> Vec<u8> {
+        let mut inner = uuid.to_vec();
+        inner.extend_from_slice(payload);
+        make_box(b"uuid", &inner)
+    }
+
+    #[test]
+    fn test_tiff_based_raw() {
+        let data = tiff_with_previews(&[jpeg(640, 480), jpeg(160, 120)]);
+
+        let normal = generate(data.clone(), "image/x-nikon-nef", ThumbnailSize::Normal);
+        assert_eq!(normal, (160, 120, Some(Orientation::Rotate90)));
+
+        let large = generate(data, "image/x-nikon-nef", ThumbnailSize::Large);
+        assert_eq!((large.0, large.1), (640, 480));
+    }
+
+    #[test]
+    fn test_cr3() {
+        // CMT1 holds a big-endian TIFF IFD0 with Orientation = 8.
+        let mut cmt1 = b"MM\0*".to_vec();
+        cmt1.extend_from_slice(&8u32.to_be_bytes());
+        cmt1.extend_from_slice(&1u16.to_be_bytes());
+        cmt1.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0]);
+        cmt1.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut thmb = vec![0u8; 16];
+        thmb.extend(jpeg(160, 120));
+        let mut canon = make_box(b"CMT1", &cmt1);
+        canon.extend(make_box(b"THMB", &thmb));
+
+        let mut prvw = vec![0u8; 16];
+        prvw.extend(jpeg(400, 300));
+        let mut preview = vec![0u8; 8];
+        preview.extend(make_box(b"PRVW", &prvw));
+
+        let mut data = make_box(b"ftyp", b"crx \0\0\0\x01");
+        data.extend(make_box(b"moov", &uuid_box(&CANON_UUID, &canon)));
+        data.extend(uuid_box(&CANON_PREVIEW_UUID, &preview));
+
+        assert_eq!(
+            generate(data.clone(), "image/x-canon-cr3", ThumbnailSize::Large),
+            (400, 300, Some(Orientation::Rotate270))
+        );
+        let small = generate(data, "image/x-canon-cr3", ThumbnailSize::Small);
+        assert_eq!((small.0, small.1), (160, 120));
+    }
+
+    #[test]
+    fn test_jpeg_dimensions() {
+        assert_eq!(jpeg_dimensions(&jpeg(33, 17)), Some((33, 17)));
+        // A lossless (SOF3) header, as used for raw sensor data.
+        let lossless = [0xFF, 0xD8, 0xFF, 0xC3, 0, 11, 8, 0, 16, 0, 16, 1, 1, 0x11, 0];
+        assert_eq!(jpeg_dimensions(&lossless), None);
+    }
+}
@@ -0,0 +1,375 @@
+use flate2::read::GzDecoder;
+use image::{DynamicImage, RgbaImage};
+use log::debug;
+use resvg::{
+    tiny_skia::{NonZeroRect, Pixmap, Transform},
+    usvg::{fontdb, Group, ImageHrefResolver, Node, Options, Tree},
+};
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    error::ThumbnailError,
+    generators::{text::FONT, GenerateContext, Source, ThumbnailGenerator},
+    limits::DecodeLimits,
+};
+
+/// MIME types of SVG images, plain and gzip compressed.
+pub const MIME_TYPES: &[&str] = &["image/svg+xml", "image/svg+xml-compressed"];
+
+/// Documents larger than this, before or after decompression, are rejected.
+const MAX_SVG_BYTES: u64 = 32 * 1024 * 1024;
+
+/// The family name of the bundled font, used for every generic family.
+const FONT_FAMILY: &str = "Hack";
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+/// Documents with more elements than this, counting every use of a pattern,
+/// clip path or mask, are not rendered.
+const MAX_NODES: usize = 100_000;
+
+/// The total area, in output pixels, that filters may cover, counting a
+/// region once per filter applied to it.
+const MAX_FILTER_PIXELS: f64 = 16.0 * 1024.0 * 1024.0;
+
+/// The most render threads that may run at once.
+const MAX_RENDERS: usize = 4;
+
+/// Render threads still running, including the ones given up on.
+static RENDERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Render threads that outlived their timeout and are still running.
+static ABANDONED: AtomicUsize = AtomicUsize::new(0);
+
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+const GIVEN_UP: u8 = 2;
+
+/// Renders SVG images with resvg, scaled to fit the requested size.
+///
+/// Only the document itself is read: `<image>` elements may embed pictures
+/// as `data:` URLs but never reference other files, and text uses the bundled
+/// font rather than the system ones. Scripts and animations are not supported
+/// by resvg and are ignored. Documents with too many elements or too much
+/// filtered area fail with `ThumbnailError::LimitsExceeded` before rendering.
+///
+/// Parsing and rendering run on a separate thread; when they take longer than
+/// `timeout` the generator gives up with `ThumbnailError::ThumbnailerTimedOut`.
+/// resvg cannot be interrupted, so that thread cannot be cancelled and keeps
+/// running until the render finishes. At most four renders run at once, and
+/// while a timed-out one is still running new documents are declined with
+/// `ThumbnailError::Unsupported` rather than starting another thread.
+#[derive(Debug)]
+pub struct SvgGenerator {
+    pub timeout: Duration,
+}
+
+impl Default for SvgGenerator {
+    fn default() -> Self {
+        SvgGenerator {
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ThumbnailGenerator for SvgGenerator {
+    fn name(&self) -> &str {
+        "svg"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let data = read_document(source.reader()?)?;
+        let dimension = ctx.size().to_dimension();
+        let limits = *ctx.limits();
+
+        let slot = RenderSlot::acquire()?;
+        let state = slot.state.clone();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::Builder::new().name("svg-render".to_string()).spawn(move || {
+            let _slot = slot;
+            let tree = match Tree::from_data(&data, &options()) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    let _ = sender.send(Err(ThumbnailError::Unsupported(format!("Invalid SVG: {}", e))));
+                    return;
+                }
+            };
+            let _ = sender.send(render(&tree, dimension, &limits));
+        })?;
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(result) => result.map(DynamicImage::ImageRgba8),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Counted before the state changes so that the thread, which
+                // only decrements after seeing GIVEN_UP, never underflows.
+                ABANDONED.fetch_add(1, Ordering::SeqCst);
+                if state
+                    .compare_exchange(RUNNING, GIVEN_UP, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    ABANDONED.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(ThumbnailError::ThumbnailerTimedOut(self.name().to_string()))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(ThumbnailError::ThumbnailerCrashed(self.name().to_string())),
+        }
+    }
+}
+
+/// A place among the `MAX_RENDERS` render threads, held by the thread until
+/// it finishes.
+struct RenderSlot {
+    state: Arc<AtomicU8>,
+}
+
+impl RenderSlot {
+    fn acquire() -> Result<Self, ThumbnailError> {
+        let busy = || ThumbnailError::Unsupported("SVG renderer busy".to_string());
+        if ABANDONED.load(Ordering::SeqCst) > 0 {
+            return Err(busy());
+        }
+        RENDERS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (running < MAX_RENDERS).then_some(running + 1)
+            })
+            .map_err(|_| busy())?;
+        Ok(RenderSlot {
+            state: Arc::new(AtomicU8::new(RUNNING)),
+        })
+    }
+}
+
+impl Drop for RenderSlot {
+    fn drop(&mut self) {
+        if self.state.swap(FINISHED, Ordering::SeqCst) == GIVEN_UP {
+            ABANDONED.fetch_sub(1, Ordering::SeqCst);
+        }
+        RENDERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the document, decompressing `.svgz` files.
+fn read_document<R: Read>(reader: R) -> Result<Vec<u8>, ThumbnailError> {
+    let mut data = Vec::new();
+    reader.take(MAX_SVG_BYTES + 1).read_to_end(&mut data)?;
+    if data.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&data[..])
+            .take(MAX_SVG_BYTES + 1)
+            .read_to_end(&mut decompressed)?;
+        data = decompressed;
+    }
+    if data.len() as u64 > MAX_SVG_BYTES {
+        return Err(ThumbnailError::LimitsExceeded(format!(
+            "SVG document exceeds {} bytes",
+            MAX_SVG_BYTES
+        )));
+    }
+    Ok(data)
+}
+
+/// Parsing options that keep the renderer away from the file system.
+fn options() -> Options<'static> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    let fontdb = FONTS.get_or_init(|| {
+        let mut db = fontdb::Database::new();
+        db.load_font_data(FONT.to_vec());
+        db.set_serif_family(FONT_FAMILY);
+        db.set_sans_serif_family(FONT_FAMILY);
+        db.set_monospace_family(FONT_FAMILY);
+        db.set_cursive_family(FONT_FAMILY);
+        db.set_fantasy_family(FONT_FAMILY);
+        Arc::new(db)
+    });
+
+    Options {
+        resources_dir: None,
+        font_family: "monospace".to_string(),
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|href, _| {
+                debug!("Ignoring external SVG image reference {}", href);
+                None
+            }),
+        },
+        fontdb: fontdb.clone(),
+        ..Options::default()
+    }
+}
+
+/// Renders `tree` scaled to fit a `dimension` sized square.
+fn render(tree: &Tree, dimension: u32, limits: &DecodeLimits) -> Result<RgbaImage, ThumbnailError> {
+    let size = tree.size();
+    let scale = dimension as f32 / size.width().max(size.height());
+    let width = (size.width() * scale).round().max(1.0) as u32;
+    let height = (size.height() * scale).round().max(1.0) as u32;
+    limits.check(width, height, 4)?;
+    check_group(tree.root(), scale, &mut Budget::new(width, height))?;
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| ThumbnailError::Unsupported(format!("Cannot render SVG at {}x{}", width, height)))?;
+    resvg::render(tree, Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+    debug!("Rendered {}x{} SVG at {}x{}", size.width(), size.height(), width, height);
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| ThumbnailError::Unsupported("Invalid SVG render buffer".to_string()))
+}
+
+/// The work counted so far by `check_group` for a `width`x`height` render.
+struct Budget {
+    width: f32,
+    height: f32,
+    nodes: usize,
+    filter_pixels: f64,
+}
+
+impl Budget {
+    fn new(width: u32, height: u32) -> Self {
+        Budget {
+            width: width as f32,
+            height: height as f32,
+            nodes: 0,
+            filter_pixels: 0.0,
+        }
+    }
+
+    /// The area of `region` that lies on the canvas; resvg does not filter
+    /// outside of it.
+    fn visible_area(&self, region: NonZeroRect) -> f64 {
+        let width = region.right().min(self.width) - region.left().max(0.0);
+        let height = region.bottom().min(self.height) - region.top().max(0.0);
+        width.max(0.0) as f64 * height.max(0.0) as f64
+    }
+}
+
+/// Counts the elements and filtered area below `group`, failing as soon as
+/// either exceeds its limit.
+fn check_group(group: &Group, scale: f32, budget: &mut Budget) -> Result<(), ThumbnailError> {
+    let region = group
+        .filters_bounding_box()
+        .and_then(|region| region.transform(group.abs_transform().post_scale(scale, scale)));
+    if let Some(region) = region {
+        budget.filter_pixels += budget.visible_area(region) * group.filters().len() as f64;
+        if budget.filter_pixels > MAX_FILTER_PIXELS {
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "SVG filters cover more than {} pixels",
+                MAX_FILTER_PIXELS
+            )));
+        }
+    }
+
+    for node in group.children() {
+        budget.nodes += 1;
+        if budget.nodes > MAX_NODES {
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "SVG has more than {} elements",
+                MAX_NODES
+            )));
+        }
+        if let Node::Group(child) = node {
+            check_group(child, scale, budget)?;
+        }
+        let mut result = Ok(());
+        node.subroots(|subroot| {
+            if result.is_ok() {
+                result = check_group(subroot, scale, budget);
+            }
+        });
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::{Cursor, Write};
+
+    use super::SvgGenerator;
+    use crate::error::ThumbnailError;
+    use crate::generators::{GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    const SVG: &[u8] = br##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="20" height="10">
+        <rect width="10" height="10" fill="#ff0000"/>
+        <image x="10" width="10" height="10" xlink:href="/etc/passwd"/>
+        <script>alert(1)</script>
+    </svg>"##;
+
+    fn try_generate(data: Vec<u8>) -> Result<image::RgbaImage, ThumbnailError> {
+        let mut cursor = Cursor::new(data);
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "image/svg+xml");
+        SvgGenerator::default()
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .map(|img| img.into_rgba8())
+    }
+
+    fn generate(data: Vec<u8>) -> image::RgbaImage {
+        try_generate(data).unwrap()
+    }
+
+    /// A document that uses `leaf` ten thousand times through nested `<use>`
+    /// elements.
+    fn nested_uses(defs: &str, leaf: &str) -> Vec<u8> {
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="20" height="10"><defs>{}<g id="l0">{}</g>"#,
+            defs, leaf
+        );
+        for level in 1..5 {
+            svg.push_str(&format!(r#"<g id="l{}">"#, level));
+            for _ in 0..10 {
+                svg.push_str(&format!(r##"<use xlink:href="#l{}"/>"##, level - 1));
+            }
+            svg.push_str("</g>");
+        }
+        svg.push_str(r##"</defs><use xlink:href="#l4"/></svg>"##);
+        svg.into_bytes()
+    }
+
+    #[test]
+    fn test_render_to_bounding_box() {
+        let img = generate(SVG.to_vec());
+        assert_eq!(img.dimensions(), (128, 64));
+        assert_eq!(img.get_pixel(10, 10).0, [255, 0, 0, 255]);
+        // The external image is not loaded, leaving the right half empty.
+        assert_eq!(img.get_pixel(100, 30).0[3], 0);
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::fast());
+        gzip.write_all(SVG).unwrap();
+        assert_eq!(generate(gzip.finish().unwrap()), img);
+    }
+
+    #[test]
+    fn test_limits() {
+        let nodes = nested_uses("", &r#"<rect width="1" height="1"/>"#.repeat(10));
+        match try_generate(nodes) {
+            Err(ThumbnailError::LimitsExceeded(message)) => assert!(message.contains("elements"), "{}", message),
+            other => panic!("unexpected result {:?}", other.map(|img| img.dimensions())),
+        }
+
+        let filters = nested_uses(
+            r#"<filter id="f" filterUnits="userSpaceOnUse" x="0" y="0" width="20" height="10"><feGaussianBlur stdDeviation="2"/></filter>"#,
+            r#"<rect width="10" height="10" filter="url(#f)"/>"#,
+        );
+        match try_generate(filters) {
+            Err(ThumbnailError::LimitsExceeded(message)) => assert!(message.contains("filters"), "{}", message),
+            other => panic!("unexpected result {:?}", other.map(|img| img.dimensions())),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Fixtures shared by the generator tests.
+
+use image::{codecs::jpeg::JpegEncoder, ImageFormat, RgbImage};
+use std::io::Cursor;
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+    sizes::ThumbnailSize,
+};
+
+/// A black `width`x`height` PNG.
+pub(crate) fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    RgbImage::new(width, height)
+        .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .unwrap();
+    out
+}
+
+/// A black `width`x`height` JPEG.
+pub(crate) fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    JpegEncoder::new(&mut out)
+        .encode_image(&RgbImage::new(width, height))
+        .unwrap();
+    out
+}
+
+/// An ISO base media file format box.
+pub(crate) fn make_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Runs `generator` on `data` at the normal size and returns the size of
+/// the image it produced.
+pub(crate) fn generate(
+    generator: &dyn ThumbnailGenerator,
+    data: Vec<u8>,
+    mime_type: &str,
+) -> Result<(u32, u32), ThumbnailError> {
+    let mut cursor = Cursor::new(data);
+    let mut ctx = GenerateContext::new(ThumbnailSize::Normal, mime_type);
+    generator
+        .generate(Source::Reader(&mut cursor), &mut ctx)
+        .map(|img| (img.width(), img.height()))
+}
@@ -0,0 +1,414 @@
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, Rgb, RgbImage};
+use log::debug;
+use std::io::Read;
+
+use crate::{
+    error::ThumbnailError,
+    generators::{GenerateContext, Source, ThumbnailGenerator},
+};
+
+/// Text MIME types. Everything under `text/` is rendered, plus the common
+/// textual formats filed under `application/`.
+pub const MIME_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/x-shellscript",
+    "application/x-sh",
+    "application/toml",
+    "application/x-yaml",
+    "application/yaml",
+    "application/xml",
+    "application/sql",
+];
+
+/// The bundled monospace font, Hack (MIT and Bitstream Vera licenses, see
+/// `assets/fonts/Hack-LICENSE.txt`).
+pub(crate) static FONT: &[u8] = include_bytes!("../../assets/fonts/Hack-Regular.ttf");
+
+/// Only the start of the file is read.
+const MAX_TEXT_BYTES: u64 = 64 * 1024;
+
+/// Tab stops are this many columns apart.
+const TAB_WIDTH: usize = 4;
+
+/// Width of the page relative to its height, as for A4 paper.
+const PAGE_ASPECT: f32 = 0.707;
+
+const PAGE: Rgb<u8> = Rgb([255, 255, 255]);
+const BORDER: Rgb<u8> = Rgb([192, 192, 192]);
+const PLAIN: Rgb<u8> = Rgb([32, 32, 32]);
+const KEYWORD: Rgb<u8> = Rgb([26, 79, 176]);
+const STRING: Rgb<u8> = Rgb([46, 125, 50]);
+const NUMBER: Rgb<u8> = Rgb([176, 90, 0]);
+const COMMENT: Rgb<u8> = Rgb([138, 138, 138]);
+
+/// Renders the first lines of text files onto a page-shaped thumbnail with
+/// the bundled Hack font.
+///
+/// The encoding is detected from a UTF-8 or UTF-16 byte order mark, then by
+/// trying UTF-8, falling back to Latin-1. Files containing NUL bytes are
+/// treated as binary and declined. Source code of a few common languages is
+/// coloured by a lightweight tokenizer selected by MIME type.
+#[derive(Debug, Clone)]
+pub struct TextPreviewGenerator {
+    /// The number of lines the page holds.
+    pub max_lines: usize,
+    /// Whether to colour keywords, strings, numbers and comments.
+    pub syntax_colouring: bool,
+}
+
+impl Default for TextPreviewGenerator {
+    fn default() -> Self {
+        TextPreviewGenerator {
+            max_lines: 40,
+            syntax_colouring: true,
+        }
+    }
+}
+
+impl ThumbnailGenerator for TextPreviewGenerator {
+    fn name(&self) -> &str {
+        "text-preview"
+    }
+
+    fn generate(&self, mut source: Source<'_>, ctx: &mut GenerateContext) -> Result<DynamicImage, ThumbnailError> {
+        let mut bytes = Vec::new();
+        source.reader()?.take(MAX_TEXT_BYTES).read_to_end(&mut bytes)?;
+        let text = decode_text(&bytes).ok_or_else(|| ThumbnailError::Unsupported("Binary data in text file".to_string()))?;
+
+        let syntax = if self.syntax_colouring {
+            syntax_for(ctx.mime_type())
+        } else {
+            None
+        };
+        debug!(
+            "Rendering text preview of {} bytes with {} colouring",
+            bytes.len(),
+            if syntax.is_some() { "syntax" } else { "no" }
+        );
+
+        // Rendered at twice the thumbnail size so the downscale smooths the glyphs.
+        let height = ctx.size().to_dimension() * 2;
+        let width = (height as f32 * PAGE_ASPECT).round() as u32;
+        Ok(DynamicImage::ImageRgb8(render_page(&text, syntax, self.max_lines.max(1), width, height)))
+    }
+}
+
+/// Decodes text, returning `None` for binary data.
+fn decode_text(bytes: &[u8]) -> Option<String> {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return Some(String::from_utf8_lossy(rest).into_owned());
+    }
+    let utf16 = match bytes {
+        [0xFF, 0xFE, rest @ ..] => Some((rest, false)),
+        [0xFE, 0xFF, rest @ ..] => Some((rest, true)),
+        _ => None,
+    };
+    if let Some((rest, big_endian)) = utf16 {
+        let units = rest.chunks_exact(2).map(|c| {
+            if big_endian {
+                u16::from_be_bytes([c[0], c[1]])
+            } else {
+                u16::from_le_bytes([c[0], c[1]])
+            }
+        });
+        return Some(
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        );
+    }
+
+    if bytes.contains(&0) {
+        return None;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text.to_string()),
+        // A character cut off by the read limit does not rule out UTF-8.
+        Err(e) if e.error_len().is_none() => Some(String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned()),
+        Err(_) => Some(bytes.iter().map(|&b| b as char).collect()),
+    }
+}
+
+/// Draws the lines of `text` that fit on a `width`x`height` page.
+fn render_page(text: &str, syntax: Option<&Syntax>, max_lines: usize, width: u32, height: u32) -> RgbImage {
+    let mut page = RgbImage::from_pixel(width, height, PAGE);
+    for x in 0..width {
+        page.put_pixel(x, 0, BORDER);
+        page.put_pixel(x, height - 1, BORDER);
+    }
+    for y in 0..height {
+        page.put_pixel(0, y, BORDER);
+        page.put_pixel(width - 1, y, BORDER);
+    }
+
+    let font = FontRef::try_from_slice(FONT).expect("bundled font is valid");
+    let margin = width as f32 / 16.0;
+    let line_height = (height as f32 - 2.0 * margin) / max_lines as f32;
+    // Hack's line spacing is about 1.17 times its size.
+    let font = font.into_scaled(PxScale::from(line_height / 1.17));
+    let advance = font.h_advance(font.glyph_id('M'));
+    let columns = ((width as f32 - 2.0 * margin) / advance).floor() as usize;
+
+    let mut in_block_comment = false;
+    for (row, line) in text.lines().take(max_lines).enumerate() {
+        let line = expand_tabs(line);
+        let colours = match syntax {
+            Some(syntax) => colour_line(&line, syntax, &mut in_block_comment),
+            None => vec![PLAIN; line.len()],
+        };
+        let baseline = margin + row as f32 * line_height + font.ascent();
+
+        for (column, (&c, &colour)) in line.iter().zip(&colours).take(columns).enumerate() {
+            if c.is_whitespace() || c.is_control() {
+                continue;
+            }
+            let mut glyph = font.scaled_glyph(c);
+            glyph.position = point(margin + column as f32 * advance, baseline);
+            let Some(outline) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = bounds.min.x as i64 + x as i64;
+                let y = bounds.min.y as i64 + y as i64;
+                if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                    return;
+                }
+                let pixel = page.get_pixel_mut(x as u32, y as u32);
+                for i in 0..3 {
+                    let blended = pixel[i] as f32 + (colour[i] as f32 - pixel[i] as f32) * coverage.min(1.0);
+                    pixel[i] = blended.round() as u8;
+                }
+            });
+        }
+    }
+    page
+}
+
+/// Replaces tabs with spaces up to the next tab stop.
+fn expand_tabs(line: &str) -> Vec<char> {
+    let mut out = Vec::with_capacity(line.len());
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - out.len() % TAB_WIDTH;
+            out.extend(std::iter::repeat_n(' ', spaces));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Comment markers and keywords of a language.
+#[derive(Debug)]
+struct Syntax {
+    line_comment: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    keywords: &'static [&'static str],
+}
+
+const C_LIKE: Syntax = Syntax {
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    keywords: &[
+        "as", "async", "await", "break", "case", "catch", "char", "class", "const", "continue", "default", "do",
+        "double", "else", "enum", "export", "extends", "extern", "false", "final", "float", "fn", "for", "func",
+        "function", "go", "if", "impl", "import", "in", "int", "interface", "let", "long", "loop", "match", "mod",
+        "mut", "new", "null", "package", "private", "protected", "pub", "public", "return", "self", "short",
+        "static", "struct", "super", "switch", "this", "throw", "trait", "true", "try", "type", "typedef", "unsigned",
+        "use", "var", "void", "where", "while",
+    ],
+};
+
+const PYTHON: Syntax = Syntax {
+    line_comment: &["#"],
+    block_comment: None,
+    keywords: &[
+        "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "not",
+        "or", "pass", "raise", "return", "try", "while", "with", "yield",
+    ],
+};
+
+const SHELL: Syntax = Syntax {
+    line_comment: &["#"],
+    block_comment: None,
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if", "in", "local",
+        "return", "then", "until", "while",
+    ],
+};
+
+const CONFIG: Syntax = Syntax {
+    line_comment: &["#"],
+    block_comment: None,
+    keywords: &["false", "no", "null", "true", "yes"],
+};
+
+const SQL: Syntax = Syntax {
+    line_comment: &["--"],
+    block_comment: Some(("/*", "*/")),
+    keywords: &[
+        "AND", "AS", "BY", "CREATE", "DELETE", "FROM", "GROUP", "INSERT", "INTO", "JOIN", "NOT", "NULL", "ON", "OR",
+        "ORDER", "SELECT", "SET", "TABLE", "UPDATE", "VALUES", "WHERE",
+    ],
+};
+
+/// The MIME types each syntax applies to.
+const SYNTAXES: &[(&[&str], &Syntax)] = &[
+    (
+        &[
+            "text/rust",
+            "text/x-rust",
+            "text/x-c",
+            "text/x-csrc",
+            "text/x-chdr",
+            "text/x-c++src",
+            "text/x-c++hdr",
+            "text/x-java",
+            "text/x-java-source",
+            "text/x-go",
+            "text/x-csharp",
+            "text/javascript",
+            "application/javascript",
+            "application/json",
+        ],
+        &C_LIKE,
+    ),
+    (&["text/x-python", "text/x-python3", "text/x-script.python"], &PYTHON),
+    (
+        &["application/x-shellscript", "application/x-sh", "text/x-sh", "text/x-shellscript"],
+        &SHELL,
+    ),
+    (
+        &["application/toml", "text/x-toml", "application/x-yaml", "application/yaml", "text/x-yaml", "text/yaml"],
+        &CONFIG,
+    ),
+    (&["application/sql", "text/x-sql"], &SQL),
+];
+
+fn syntax_for(mime_type: &str) -> Option<&'static Syntax> {
+    SYNTAXES
+        .iter()
+        .find(|(mime_types, _)| mime_types.contains(&mime_type))
+        .map(|(_, syntax)| *syntax)
+}
+
+/// Colours the characters of one line. `in_block_comment` carries an open
+/// block comment over to the next line.
+fn colour_line(line: &[char], syntax: &Syntax, in_block_comment: &mut bool) -> Vec<Rgb<u8>> {
+    let starts_with = |i: usize, marker: &str| marker.chars().enumerate().all(|(j, m)| line.get(i + j) == Some(&m));
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut colours = vec![PLAIN; line.len()];
+    let mut i = 0;
+    while i < line.len() {
+        let start = i;
+        let c = line[i];
+        let colour = if *in_block_comment {
+            let (_, end) = syntax.block_comment.unwrap_or(("", ""));
+            while i < line.len() && !starts_with(i, end) {
+                i += 1;
+            }
+            if i < line.len() {
+                i += end.chars().count();
+                *in_block_comment = false;
+            }
+            COMMENT
+        } else if let Some((open, _)) = syntax.block_comment.filter(|(open, _)| starts_with(i, open)) {
+            i += open.chars().count();
+            *in_block_comment = true;
+            COMMENT
+        } else if syntax.line_comment.iter().any(|marker| starts_with(i, marker)) {
+            i = line.len();
+            COMMENT
+        } else if c == '"' || (c == '\'' && line[i + 1..].iter().take(3).any(|&q| q == '\'')) {
+            i += 1;
+            while i < line.len() && line[i] != c {
+                i += if line[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(line.len());
+            STRING
+        } else if c.is_ascii_digit() && (i == 0 || !is_ident(line[i - 1])) {
+            while i < line.len() && (is_ident(line[i]) || line[i] == '.') {
+                i += 1;
+            }
+            NUMBER
+        } else if is_ident(c) {
+            while i < line.len() && is_ident(line[i]) {
+                i += 1;
+            }
+            let word: String = line[start..i].iter().collect();
+            if syntax.keywords.contains(&word.as_str()) {
+                KEYWORD
+            } else {
+                PLAIN
+            }
+        } else {
+            i += 1;
+            PLAIN
+        };
+        colours[start..i].fill(colour);
+    }
+    colours
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{colour_line, decode_text, TextPreviewGenerator, C_LIKE, COMMENT, KEYWORD, NUMBER, PLAIN, STRING};
+    use crate::generators::{GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text(b"\xEF\xBB\xBFhi").unwrap(), "hi");
+        assert_eq!(decode_text(b"\xFF\xFEh\0i\0").unwrap(), "hi");
+        assert_eq!(decode_text(b"\xFE\xFF\0h\0i").unwrap(), "hi");
+        assert_eq!(decode_text("caf\u{e9}".as_bytes()).unwrap(), "caf\u{e9}");
+        // Cut off in the middle of a two byte character.
+        assert_eq!(decode_text(b"caf\xC3").unwrap(), "caf");
+        assert_eq!(decode_text(b"caf\xE9 au lait").unwrap(), "caf\u{e9} au lait");
+        assert!(decode_text(b"\x7FELF\x02\x01\x01\0").is_none());
+    }
+
+    #[test]
+    fn test_colour_line() {
+        let line: Vec<char> = "let x = \"a\"; // 1".chars().collect();
+        let mut in_block = false;
+        let colours = colour_line(&line, &C_LIKE, &mut in_block);
+        assert_eq!(colours[0], KEYWORD);
+        assert_eq!(colours[4], PLAIN);
+        assert_eq!(colours[9], STRING);
+        assert_eq!(colours[16], COMMENT);
+
+        let line: Vec<char> = "x1 = 42 /* open".chars().collect();
+        let colours = colour_line(&line, &C_LIKE, &mut in_block);
+        assert_eq!(colours[1], PLAIN);
+        assert_eq!(colours[5], NUMBER);
+        assert!(in_block);
+        let line: Vec<char> = "close */ fn".chars().collect();
+        let colours = colour_line(&line, &C_LIKE, &mut in_block);
+        assert_eq!(colours[0], COMMENT);
+        assert_eq!(colours[9], KEYWORD);
+        assert!(!in_block);
+    }
+
+    #[test]
+    fn test_render_page() {
+        let mut cursor = Cursor::new(b"fn main() {\n\tprintln!(\"hello\");\n}\n".to_vec());
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "text/rust");
+        let img = TextPreviewGenerator::default()
+            .generate(Source::Reader(&mut cursor), &mut ctx)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(img.dimensions(), (181, 256));
+        // Text is drawn in the top left, the bottom of the page stays blank.
+        assert!(img.enumerate_pixels().any(|(x, y, p)| x < 90 && y < 40 && p.0 != [255, 255, 255]));
+        assert!(img.enumerate_pixels().all(|(x, y, p)| !(1..180).contains(&x) || !(128..255).contains(&y) || p.0 == [255, 255, 255]));
+    }
+}
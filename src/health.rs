@@ -0,0 +1,167 @@
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Settings for suspending thumbnailers that keep crashing or timing out.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    /// Number of consecutive crashes or timeouts before a thumbnailer is suspended.
+    pub failure_threshold: u32,
+    /// How long a tripped thumbnailer stays suspended before it is tried again.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The kind of failure that counts against a thumbnailer's health.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The process was killed by a signal or could not be loaded.
+    Crashed,
+    /// The process exceeded the configured timeout and was killed.
+    TimedOut,
+}
+
+/// Health record for a single thumbnailer.
+#[derive(Debug, Clone, Default)]
+pub struct ThumbnailerHealth {
+    /// Crashes or timeouts since the last successful run.
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub total_successes: u64,
+    pub last_failure: Option<FailureKind>,
+    /// Set while the thumbnailer is suspended by the circuit breaker.
+    pub suspended_until: Option<Instant>,
+}
+
+impl ThumbnailerHealth {
+    /// Returns true if the thumbnailer is currently suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_until.is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// Tracks the health of external thumbnailers across calls.
+///
+/// Thumbnailers are keyed by the path of their `.thumbnailer` file.
+#[derive(Debug, Default)]
+pub struct HealthTracker {
+    entries: Mutex<HashMap<String, ThumbnailerHealth>>,
+}
+
+static GLOBAL: LazyLock<HealthTracker> = LazyLock::new(HealthTracker::new);
+
+impl HealthTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process-wide tracker used by `generate_thumbnail`.
+    pub fn global() -> &'static HealthTracker {
+        &GLOBAL
+    }
+
+    /// Returns the health record of a thumbnailer, if it has been run.
+    pub fn get(&self, thumbnailer: &str) -> Option<ThumbnailerHealth> {
+        self.lock().get(thumbnailer).cloned()
+    }
+
+    /// Returns the health records of every thumbnailer that has been run.
+    pub fn snapshot(&self) -> Vec<(String, ThumbnailerHealth)> {
+        self.lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Forgets the history of a thumbnailer, lifting any suspension.
+    pub fn reset(&self, thumbnailer: &str) {
+        self.lock().remove(thumbnailer);
+    }
+
+    /// Returns the remaining suspension time if the thumbnailer must not be run.
+    pub(crate) fn suspended_for(&self, thumbnailer: &str) -> Option<Duration> {
+        let entries = self.lock();
+        let until = entries.get(thumbnailer)?.suspended_until?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    pub(crate) fn record_success(&self, thumbnailer: &str) {
+        let mut entries = self.lock();
+        let entry = entries.entry(thumbnailer.to_string()).or_default();
+        entry.consecutive_failures = 0;
+        entry.total_successes += 1;
+        entry.suspended_until = None;
+    }
+
+    pub(crate) fn record_failure(
+        &self,
+        thumbnailer: &str,
+        kind: FailureKind,
+        breaker: Option<&CircuitBreaker>,
+    ) {
+        let mut entries = self.lock();
+        let entry = entries.entry(thumbnailer.to_string()).or_default();
+        entry.consecutive_failures += 1;
+        entry.total_failures += 1;
+        entry.last_failure = Some(kind);
+        debug!(
+            "Thumbnailer {} failed ({:?}), {} consecutive failures",
+            thumbnailer, kind, entry.consecutive_failures
+        );
+
+        if let Some(breaker) = breaker {
+            if entry.consecutive_failures >= breaker.failure_threshold {
+                warn!(
+                    "Suspending thumbnailer {} for {:?} after {} consecutive failures",
+                    thumbnailer, breaker.cooldown, entry.consecutive_failures
+                );
+                entry.suspended_until = Some(Instant::now() + breaker.cooldown);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, ThumbnailerHealth>> {
+        // The map stays consistent even if a holder panicked, so ignore poisoning.
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CircuitBreaker, FailureKind, HealthTracker};
+
+    #[test]
+    fn test_breaker_trips_and_resets() {
+        let tracker = HealthTracker::new();
+        let breaker = CircuitBreaker {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        };
+
+        tracker.record_failure("a", FailureKind::Crashed, Some(&breaker));
+        assert!(tracker.suspended_for("a").is_none());
+
+        tracker.record_failure("a", FailureKind::TimedOut, Some(&breaker));
+        assert!(tracker.suspended_for("a").is_some());
+        assert!(tracker.get("a").unwrap().is_suspended());
+
+        tracker.record_success("a");
+        let health = tracker.get("a").unwrap();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.total_failures, 2);
+        assert!(!health.is_suspended());
+    }
+}
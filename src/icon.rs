@@ -0,0 +1,282 @@
+//! Readers for the entries of Windows ICO and CUR files and Apple ICNS
+//! files. Each entry is described by its size and bit depth, read from the
+//! image data itself since icon directories are often wrong or, for cursors,
+//! hold the hotspot instead.
+
+use image::RgbaImage;
+use std::io;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// ICNS element types holding a PNG or JPEG 2000 stream, or for `ic04` and
+/// `ic05` also packed ARGB data.
+const ICNS_IMAGE_TYPES: &[&[u8; 4]] = &[
+    b"icp4", b"icp5", b"icp6", b"ic04", b"ic05", b"ic07", b"ic08", b"ic09", b"ic10", b"ic11", b"ic12", b"ic13", b"ic14",
+];
+
+/// ICNS packed RGB element types with their size and the matching mask.
+const ICNS_RGB_TYPES: &[(&[u8; 4], u32, &[u8; 4])] = &[
+    (b"is32", 16, b"s8mk"),
+    (b"il32", 32, b"l8mk"),
+    (b"ih32", 48, b"h8mk"),
+    (b"it32", 128, b"t8mk"),
+];
+
+/// Sizes of the packed ARGB element types.
+const ICNS_ARGB_TYPES: &[(&[u8; 4], u32)] = &[(b"ic04", 16), (b"ic05", 32)];
+
+/// One image of an icon file.
+#[derive(Debug, Clone)]
+pub struct IconEntry<'a> {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel, including the alpha mask of ICNS entries.
+    pub bit_depth: u16,
+    pub data: EntryData<'a>,
+}
+
+/// The encoded image of an entry.
+#[derive(Debug, Clone)]
+pub enum EntryData<'a> {
+    /// A PNG stream.
+    Png(&'a [u8]),
+    /// An ICO file holding only this BMP entry, which the `image` crate
+    /// decodes along with its transparency mask.
+    Ico(Vec<u8>),
+    /// ICNS run-length packed RGB channels with an optional 8-bit mask.
+    PackedRgb { data: &'a [u8], mask: Option<&'a [u8]> },
+    /// ICNS run-length packed ARGB channels.
+    PackedArgb(&'a [u8]),
+}
+
+/// Lists the entries of an ICO or CUR file. Entries pointing outside the
+/// file or holding neither PNG nor BMP data are left out.
+pub fn ico_entries(data: &[u8]) -> io::Result<Vec<IconEntry<'_>>> {
+    if data.len() < 6 || data[..2] != [0, 0] || !matches!(u16_le(&data[2..]), 1 | 2) {
+        return Err(invalid("Not an ICO or CUR file"));
+    }
+    let count = u16_le(&data[4..]) as usize;
+
+    let mut entries = Vec::new();
+    for index in 0..count {
+        let Some(dir) = data.get(6 + index * 16..6 + (index + 1) * 16) else {
+            break;
+        };
+        let (len, offset) = (u32_le(&dir[8..]) as usize, u32_le(&dir[12..]) as usize);
+        let Some(image) = offset.checked_add(len).and_then(|end| data.get(offset..end)) else {
+            continue;
+        };
+        if let Some(entry) = png_entry(image).or_else(|| bmp_entry(image)) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads the size and depth of a PNG stream from its `IHDR` chunk.
+fn png_entry(data: &[u8]) -> Option<IconEntry<'_>> {
+    if !data.starts_with(PNG_MAGIC) || data.len() < 26 || &data[12..16] != b"IHDR" {
+        return None;
+    }
+    let channels = match data[25] {
+        0 | 3 => 1,
+        4 => 2,
+        2 => 3,
+        6 => 4,
+        _ => return None,
+    };
+    Some(IconEntry {
+        width: u32_be(&data[16..]),
+        height: u32_be(&data[20..]),
+        bit_depth: data[24] as u16 * channels,
+        data: EntryData::Png(data),
+    })
+}
+
+/// Reads a BMP entry, whose height counts both the color and the mask rows.
+fn bmp_entry(data: &[u8]) -> Option<IconEntry<'_>> {
+    if data.len() < 40 || u32_le(data) < 40 {
+        return None;
+    }
+    let width = i32::from_le_bytes(data[4..8].try_into().unwrap()).unsigned_abs();
+    let height = i32::from_le_bytes(data[8..12].try_into().unwrap()).unsigned_abs() / 2;
+    let bit_depth = u16_le(&data[14..]);
+    if width == 0 || height == 0 || width > 0x10000 || height > 0x10000 {
+        return None;
+    }
+
+    // The directory stores 256 and larger as 0.
+    let dir_size = |size: u32| if size >= 256 { 0 } else { size as u8 };
+    let mut ico = vec![0, 0, 1, 0, 1, 0, dir_size(width), dir_size(height), 0, 0, 1, 0];
+    ico.extend(bit_depth.to_le_bytes());
+    ico.extend((data.len() as u32).to_le_bytes());
+    ico.extend(22u32.to_le_bytes());
+    ico.extend_from_slice(data);
+    Some(IconEntry {
+        width,
+        height,
+        bit_depth,
+        data: EntryData::Ico(ico),
+    })
+}
+
+/// Lists the entries of an ICNS file. JPEG 2000 entries and unknown element
+/// types are left out.
+pub fn icns_entries(data: &[u8]) -> io::Result<Vec<IconEntry<'_>>> {
+    if data.len() < 8 || &data[..4] != b"icns" {
+        return Err(invalid("Not an ICNS file"));
+    }
+    let end = (u32_be(&data[4..]) as usize).min(data.len());
+
+    let mut elements = Vec::new();
+    let mut pos = 8;
+    while pos + 8 <= end {
+        let kind: &[u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let len = u32_be(&data[pos + 4..]) as usize;
+        if len < 8 || pos + len > end {
+            break;
+        }
+        elements.push((kind, &data[pos + 8..pos + len]));
+        pos += len;
+    }
+    let find = |kind: &[u8; 4]| elements.iter().find(|(k, _)| *k == kind).map(|(_, data)| *data);
+
+    let mut entries = Vec::new();
+    for &(kind, data) in &elements {
+        if let Some(&(_, size, mask)) = ICNS_RGB_TYPES.iter().find(|(k, _, _)| *k == kind) {
+            let mask = find(mask);
+            // 128x128 channels start after four zero bytes.
+            let data = if kind == b"it32" { data.get(4..).unwrap_or_default() } else { data };
+            entries.push(IconEntry {
+                width: size,
+                height: size,
+                bit_depth: if mask.is_some() { 32 } else { 24 },
+                data: EntryData::PackedRgb { data, mask },
+            });
+        } else if ICNS_IMAGE_TYPES.contains(&kind) {
+            if let Some(entry) = png_entry(data) {
+                entries.push(entry);
+            } else if let (Some(packed), Some(&(_, size))) =
+                (data.strip_prefix(b"ARGB"), ICNS_ARGB_TYPES.iter().find(|(k, _)| *k == kind))
+            {
+                entries.push(IconEntry {
+                    width: size,
+                    height: size,
+                    bit_depth: 32,
+                    data: EntryData::PackedArgb(packed),
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+impl IconEntry<'_> {
+    /// Decodes a packed ICNS entry. Returns `None` for PNG and BMP entries,
+    /// which are left to the `image` crate.
+    pub fn unpack(&self) -> io::Result<Option<RgbaImage>> {
+        let pixels = self.width as usize * self.height as usize;
+        let rgba = match &self.data {
+            EntryData::PackedRgb { data, mask } => {
+                let planes = unpack_planes(data, 3, pixels)?;
+                let alpha = match *mask {
+                    Some(mask) if mask.len() >= pixels => &mask[..pixels],
+                    Some(_) => return Err(invalid("Truncated ICNS mask")),
+                    None => &[],
+                };
+                (0..pixels)
+                    .flat_map(|i| {
+                        let a = alpha.get(i).copied().unwrap_or(255);
+                        [planes[i], planes[pixels + i], planes[2 * pixels + i], a]
+                    })
+                    .collect()
+            }
+            EntryData::PackedArgb(data) => {
+                let planes = unpack_planes(data, 4, pixels)?;
+                (0..pixels)
+                    .flat_map(|i| [planes[pixels + i], planes[2 * pixels + i], planes[3 * pixels + i], planes[i]])
+                    .collect()
+            }
+            EntryData::Png(_) | EntryData::Ico(_) => return Ok(None),
+        };
+        Ok(RgbaImage::from_raw(self.width, self.height, rgba))
+    }
+}
+
+/// Expands `channels` consecutive planes of `pixels` bytes each. Small icons
+/// are sometimes stored uncompressed, which shows in the data length.
+fn unpack_planes(data: &[u8], channels: usize, pixels: usize) -> io::Result<Vec<u8>> {
+    let len = channels * pixels;
+    if data.len() == len {
+        return Ok(data.to_vec());
+    }
+
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while out.len() < len {
+        let header = *data.get(pos).ok_or_else(|| invalid("Truncated ICNS image"))? as usize;
+        pos += 1;
+        if header < 0x80 {
+            let run = data.get(pos..pos + header + 1).ok_or_else(|| invalid("Truncated ICNS image"))?;
+            out.extend_from_slice(run);
+            pos += header + 1;
+        } else {
+            let value = *data.get(pos).ok_or_else(|| invalid("Truncated ICNS image"))?;
+            out.resize(out.len() + header - 0x80 + 3, value);
+            pos += 1;
+        }
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn u32_be(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{icns_entries, EntryData};
+
+    #[test]
+    fn test_icns_packed_entry() {
+        // A 16x16 red icon, each plane packed as a run of 130 and one of 126.
+        let plane = |value: u8| vec![0xFF, value, 0xFB, value];
+        let mut rgb = Vec::new();
+        for value in [255, 0, 0] {
+            rgb.extend(plane(value));
+        }
+        let mask = vec![128u8; 256];
+
+        let element = |kind: &[u8], data: &[u8]| {
+            let mut out = kind.to_vec();
+            out.extend((data.len() as u32 + 8).to_be_bytes());
+            out.extend_from_slice(data);
+            out
+        };
+        let mut body = element(b"is32", &rgb);
+        body.extend(element(b"s8mk", &mask));
+        let mut file = b"icns".to_vec();
+        file.extend((body.len() as u32 + 8).to_be_bytes());
+        file.extend(body);
+
+        let entries = icns_entries(&file).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].width, entries[0].bit_depth), (16, 32));
+        assert!(matches!(entries[0].data, EntryData::PackedRgb { mask: Some(_), .. }));
+        let img = entries[0].unpack().unwrap().unwrap();
+        assert_eq!(img.get_pixel(15, 15).0, [255, 0, 0, 128]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Minimal reader for ISO base media file format boxes, the container of MP4,
+//! M4A, HEIF and Canon CR3 files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Upper bound on the number of sibling boxes read from one parent.
+const MAX_CHILDREN: usize = 4096;
+
+/// The position and type of a box in the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoxHeader {
+    /// The four-character box type, such as `moov`.
+    pub kind: [u8; 4],
+    /// The extended type of `uuid` boxes.
+    pub uuid: Option<[u8; 16]>,
+    /// Offset of the box header.
+    pub start: u64,
+    /// Offset of the box payload, after the header and any extended type.
+    pub data_start: u64,
+    /// Offset just past the end of the box.
+    pub end: u64,
+}
+
+impl BoxHeader {
+    /// Length of the payload in bytes.
+    pub fn data_len(&self) -> u64 {
+        self.end - self.data_start
+    }
+}
+
+/// Reads the header of the box at `pos`, which must end by `parent_end`.
+/// Returns `None` when no complete box header fits before `parent_end`.
+pub fn read_box_header<R: Read + Seek>(reader: &mut R, pos: u64, parent_end: u64) -> io::Result<Option<BoxHeader>> {
+    if pos.saturating_add(8) > parent_end {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+
+    let kind = [header[4], header[5], header[6], header[7]];
+    let mut data_start = pos + 8;
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        // The box extends to the end of its parent.
+        0 => parent_end - pos,
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            data_start += 8;
+            u64::from_be_bytes(large)
+        }
+        size => size as u64,
+    };
+
+    let uuid = if &kind == b"uuid" {
+        let mut uuid = [0u8; 16];
+        reader.read_exact(&mut uuid)?;
+        data_start += 16;
+        Some(uuid)
+    } else {
+        None
+    };
+
+    let end = pos.checked_add(size).ok_or_else(|| invalid("Box size overflows"))?;
+    if end < data_start || end > parent_end {
+        return Err(invalid("Box exceeds its parent"));
+    }
+    Ok(Some(BoxHeader {
+        kind,
+        uuid,
+        start: pos,
+        data_start,
+        end,
+    }))
+}
+
+/// Reads the headers of the boxes laid out back to back in `start..end`.
+pub fn children<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<Vec<BoxHeader>> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while let Some(header) = read_box_header(reader, pos, end)? {
+        pos = header.end;
+        boxes.push(header);
+        if boxes.len() >= MAX_CHILDREN || header.end == header.start {
+            break;
+        }
+    }
+    Ok(boxes)
+}
+
+/// Finds the first box of type `kind` in `start..end`.
+pub fn find_box<R: Read + Seek>(reader: &mut R, start: u64, end: u64, kind: &[u8; 4]) -> io::Result<Option<BoxHeader>> {
+    Ok(children(reader, start, end)?.into_iter().find(|b| &b.kind == kind))
+}
+
+/// Finds the first `uuid` box with extended type `uuid` in `start..end`.
+pub fn find_uuid_box<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    uuid: &[u8; 16],
+) -> io::Result<Option<BoxHeader>> {
+    Ok(children(reader, start, end)?
+        .into_iter()
+        .find(|b| b.uuid.as_ref() == Some(uuid)))
+}
+
+/// Reads the payload of `header`, refusing payloads larger than `max_len`.
+pub fn read_data<R: Read + Seek>(reader: &mut R, header: &BoxHeader, max_len: u64) -> io::Result<Vec<u8>> {
+    if header.data_len() > max_len {
+        return Err(invalid("Box payload too large"));
+    }
+    reader.seek(SeekFrom::Start(header.data_start))?;
+    let mut data = Vec::new();
+    reader.take(header.data_len()).read_to_end(&mut data)?;
+    if data.len() as u64 != header.data_len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated box"));
+    }
+    Ok(data)
+}
+
+/// Returns the length of the stream, for use as the end of the top level.
+pub fn stream_len<R: Seek>(reader: &mut R) -> io::Result<u64> {
+    reader.seek(SeekFrom::End(0))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{children, find_box, read_data};
+    use crate::generators::test_util::make_box;
+
+    #[test]
+    fn test_nested_boxes() {
+        let inner = make_box(b"covr", b"data");
+        let mut file = make_box(b"ftyp", b"M4A ");
+        file.extend(make_box(b"moov", &inner));
+        let len = file.len() as u64;
+        let mut cursor = Cursor::new(file);
+
+        let top = children(&mut cursor, 0, len).unwrap();
+        assert_eq!(top.len(), 2);
+        let moov = find_box(&mut cursor, 0, len, b"moov").unwrap().unwrap();
+        let covr = find_box(&mut cursor, moov.data_start, moov.end, b"covr").unwrap().unwrap();
+        assert_eq!(read_data(&mut cursor, &covr, 16).unwrap(), b"data");
+        assert!(read_data(&mut cursor, &covr, 2).is_err());
+    }
+}
@@ -1,9 +1,41 @@
+pub mod alpha;
+pub mod animation;
+pub mod blend;
+pub mod color;
 pub mod file;
+pub mod generators;
 pub mod sizes;
 pub mod hash;
+pub mod icon;
 pub mod thumbnailer;
+pub mod tiff;
+pub mod tonemap;
+pub mod ebml;
 pub mod error;
+pub mod health;
+pub mod isobmff;
+pub mod limits;
+pub mod mesh;
+pub mod mime;
+pub mod options;
+pub mod orientation;
+pub mod psd;
+pub mod resize;
+pub mod shape;
+pub mod trust;
+pub mod xcf;
 
-pub use thumbnailer::generate_thumbnail;
+pub use thumbnailer::{generate_thumbnail, generate_thumbnail_with_options};
+pub use options::ThumbnailOptions;
+pub use animation::FramePolicy;
+pub use limits::DecodeLimits;
+pub use orientation::OrientationMode;
+pub use resize::{ResizeFilter, ResizeOptions};
+pub use shape::ThumbnailShape;
+pub use alpha::AlphaPolicy;
+pub use tonemap::{ToneMapOperator, ToneMapOptions};
+pub use generators::{GenerateContext, GeneratorRegistry, Source, ThumbnailGenerator};
+pub use health::{CircuitBreaker, HealthTracker, ThumbnailerHealth};
+pub use trust::{TrustError, TrustPolicy};
 pub use sizes::ThumbnailSize;
 pub use error::ThumbnailError;
@@ -0,0 +1,109 @@
+use log::warn;
+
+use crate::error::ThumbnailError;
+
+/// Limits applied to every image decoded inside the crate, to defend against
+/// decompression bombs such as a tiny PNG declaring 100000x100000 pixels.
+///
+/// `None` disables the respective limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Maximum width of a decoded image, in pixels.
+    pub max_width: Option<u32>,
+    /// Maximum height of a decoded image, in pixels.
+    pub max_height: Option<u32>,
+    /// Maximum number of bytes a decoder may allocate.
+    pub max_alloc: Option<u64>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: Some(32_768),
+            max_height: Some(32_768),
+            max_alloc: Some(512 * 1024 * 1024),
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Disables all limits.
+    pub fn unlimited() -> Self {
+        DecodeLimits {
+            max_width: None,
+            max_height: None,
+            max_alloc: None,
+        }
+    }
+
+    /// Converts the limits for use with `image::ImageReader::limits`.
+    pub fn to_image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_alloc;
+        limits
+    }
+
+    /// Converts the allocation limit for use with `png::Decoder::new_with_limits`.
+    pub fn to_png_limits(&self) -> png::Limits {
+        png::Limits {
+            bytes: self
+                .max_alloc
+                .map_or(usize::MAX, |bytes| usize::try_from(bytes).unwrap_or(usize::MAX)),
+        }
+    }
+
+    /// Checks a `width`x`height` image with `bytes_per_pixel` bytes per pixel
+    /// against the limits, for decoders that do not take limits themselves.
+    pub fn check(&self, width: u32, height: u32, bytes_per_pixel: u64) -> Result<(), ThumbnailError> {
+        if self.max_width.is_some_and(|max| width > max) || self.max_height.is_some_and(|max| height > max) {
+            warn!("Rejecting {}x{} image: dimensions exceed decode limits", width, height);
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "{}x{} exceeds the maximum of {:?}x{:?}",
+                width, height, self.max_width, self.max_height
+            )));
+        }
+
+        let bytes = width as u64 * height as u64 * bytes_per_pixel;
+        if self.max_alloc.is_some_and(|max| bytes > max) {
+            warn!("Rejecting {}x{} image: {} bytes exceed decode limits", width, height, bytes);
+            return Err(ThumbnailError::LimitsExceeded(format!(
+                "{} bytes exceeds the allocation limit of {:?}",
+                bytes, self.max_alloc
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    use super::DecodeLimits;
+    use crate::error::ThumbnailError;
+    use crate::generators::{image::ImageGenerator, GenerateContext, Source, ThumbnailGenerator};
+    use crate::ThumbnailSize;
+
+    #[test]
+    fn test_decode_limits_reject_large_images() {
+        let mut png = Vec::new();
+        RgbImage::new(200, 100)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let limits = DecodeLimits {
+            max_width: Some(150),
+            ..DecodeLimits::default()
+        };
+        let mut cursor = Cursor::new(png);
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "image/png").with_limits(limits);
+        let result = ImageGenerator.generate(Source::Reader(&mut cursor), &mut ctx);
+        assert!(matches!(result, Err(ThumbnailError::LimitsExceeded(_))));
+
+        let mut ctx = GenerateContext::new(ThumbnailSize::Normal, "image/png");
+        assert!(ImageGenerator.generate(Source::Reader(&mut cursor), &mut ctx).is_ok());
+    }
+}
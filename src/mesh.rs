@@ -0,0 +1,454 @@
+//! Loaders for the triangle meshes of STL (binary and ASCII), Wavefront OBJ
+//! and PLY (ASCII and binary) files. Only positions and faces are read;
+//! polygons are split into triangle fans.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use crate::error::ThumbnailError;
+
+/// The file formats understood by [`read_mesh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshFormat {
+    Stl,
+    Obj,
+    Ply,
+}
+
+/// Vertex positions and the triangles indexing them.
+#[derive(Debug, Default, Clone)]
+pub struct Mesh {
+    pub vertices: Vec<[f32; 3]>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+/// Faces with more corners than this are treated as corrupt.
+const MAX_FACE_VERTICES: usize = 1024;
+
+/// Reads a mesh of at most `max_triangles` triangles, and at most three
+/// vertices per allowed triangle. Larger meshes fail with
+/// `ThumbnailError::LimitsExceeded`.
+pub fn read_mesh<R: BufRead + Seek>(reader: R, format: MeshFormat, max_triangles: usize) -> Result<Mesh, ThumbnailError> {
+    let mut builder = Builder {
+        mesh: Mesh::default(),
+        max_triangles,
+    };
+    match format {
+        MeshFormat::Stl => read_stl(reader, &mut builder)?,
+        MeshFormat::Obj => read_obj(reader, &mut builder)?,
+        MeshFormat::Ply => read_ply(reader, &mut builder)?,
+    }
+    Ok(builder.mesh)
+}
+
+/// Collects the mesh while enforcing the size limit.
+struct Builder {
+    mesh: Mesh,
+    max_triangles: usize,
+}
+
+impl Builder {
+    fn vertex(&mut self, position: [f32; 3]) -> Result<(), ThumbnailError> {
+        if self.mesh.vertices.len() >= self.max_triangles.saturating_mul(3) {
+            return Err(self.exceeded());
+        }
+        self.mesh.vertices.push(position);
+        Ok(())
+    }
+
+    /// Adds a polygon as a fan of triangles around its first corner.
+    fn face(&mut self, corners: &[u32]) -> Result<(), ThumbnailError> {
+        for pair in corners.get(1..).unwrap_or_default().windows(2) {
+            if self.mesh.triangles.len() >= self.max_triangles {
+                return Err(self.exceeded());
+            }
+            self.mesh.triangles.push([corners[0], pair[0], pair[1]]);
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self) -> ThumbnailError {
+        ThumbnailError::LimitsExceeded(format!("Mesh has more than {} triangles", self.max_triangles))
+    }
+}
+
+/// Tells binary from ASCII STL by the file size, since binary files may
+/// start with `solid` too.
+fn read_stl<R: BufRead + Seek>(mut reader: R, builder: &mut Builder) -> Result<(), ThumbnailError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(84);
+    (&mut reader).take(84).read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let count = header.get(80..84).map(|c| u32::from_le_bytes(c.try_into().unwrap()) as u64);
+    if let Some(count) = count.filter(|count| len == 84 + 50 * count) {
+        read_binary_stl(reader, count, builder)
+    } else if header.starts_with(b"solid") {
+        read_ascii_stl(reader, builder)
+    } else {
+        Err(invalid("Invalid STL file").into())
+    }
+}
+
+fn read_binary_stl<R: BufRead>(mut reader: R, count: u64, builder: &mut Builder) -> Result<(), ThumbnailError> {
+    if count > builder.max_triangles as u64 {
+        return Err(builder.exceeded());
+    }
+    reader.read_exact(&mut [0u8; 84])?;
+    let mut facet = [0u8; 50];
+    for _ in 0..count {
+        reader.read_exact(&mut facet)?;
+        // A normal, three vertices and an attribute word.
+        let first = builder.mesh.vertices.len() as u32;
+        for corner in facet[12..48].chunks_exact(12) {
+            let coord = |i: usize| f32::from_le_bytes(corner[i * 4..i * 4 + 4].try_into().unwrap());
+            builder.vertex([coord(0), coord(1), coord(2)])?;
+        }
+        builder.face(&[first, first + 1, first + 2])?;
+    }
+    Ok(())
+}
+
+fn read_ascii_stl<R: BufRead>(reader: R, builder: &mut Builder) -> Result<(), ThumbnailError> {
+    let mut corners = Vec::with_capacity(3);
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let mut tokens = std::str::from_utf8(&line).unwrap_or_default().split_whitespace();
+        match tokens.next() {
+            Some("vertex") => {
+                corners.push(builder.mesh.vertices.len() as u32);
+                builder.vertex(parse_position(tokens).ok_or_else(|| invalid("Invalid STL vertex"))?)?;
+            }
+            Some("endloop") => {
+                builder.face(&corners)?;
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads `v` and `f` statements; OBJ indices are 1-based, or relative to
+/// the last vertex when negative.
+fn read_obj<R: BufRead>(reader: R, builder: &mut Builder) -> Result<(), ThumbnailError> {
+    let mut corners = Vec::new();
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let mut tokens = std::str::from_utf8(&line).unwrap_or_default().split_whitespace();
+        match tokens.next() {
+            Some("v") => builder.vertex(parse_position(tokens).ok_or_else(|| invalid("Invalid OBJ vertex"))?)?,
+            Some("f") => {
+                corners.clear();
+                let defined = builder.mesh.vertices.len() as i64;
+                for token in tokens.take(MAX_FACE_VERTICES) {
+                    let index: i64 = token
+                        .split('/')
+                        .next()
+                        .and_then(|i| i.parse().ok())
+                        .ok_or_else(|| invalid("Invalid OBJ face"))?;
+                    let index = if index < 0 { defined + index } else { index - 1 };
+                    corners.push(u32::try_from(index).map_err(|_| invalid("Invalid OBJ face index"))?);
+                }
+                builder.face(&corners)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_position<'a>(mut tokens: impl Iterator<Item = &'a str>) -> Option<[f32; 3]> {
+    let mut coord = || tokens.next()?.parse::<f32>().ok();
+    Some([coord()?, coord()?, coord()?])
+}
+
+/// The storage of a PLY property value.
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        Some(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Property {
+    Scalar(String, Scalar),
+    List(String, Scalar, Scalar),
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: u64,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// Reads the `vertex` positions and `face` index lists of a PLY file,
+/// skipping over any other elements and properties.
+fn read_ply<R: BufRead>(mut reader: R, builder: &mut Builder) -> Result<(), ThumbnailError> {
+    let (encoding, elements) = read_ply_header(&mut reader)?;
+    let mut values = PlyValues {
+        reader,
+        encoding,
+        line: Vec::new(),
+        tokens: Vec::new(),
+    };
+
+    for element in &elements {
+        // Elements without properties take up no data, so their count would
+        // not be bounded by the file size.
+        if element.properties.is_empty() {
+            return Err(invalid("PLY element without properties").into());
+        }
+        let limit = match element.name.as_str() {
+            "vertex" => builder.max_triangles.saturating_mul(3),
+            "face" => builder.max_triangles,
+            _ => continue,
+        };
+        if element.count > limit as u64 {
+            return Err(builder.exceeded());
+        }
+    }
+
+    let mut corners = Vec::new();
+    for element in &elements {
+        for _ in 0..element.count {
+            let mut position = [0f32; 3];
+            for property in &element.properties {
+                match property {
+                    Property::Scalar(name, scalar) => {
+                        let value = values.next(*scalar)?;
+                        if element.name == "vertex" {
+                            match name.as_str() {
+                                "x" => position[0] = value as f32,
+                                "y" => position[1] = value as f32,
+                                "z" => position[2] = value as f32,
+                                _ => {}
+                            }
+                        }
+                    }
+                    Property::List(name, count, item) => {
+                        let count = values.next(*count)?;
+                        if !(0.0..=MAX_FACE_VERTICES as f64).contains(&count) {
+                            return Err(invalid("Invalid PLY list length").into());
+                        }
+                        corners.clear();
+                        for _ in 0..count as usize {
+                            corners.push(values.next(*item)? as u32);
+                        }
+                        if element.name == "face" && (name == "vertex_indices" || name == "vertex_index") {
+                            builder.face(&corners)?;
+                        }
+                    }
+                }
+            }
+            if element.name == "vertex" {
+                builder.vertex(position)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_ply_header<R: BufRead>(reader: &mut R) -> io::Result<(Encoding, Vec<Element>)> {
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut line = Vec::new();
+    let mut first = true;
+
+    loop {
+        line.clear();
+        if reader.take(1024).read_until(b'\n', &mut line)? == 0 {
+            return Err(invalid("Truncated PLY header"));
+        }
+        let text = std::str::from_utf8(&line).map_err(|_| invalid("Invalid PLY header"))?;
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        if first {
+            if tokens != ["ply"] {
+                return Err(invalid("Not a PLY file"));
+            }
+            first = false;
+            continue;
+        }
+
+        match tokens.as_slice() {
+            ["format", format, _] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    _ => return Err(invalid("Unknown PLY format")),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| invalid("Invalid PLY element count"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => {
+                let element = elements.last_mut().ok_or_else(|| invalid("PLY property outside an element"))?;
+                let count = Scalar::parse(count).ok_or_else(|| invalid("Unknown PLY type"))?;
+                let item = Scalar::parse(item).ok_or_else(|| invalid("Unknown PLY type"))?;
+                element.properties.push(Property::List(name.to_string(), count, item));
+            }
+            ["property", scalar, name] => {
+                let element = elements.last_mut().ok_or_else(|| invalid("PLY property outside an element"))?;
+                let scalar = Scalar::parse(scalar).ok_or_else(|| invalid("Unknown PLY type"))?;
+                element.properties.push(Property::Scalar(name.to_string(), scalar));
+            }
+            ["end_header"] => break,
+            _ => {}
+        }
+    }
+
+    Ok((encoding.ok_or_else(|| invalid("Missing PLY format"))?, elements))
+}
+
+/// Reads property values one at a time, from whitespace separated tokens
+/// in ASCII files or fixed size fields in binary ones.
+struct PlyValues<R> {
+    reader: R,
+    encoding: Encoding,
+    line: Vec<u8>,
+    /// The remaining tokens of the current ASCII line, last one first.
+    tokens: Vec<String>,
+}
+
+impl<R: BufRead> PlyValues<R> {
+    fn next(&mut self, scalar: Scalar) -> io::Result<f64> {
+        if self.encoding == Encoding::Ascii {
+            while self.tokens.is_empty() {
+                self.line.clear();
+                if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                    return Err(invalid("Truncated PLY data"));
+                }
+                let text = std::str::from_utf8(&self.line).map_err(|_| invalid("Invalid PLY data"))?;
+                self.tokens = text.split_whitespace().rev().map(str::to_string).collect();
+            }
+            let token = self.tokens.pop().unwrap_or_default();
+            return token.parse().map_err(|_| invalid("Invalid PLY value"));
+        }
+
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..scalar.size()];
+        self.reader.read_exact(bytes)?;
+        if self.encoding == Encoding::LittleEndian {
+            bytes.reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => bytes[0] as i8 as f64,
+            Scalar::U8 => bytes[0] as f64,
+            Scalar::I16 => i16::from_be_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::U16 => u16::from_be_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::I32 => i32::from_be_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::U32 => u32::from_be_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::F32 => f32::from_be_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::F64 => f64::from_be_bytes(bytes.try_into().unwrap()),
+        })
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_mesh, MeshFormat};
+    use crate::error::ThumbnailError;
+
+    #[test]
+    fn test_read_formats() {
+        let obj = b"# a quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1/1 2/2 3/3 4/4\nf -4 -3 -2\n";
+        let mesh = read_mesh(Cursor::new(&obj[..]), MeshFormat::Obj, 100).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.triangles, [[0, 1, 2], [0, 2, 3], [0, 1, 2]]);
+
+        let mut ply = b"ply\nformat binary_big_endian 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+            property float z\nproperty uchar red\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n"
+            .to_vec();
+        for position in [[0f32, 0., 0.], [1., 0., 0.], [0., 1., 0.]] {
+            position.iter().for_each(|c| ply.extend(c.to_be_bytes()));
+            ply.push(255);
+        }
+        ply.push(3);
+        [2i32, 1, 0].iter().for_each(|i| ply.extend(i.to_be_bytes()));
+        let mesh = read_mesh(Cursor::new(&ply), MeshFormat::Ply, 100).unwrap();
+        assert_eq!(mesh.vertices[1], [1., 0., 0.]);
+        assert_eq!(mesh.triangles, [[2, 1, 0]]);
+
+        let stl = b"solid cube\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid\n";
+        let mesh = read_mesh(Cursor::new(&stl[..]), MeshFormat::Stl, 100).unwrap();
+        assert_eq!(mesh.triangles, [[0, 1, 2]]);
+
+        let mut binary = vec![0u8; 80];
+        binary.extend(2u32.to_le_bytes());
+        binary.extend([0u8; 100]);
+        assert_eq!(read_mesh(Cursor::new(&binary), MeshFormat::Stl, 2).unwrap().triangles.len(), 2);
+        assert!(matches!(
+            read_mesh(Cursor::new(&binary), MeshFormat::Stl, 1),
+            Err(ThumbnailError::LimitsExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_ply_element_counts() {
+        let empty = b"ply\nformat ascii 1.0\nelement junk 4000000000\nend_header\n";
+        assert!(matches!(
+            read_mesh(Cursor::new(&empty[..]), MeshFormat::Ply, 100),
+            Err(ThumbnailError::Io(_))
+        ));
+
+        let vertices = b"ply\nformat ascii 1.0\nelement vertex 301\nproperty float x\nend_header\n";
+        assert!(matches!(
+            read_mesh(Cursor::new(&vertices[..]), MeshFormat::Ply, 100),
+            Err(ThumbnailError::LimitsExceeded(_))
+        ));
+
+        let faces = b"ply\nformat ascii 1.0\nelement face 4000000000\nproperty list uchar int vertex_indices\nend_header\n";
+        assert!(matches!(
+            read_mesh(Cursor::new(&faces[..]), MeshFormat::Ply, 100),
+            Err(ThumbnailError::LimitsExceeded(_))
+        ));
+    }
+}
@@ -0,0 +1,57 @@
+use log::debug;
+use std::path::Path;
+
+/// Extensions whose MIME type, as named by the freedesktop shared-mime-info
+/// database that `.thumbnailer` files refer to, differs from `mime_guess`.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("blend", "application/x-blender"),
+    ("cbz", "application/vnd.comicbook+zip"),
+    ("cur", "image/x-win-bitmap"),
+    ("icns", "image/x-icns"),
+    ("kra", "application/x-krita"),
+    ("m4a", "audio/mp4"),
+    ("m4b", "audio/x-m4b"),
+    ("obj", "model/obj"),
+    ("ora", "image/openraster"),
+    ("otf", "font/otf"),
+    ("ply", "model/x-ply"),
+    ("psd", "image/vnd.adobe.photoshop"),
+    ("stl", "model/stl"),
+    ("woff", "font/woff"),
+    ("xcf", "image/x-xcf"),
+];
+
+/// Guesses the MIME type of `path` from its extension, preferring the
+/// freedesktop names listed in `OVERRIDES`.
+pub fn guess_mime_type(path: &Path) -> String {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let mime_type = extension
+        .as_deref()
+        .and_then(|e| OVERRIDES.iter().find(|(ext, _)| *ext == e))
+        .map(|(_, mime)| mime.to_string())
+        .unwrap_or_else(|| mime_guess::from_path(path).first_or_octet_stream().essence_str().to_string());
+
+    debug!("Detected MIME type for {:?} as {}", path, mime_type);
+    mime_type
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::guess_mime_type;
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(guess_mime_type(Path::new("book.M4B")), "audio/x-m4b");
+        assert_eq!(guess_mime_type(Path::new("song.m4a")), "audio/mp4");
+        assert_eq!(guess_mime_type(Path::new("issue.cbz")), "application/vnd.comicbook+zip");
+        assert_eq!(guess_mime_type(Path::new("poster.PSD")), "image/vnd.adobe.photoshop");
+        assert_eq!(guess_mime_type(Path::new("pointer.cur")), "image/x-win-bitmap");
+        assert_eq!(guess_mime_type(Path::new("photo.jpg")), "image/jpeg");
+        assert_eq!(guess_mime_type(Path::new("unknown")), "application/octet-stream");
+    }
+}
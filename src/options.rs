@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use crate::{
+    alpha::AlphaPolicy,
+    animation::FramePolicy,
+    generators::GeneratorRegistry,
+    health::CircuitBreaker,
+    limits::DecodeLimits,
+    orientation::{OrientationMode, DEFAULT_NON_ROTATING_THUMBNAILERS},
+    resize::ResizeOptions,
+    shape::ThumbnailShape,
+    tonemap::ToneMapOptions,
+    trust::TrustPolicy,
+};
+
+/// Settings that control how thumbnails are generated.
+///
+/// `ThumbnailOptions::default()` reproduces the behaviour of `generate_thumbnail`.
+#[derive(Debug, Clone)]
+pub struct ThumbnailOptions {
+    /// How thumbnailer definitions and executables are vetted before running them.
+    pub trust_policy: TrustPolicy,
+    /// Kill external thumbnailers that run longer than this.
+    pub timeout: Option<Duration>,
+    /// Suspend thumbnailers that keep crashing or timing out. While enabled,
+    /// crashes and timeouts do not produce fail markers.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Try every in-process generator before external thumbnailers, whatever
+    /// its registered priority.
+    pub prefer_builtin: bool,
+    /// In-process generators consulted alongside external thumbnailers.
+    pub generators: GeneratorRegistry,
+    /// When to apply the source's EXIF orientation to the thumbnail.
+    pub orientation: OrientationMode,
+    /// Executable names of external thumbnailers whose output ignores the
+    /// EXIF orientation, corrected under `OrientationMode::Auto`.
+    pub non_rotating_thumbnailers: Vec<String>,
+    /// Limits applied to every image decoded in-process.
+    pub decode_limits: DecodeLimits,
+    /// How in-process output is scaled; `None` uses `ResizeOptions::for_size`.
+    pub resize: Option<ResizeOptions>,
+    /// Convert in-process output with an embedded ICC profile to sRGB.
+    /// Requires the `icc` cargo feature; otherwise profiles are ignored.
+    pub color_management: bool,
+    /// Which frame of animated GIF, APNG and WebP files to thumbnail.
+    pub frame_policy: FramePolicy,
+    /// How 16-bit and HDR in-process output is reduced to 8 bits.
+    pub tone_map: ToneMapOptions,
+    /// The shape of thumbnails. Anything but `ThumbnailShape::Fit` is cached
+    /// separately from the spec-standard thumbnails.
+    pub shape: ThumbnailShape,
+    /// Whether thumbnails keep transparency or are composited onto a background.
+    pub alpha: AlphaPolicy,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        ThumbnailOptions {
+            trust_policy: TrustPolicy::default(),
+            timeout: None,
+            circuit_breaker: None,
+            prefer_builtin: false,
+            generators: GeneratorRegistry::default(),
+            orientation: OrientationMode::default(),
+            non_rotating_thumbnailers: DEFAULT_NON_ROTATING_THUMBNAILERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            decode_limits: DecodeLimits::default(),
+            resize: None,
+            color_management: true,
+            frame_policy: FramePolicy::default(),
+            tone_map: ToneMapOptions::default(),
+            shape: ThumbnailShape::default(),
+            alpha: AlphaPolicy::default(),
+        }
+    }
+}
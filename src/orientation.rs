@@ -0,0 +1,110 @@
+use image::{metadata::Orientation, ImageDecoder, ImageReader};
+use log::debug;
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::tiff::{find_jpeg_exif, tags, TiffReader};
+
+/// External thumbnailers known to copy pixels without applying the EXIF
+/// orientation of the source, typically because they extract embedded previews.
+pub const DEFAULT_NON_ROTATING_THUMBNAILERS: &[&str] = &["ufraw-batch", "dcraw", "exiftool"];
+
+/// Controls when the source's EXIF orientation is applied to the thumbnail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrientationMode {
+    /// Apply it to the output of in-process generators that return the
+    /// source's own pixels, and of external thumbnailers listed in
+    /// `non_rotating_thumbnailers`.
+    #[default]
+    Auto,
+    /// Apply it to every thumbnail, including all external thumbnailer output.
+    Always,
+    /// Never touch the orientation.
+    Never,
+}
+
+/// Reads the EXIF orientation of the file at `path`.
+///
+/// Formats the `image` crate understands (JPEG, TIFF, WebP, PNG, AVIF) are
+/// handled by its decoders; anything else starting with a TIFF header, such as
+/// most camera RAW files, is read directly from IFD0.
+pub fn read_orientation(path: &Path) -> Option<Orientation> {
+    let from_decoder = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .ok()
+        .and_then(|r| r.into_decoder().ok())
+        .and_then(|mut d| d.orientation().ok());
+    if from_decoder.is_some() {
+        debug!("Orientation of {:?} is {:?}", path, from_decoder);
+        return from_decoder;
+    }
+
+    let orientation = read_exif_orientation(path);
+    debug!("Orientation of {:?} from EXIF is {:?}", path, orientation);
+    orientation
+}
+
+/// Reads the orientation tag from IFD0 of the EXIF data of a JPEG file, or of
+/// a TIFF-structured file.
+pub fn read_exif_orientation(path: &Path) -> Option<Orientation> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let base = find_jpeg_exif(&mut reader).ok().flatten().unwrap_or(0);
+
+    let (mut tiff, first_ifd) = TiffReader::new(reader, base).ok()?;
+    let ifd0 = tiff.read_ifd(first_ifd).ok()?;
+    let value = tiff.value_u32(ifd0.get(tags::ORIENTATION)?)?;
+    Orientation::from_exif(u8::try_from(value).ok()?)
+}
+
+/// Returns true if the external thumbnailer `executable` is listed as one that
+/// does not apply orientation itself. Only the file name is compared.
+pub fn is_non_rotating(executable: &str, non_rotating: &[String]) -> bool {
+    let name = Path::new(executable)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(executable);
+    non_rotating.iter().any(|n| n == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::jpeg::JpegEncoder, metadata::Orientation, RgbImage};
+    use tempfile::tempdir;
+
+    use super::{is_non_rotating, read_exif_orientation};
+
+    #[test]
+    fn test_read_exif_orientation() {
+        // Big-endian TIFF with IFD0 holding Orientation = 6 (rotate 90° CW).
+        let mut tiff = b"MM\0*".to_vec();
+        tiff.extend_from_slice(&8u32.to_be_bytes());
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&[0, 6, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut main = Vec::new();
+        JpegEncoder::new(&mut main)
+            .encode_image(&RgbImage::new(8, 4))
+            .unwrap();
+        let mut jpeg = main[..2].to_vec();
+        jpeg.extend_from_slice(&[0xFF, 0xE1]);
+        jpeg.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&main[2..]);
+
+        let dir = tempdir().expect("Failed to create temporary directory");
+        let path = dir.path().join("rotated.jpg");
+        std::fs::write(&path, jpeg).unwrap();
+        assert_eq!(read_exif_orientation(&path), Some(Orientation::Rotate90));
+    }
+
+    #[test]
+    fn test_is_non_rotating() {
+        let list = vec!["ufraw-batch".to_string()];
+        assert!(is_non_rotating("/usr/bin/ufraw-batch", &list));
+        assert!(!is_non_rotating("/usr/bin/gdk-pixbuf-thumbnailer", &list));
+    }
+}
@@ -0,0 +1,315 @@
+//! Reader for the flattened composite and the embedded thumbnail of
+//! Photoshop PSD and PSB files. Layers are never rendered: Photoshop stores
+//! a merged copy of the image after them, unless "Maximize Compatibility"
+//! was turned off, in which case the composite is blank.
+
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{error::ThumbnailError, limits::DecodeLimits};
+
+/// Image resource ID of the JPEG thumbnail written by Photoshop 5 and later.
+const THUMBNAIL_RESOURCE: u16 = 1036;
+
+/// Thumbnail resources larger than this are not read.
+const MAX_THUMBNAIL_BYTES: u32 = 4 * 1024 * 1024;
+
+/// Color modes of the composite.
+const MODE_GRAYSCALE: u16 = 1;
+const MODE_RGB: u16 = 3;
+const MODE_CMYK: u16 = 4;
+
+/// A parsed PSD or PSB file header with the offsets of its sections.
+#[derive(Debug, Clone, Copy)]
+pub struct Psd {
+    /// 1 for PSD, 2 for the large document format PSB.
+    pub version: u16,
+    pub channels: u16,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per channel: 1, 8, 16 or 32.
+    pub depth: u16,
+    pub color_mode: u16,
+    resources: (u64, u64),
+    image_data: u64,
+}
+
+impl Psd {
+    /// Reads the file header and locates the image resources and the
+    /// composite image data.
+    pub fn open<R: Read + Seek>(reader: &mut R) -> io::Result<Psd> {
+        let mut header = [0u8; 26];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"8BPS" {
+            return Err(invalid("Not a PSD file"));
+        }
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        if version != 1 && version != 2 {
+            return Err(invalid("Unknown PSD version"));
+        }
+
+        let color_mode_len = read_u32(reader)? as u64;
+        let resources_start = 26 + 4 + color_mode_len + 4;
+        reader.seek(SeekFrom::Start(resources_start - 4))?;
+        let resources_len = read_u32(reader)? as u64;
+        reader.seek(SeekFrom::Start(resources_start + resources_len))?;
+        let layers_len = if version == 2 {
+            read_u64(reader)?
+        } else {
+            read_u32(reader)? as u64
+        };
+        let image_data = reader.stream_position()?.saturating_add(layers_len);
+
+        Ok(Psd {
+            version,
+            channels: u16::from_be_bytes([header[12], header[13]]),
+            height: u32::from_be_bytes([header[14], header[15], header[16], header[17]]),
+            width: u32::from_be_bytes([header[18], header[19], header[20], header[21]]),
+            depth: u16::from_be_bytes([header[22], header[23]]),
+            color_mode: u16::from_be_bytes([header[24], header[25]]),
+            resources: (resources_start, resources_len),
+            image_data,
+        })
+    }
+
+    /// Returns the JPEG data of the thumbnail image resource, if present.
+    /// Photoshop limits it to 160 pixels on the longer side.
+    pub fn thumbnail<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let (start, len) = self.resources;
+        let end = start.saturating_add(len);
+        let mut pos = start;
+        reader.seek(SeekFrom::Start(pos))?;
+
+        while pos + 12 <= end {
+            let mut signature = [0u8; 4];
+            reader.read_exact(&mut signature)?;
+            if &signature != b"8BIM" {
+                break;
+            }
+            let id = read_u16(reader)?;
+            // A Pascal string name, padded to an even length.
+            let name_len = read_u8(reader)? as u64;
+            let name_padded = (name_len + 1).next_multiple_of(2);
+            reader.seek(SeekFrom::Current(name_padded as i64 - 1))?;
+            let size = read_u32(reader)?;
+            let data_start = pos + 4 + 2 + name_padded + 4;
+
+            if id == THUMBNAIL_RESOURCE && size > 28 && size <= MAX_THUMBNAIL_BYTES {
+                let mut data = vec![0u8; size as usize];
+                reader.read_exact(&mut data)?;
+                // A 28 byte header precedes the JFIF data; format 1 is JPEG.
+                if u32::from_be_bytes([data[0], data[1], data[2], data[3]]) != 1 {
+                    return Ok(None);
+                }
+                return Ok(Some(data.split_off(28)));
+            }
+            pos = data_start + (size as u64).next_multiple_of(2);
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+        Ok(None)
+    }
+
+    /// Decodes the flattened composite of 8 and 16 bit grayscale, RGB and
+    /// CMYK files. An extra channel of RGB and grayscale files is used as
+    /// transparency.
+    pub fn composite<R: Read + Seek>(&self, reader: &mut R, limits: &DecodeLimits) -> Result<DynamicImage, ThumbnailError> {
+        let color_channels = match self.color_mode {
+            MODE_GRAYSCALE => 1,
+            MODE_RGB => 3,
+            MODE_CMYK => 4,
+            mode => return Err(ThumbnailError::Unsupported(format!("PSD color mode {}", mode))),
+        };
+        if self.depth != 8 && self.depth != 16 {
+            return Err(ThumbnailError::Unsupported(format!("{} bit PSD", self.depth)));
+        }
+        if self.channels < color_channels {
+            return Err(invalid("Too few channels for the PSD color mode").into());
+        }
+        let has_alpha = self.color_mode != MODE_CMYK && self.channels > color_channels;
+        let channels = color_channels + has_alpha as u16;
+        limits.check(self.width, self.height, channels as u64)?;
+
+        reader.seek(SeekFrom::Start(self.image_data))?;
+        let compression = read_u16(reader)?;
+        let bytes_per_sample = self.depth as usize / 8;
+        let row_len = self.width as usize * bytes_per_sample;
+        let rows = self.height as usize;
+        let plane_len = row_len * rows;
+
+        // Channels are stored one after the other; only the ones used are kept.
+        let mut planes = Vec::with_capacity(channels as usize);
+        match compression {
+            0 => {
+                for _ in 0..channels {
+                    let mut plane = vec![0u8; plane_len];
+                    reader.read_exact(&mut plane)?;
+                    planes.push(plane);
+                }
+            }
+            1 => {
+                // Byte counts of every row of every channel precede the rows.
+                let count_len = if self.version == 2 { 4 } else { 2 };
+                let mut counts = vec![0u8; self.channels as usize * rows * count_len];
+                reader.read_exact(&mut counts)?;
+                let counts: Vec<usize> = counts
+                    .chunks_exact(count_len)
+                    .map(|c| c.iter().fold(0usize, |n, b| (n << 8) | *b as usize))
+                    .collect();
+
+                let mut packed = Vec::new();
+                for channel in 0..channels as usize {
+                    let mut plane = Vec::with_capacity(plane_len);
+                    for count in &counts[channel * rows..(channel + 1) * rows] {
+                        // PackBits grows a row by at most one byte in 128.
+                        if *count > row_len + row_len / 64 + 2 {
+                            return Err(invalid("PSD row too long").into());
+                        }
+                        packed.resize(*count, 0);
+                        reader.read_exact(&mut packed)?;
+                        unpack_bits(&packed, row_len, &mut plane);
+                    }
+                    planes.push(plane);
+                }
+            }
+            other => {
+                return Err(ThumbnailError::Unsupported(format!("PSD compression {}", other)));
+            }
+        }
+
+        // Reduce 16 bit samples to their high byte.
+        let planes: Vec<Vec<u8>> = planes
+            .into_iter()
+            .map(|plane| plane.into_iter().step_by(bytes_per_sample).collect())
+            .collect();
+        let (width, height) = (self.width, self.height);
+        let pixel = |i: usize, c: usize| planes[c][i];
+
+        let image = match (self.color_mode, has_alpha) {
+            (MODE_GRAYSCALE, false) => DynamicImage::ImageLuma8(
+                GrayImage::from_raw(width, height, planes[0].clone()).ok_or_else(|| invalid("Short PSD plane"))?,
+            ),
+            (MODE_CMYK, _) => {
+                // PSD stores CMYK inverted, 255 meaning no ink.
+                let data = (0..planes[0].len())
+                    .flat_map(|i| {
+                        let k = pixel(i, 3) as u32;
+                        [0, 1, 2].map(|c| (pixel(i, c) as u32 * k / 255) as u8)
+                    })
+                    .collect();
+                DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, data).ok_or_else(|| invalid("Short PSD plane"))?)
+            }
+            (_, false) => {
+                let data = (0..planes[0].len()).flat_map(|i| [0, 1, 2].map(|c| pixel(i, c))).collect();
+                DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, data).ok_or_else(|| invalid("Short PSD plane"))?)
+            }
+            (_, true) => {
+                let alpha = channels as usize - 1;
+                let data = (0..planes[0].len())
+                    .flat_map(|i| {
+                        let a = pixel(i, alpha);
+                        let color = |c: usize| unmatte(pixel(i, c.min(color_channels as usize - 1)), a);
+                        [color(0), color(1), color(2), a]
+                    })
+                    .collect();
+                DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, data).ok_or_else(|| invalid("Short PSD plane"))?)
+            }
+        };
+        Ok(image)
+    }
+}
+
+/// The composite of transparent files is blended against white; this
+/// recovers the original color.
+fn unmatte(value: u8, alpha: u8) -> u8 {
+    if alpha == 0 {
+        return 0;
+    }
+    let value = value as i32 - (255 - alpha as i32);
+    (value * 255 / alpha as i32).clamp(0, 255) as u8
+}
+
+/// Decodes one PackBits compressed row of `row_len` bytes onto `out`. Missing
+/// bytes of a truncated row are filled with zeros.
+fn unpack_bits(packed: &[u8], row_len: usize, out: &mut Vec<u8>) {
+    let target = out.len() + row_len;
+    let mut i = 0;
+    while i < packed.len() && out.len() < target {
+        let n = packed[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(packed.len());
+            out.extend_from_slice(&packed[i..end]);
+            i = end;
+        } else if n != -128 {
+            if let Some(&byte) = packed.get(i) {
+                out.extend(std::iter::repeat_n(byte, (1 - n as isize) as usize));
+            }
+            i += 1;
+        }
+    }
+    out.resize(target, 0);
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{unpack_bits, Psd};
+    use crate::limits::DecodeLimits;
+
+    #[test]
+    fn test_rle_composite() {
+        // A 3x2 RGB image, each channel row packed as one run of three bytes.
+        let mut file = b"8BPS\0\x01\0\0\0\0\0\0\0\x03".to_vec();
+        file.extend(2u32.to_be_bytes());
+        file.extend(3u32.to_be_bytes());
+        file.extend([0, 8, 0, 3]);
+        file.extend([0u8; 12]);
+        file.extend(1u16.to_be_bytes());
+        file.extend([0, 2].repeat(6));
+        for value in [10u8, 20, 30, 40, 50, 60] {
+            file.extend([(-2i8) as u8, value]);
+        }
+
+        let mut cursor = Cursor::new(file);
+        let psd = Psd::open(&mut cursor).unwrap();
+        assert_eq!((psd.width, psd.height, psd.channels), (3, 2, 3));
+        assert!(psd.thumbnail(&mut cursor).unwrap().is_none());
+        let img = psd.composite(&mut cursor, &DecodeLimits::default()).unwrap().to_rgb8();
+        assert_eq!(img.get_pixel(2, 0).0, [10, 30, 50]);
+        assert_eq!(img.get_pixel(0, 1).0, [20, 40, 60]);
+
+        let mut row = Vec::new();
+        unpack_bits(&[2, 1, 2, 3, 0xFE, 9, 0x80], 7, &mut row);
+        assert_eq!(row, [1, 2, 3, 9, 9, 9, 0]);
+    }
+}
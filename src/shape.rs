@@ -0,0 +1,166 @@
+use image::{imageops, imageops::FilterType, DynamicImage, GenericImageView, RgbaImage};
+use log::debug;
+
+use crate::resize::{scale_to_fit, ResizeOptions};
+
+/// Longest side of the downscaled copy used to find the smart crop window.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// The shape of generated thumbnails.
+///
+/// Only `Fit` produces spec-standard thumbnails; the other shapes are cached
+/// in their own directories so they never replace the shared ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThumbnailShape {
+    /// Scale to fit within the square bound, keeping the aspect ratio.
+    #[default]
+    Fit,
+    /// Crop the central square, then scale.
+    CenterCrop,
+    /// Crop the square holding the most detail (edge energy, with a mild
+    /// preference for the centre), then scale.
+    SmartCrop,
+    /// Scale to fit, then pad with transparent pixels to a square.
+    Pad,
+}
+
+impl ThumbnailShape {
+    /// The name used for the cache directory of this shape, or `None` for the
+    /// standard fit thumbnails.
+    pub fn cache_name(&self) -> Option<&'static str> {
+        match self {
+            ThumbnailShape::Fit => None,
+            ThumbnailShape::CenterCrop => Some("square"),
+            ThumbnailShape::SmartCrop => Some("smart"),
+            ThumbnailShape::Pad => Some("pad"),
+        }
+    }
+}
+
+/// Brings `img` into `shape` and scales it to fit within `dimension`x`dimension`.
+/// Like `scale_to_fit`, small images are never upscaled.
+pub fn shape_to_fit(img: DynamicImage, shape: ThumbnailShape, dimension: u32, options: &ResizeOptions) -> DynamicImage {
+    match shape {
+        ThumbnailShape::Fit => scale_to_fit(img, dimension, options),
+        ThumbnailShape::CenterCrop | ThumbnailShape::SmartCrop => {
+            let (width, height) = img.dimensions();
+            let side = width.min(height);
+            let (x, y) = if shape == ThumbnailShape::SmartCrop {
+                smart_crop_offset(&img, side)
+            } else {
+                ((width - side) / 2, (height - side) / 2)
+            };
+            debug!("Cropping {}x{} image to {}x{} at ({}, {})", width, height, side, side, x, y);
+            scale_to_fit(img.crop_imm(x, y, side, side), dimension, options)
+        }
+        ThumbnailShape::Pad => pad_to_square(scale_to_fit(img, dimension, options)),
+    }
+}
+
+/// Centres `img` on a transparent square canvas.
+fn pad_to_square(img: DynamicImage) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let side = width.max(height);
+    if width == height {
+        return img;
+    }
+
+    let mut canvas = RgbaImage::new(side, side);
+    imageops::overlay(
+        &mut canvas,
+        &img.to_rgba8(),
+        ((side - width) / 2) as i64,
+        ((side - height) / 2) as i64,
+    );
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Finds the offset of the `side`x`side` window along the long axis of `img`
+/// with the most edge energy, measured on a downscaled copy.
+fn smart_crop_offset(img: &DynamicImage, side: u32) -> (u32, u32) {
+    let (width, height) = img.dimensions();
+    if width == height {
+        return (0, 0);
+    }
+
+    let scale = (ANALYSIS_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let small_width = ((width as f32 * scale).round() as u32).max(1);
+    let small_height = ((height as f32 * scale).round() as u32).max(1);
+    let small = img.resize_exact(small_width, small_height, FilterType::Triangle).to_luma8();
+
+    let horizontal = width > height;
+    let len = if horizontal { small_width } else { small_height } as usize;
+    let mut energy = vec![0.0f64; len];
+    for (x, y, p) in small.enumerate_pixels() {
+        let right = small.get_pixel((x + 1).min(small_width - 1), y)[0];
+        let below = small.get_pixel(x, (y + 1).min(small_height - 1))[0];
+        let gradient = p[0].abs_diff(right) as f64 + p[0].abs_diff(below) as f64;
+        energy[if horizontal { x } else { y } as usize] += gradient;
+    }
+
+    // Weigh lines towards the edges down a little, so featureless images and
+    // ties settle on the centre.
+    let centre = (len as f64 - 1.0) / 2.0;
+    for (i, e) in energy.iter_mut().enumerate() {
+        *e *= 1.0 - 0.3 * (i as f64 - centre).abs() / centre.max(1.0);
+    }
+
+    let window = ((side as f32 * scale).round() as usize).clamp(1, len);
+    let mut sum: f64 = energy[..window].iter().sum();
+    let (mut best, mut best_start) = (sum, 0);
+    for start in 1..=len - window {
+        sum += energy[start + window - 1] - energy[start - 1];
+        if sum > best {
+            best = sum;
+            best_start = start;
+        }
+    }
+
+    let offset = ((best_start as f32 / scale).round() as u32).min(width.max(height) - side);
+    if horizontal { (offset, 0) } else { (0, offset) }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    use super::{shape_to_fit, ThumbnailShape};
+    use crate::resize::{ResizeFilter, ResizeOptions};
+
+    const OPTIONS: ResizeOptions = ResizeOptions {
+        filter: ResizeFilter::Triangle,
+        gamma_correct: false,
+        sharpen: 0.0,
+    };
+
+    #[test]
+    fn test_square_shapes() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(400, 100, Rgba([255, 0, 0, 255])));
+
+        let fit = shape_to_fit(img.clone(), ThumbnailShape::Fit, 128, &OPTIONS);
+        assert_eq!(fit.dimensions(), (128, 32));
+
+        let crop = shape_to_fit(img.clone(), ThumbnailShape::CenterCrop, 128, &OPTIONS);
+        assert_eq!(crop.dimensions(), (100, 100));
+
+        let pad = shape_to_fit(img, ThumbnailShape::Pad, 128, &OPTIONS);
+        assert_eq!(pad.dimensions(), (128, 128));
+        assert_eq!(pad.get_pixel(64, 0)[3], 0);
+        assert_eq!(pad.get_pixel(64, 64), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_smart_crop_finds_detail() {
+        // A flat image with a checkerboard near its right edge.
+        let img = RgbaImage::from_fn(400, 100, |x, y| {
+            if x >= 300 && (x / 4 + y / 4) % 2 == 0 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let crop = shape_to_fit(DynamicImage::ImageRgba8(img), ThumbnailShape::SmartCrop, 128, &OPTIONS);
+        assert_eq!(crop.dimensions(), (100, 100));
+        assert!(crop.to_rgba8().pixels().filter(|p| p[0] == 0).count() > 4000);
+    }
+}
@@ -4,32 +4,55 @@ use std::{
     fs,
     fs::File,
     io,
+    os::unix::process::ExitStatusExt,
     path::{Path, PathBuf},
-    process::Command,
-    time::UNIX_EPOCH
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
+use image::{metadata::Orientation, DynamicImage, ImageReader};
 use ini::Ini;
-use mime_guess;
 use png::Decoder;
 use shell_words::split;
+use tempfile::NamedTempFile;
 use which::which;
 
 use crate::{
+    alpha::{apply_alpha_policy, AlphaPolicy},
+    color::convert_to_srgb,
     error::ThumbnailError,
     file::{
-        add_thumbnail_metadata, get_failed_thumbnail_output, get_file_uri, get_thumbnail_hash_output, write_failed_thumbnail
+        add_thumbnail_metadata, add_thumbnail_metadata_with_limits, get_failed_thumbnail_output, get_file_uri, get_shaped_thumbnail_output, write_failed_thumbnail,
+        write_out_thumbnail,
+        write_out_thumbnail_with_text,
     },
+    generators::{GenerateContext, Source, ThumbnailGenerator, EXTERNAL_PRIORITY},
     hash::compute_hash,
+    limits::DecodeLimits,
+    mime::guess_mime_type,
+    health::{FailureKind, HealthTracker},
+    options::ThumbnailOptions,
+    orientation::{is_non_rotating, read_orientation, OrientationMode},
+    resize::{scale_exact, ResizeOptions},
+    shape::{shape_to_fit, ThumbnailShape},
     sizes::ThumbnailSize,
+    tonemap::to_rgba8,
+    trust::{verify_definition, verify_executable, TrustError, TrustPolicy},
 };
 
+/// How many times the requested dimension crop shapes ask external
+/// thumbnailers for.
+const CROP_OVERSCAN: u32 = 4;
+
 /// Holds configuration parsed from a .thumbnailer file.
 #[derive(Debug)]
 struct ThumbnailerConfig {
     try_exec: Option<String>,
     exec_line: String,
     _mime_types: Vec<String>,
+    /// Location of the .thumbnailer file this config was read from.
+    path: PathBuf,
 }
 
 /// Checks whether the thumbnail file at `thumb_path` is up to date with respect
@@ -116,16 +139,25 @@ pub fn is_thumbnail_up_to_date(thumb_path: &Path, source_path: &Path) -> bool {
 
 /// Searches standard directories for a .thumbnailer file supporting the given MIME type.
 /// Looks in:
-///   - $HOME/.local/share/thumbnailers
+///   - $HOME/.local/share/thumbnailers (skipped under `TrustPolicy::SystemOnly`)
 ///   - $XDG_DATA_DIRS/thumbnailers
 ///   - /usr/share/thumbnailers
-fn find_thumbnailer(mime_type: &str) -> Result<Option<ThumbnailerConfig>, ThumbnailError> {
+///
+/// Definitions rejected by `policy` are passed over in favour of later ones;
+/// the first rejection is only returned as `ThumbnailError::Untrusted` when
+/// no trusted definition exists.
+fn find_thumbnailer(mime_type: &str, policy: TrustPolicy) -> Result<Option<ThumbnailerConfig>, ThumbnailError> {
     debug!("Searching for .thumbnailer supporting MIME type: {}", mime_type);
 
+    // Each entry records whether the directory belongs to the user.
     let mut dirs = Vec::new();
 
     if let Ok(home) = env::var("HOME") {
-        dirs.push(PathBuf::from(home).join(".local/share/thumbnailers"));
+        if policy == TrustPolicy::SystemOnly {
+            debug!("Skipping user thumbnailers, the trust policy only allows system ones");
+        } else {
+            dirs.push((PathBuf::from(home).join(".local/share/thumbnailers"), true));
+        }
     }
 
     if let Ok(xdg_data_dirs) = env::var("XDG_DATA_DIRS") {
@@ -137,13 +169,14 @@ fn find_thumbnailer(mime_type: &str) -> Result<Option<ThumbnailerConfig>, Thumbn
 
         // Print the directories
         for dir in &data_dirs {
-            dirs.push(dir.join("thumbnailers"));
+            dirs.push((dir.join("thumbnailers"), false));
         }
     }
 
-    dirs.push(PathBuf::from("/usr/share/thumbnailers"));
+    dirs.push((PathBuf::from("/usr/share/thumbnailers"), false));
 
-    for dir in dirs {
+    let mut rejection = None;
+    for (dir, user_dir) in dirs {
         debug!("Looking for thumbnailer files in {:?}", dir);
         if dir.is_dir() {
             for entry in fs::read_dir(&dir)? {
@@ -163,15 +196,20 @@ fn find_thumbnailer(mime_type: &str) -> Result<Option<ThumbnailerConfig>, Thumbn
                                 let try_exec = section.get("TryExec").map(|s| s.to_string());
                                 let exec_line = section
                                     .get("Exec")
-                                    .ok_or_else(|| {
-                                        io::Error::new(io::ErrorKind::Other, "Missing Exec key")
-                                    })?
+                                    .ok_or_else(|| io::Error::other("Missing Exec key"))?
                                     .to_string();
 
+                                if let Err(e) = verify_definition(policy, &path, user_dir) {
+                                    warn!("Passing over untrusted thumbnailer {:?}: {}", path, e);
+                                    rejection.get_or_insert(e);
+                                    continue;
+                                }
+
                                 let config = ThumbnailerConfig {
                                     try_exec,
                                     exec_line,
                                     _mime_types: mimes,
+                                    path,
                                 };
                                 return Ok(Some(config));
                             }
@@ -181,6 +219,9 @@ fn find_thumbnailer(mime_type: &str) -> Result<Option<ThumbnailerConfig>, Thumbn
             }
         }
     }
+    if let Some(e) = rejection {
+        return Err(e.into());
+    }
     debug!("No .thumbnailer found for MIME type: {}", mime_type);
     Ok(None)
 }
@@ -227,120 +268,309 @@ fn build_command_args(
     Ok(replaced)
 }
 
-/// Generates a thumbnail for the given file using the GNOME thumbnailer approach.
+/// Runs `command` to completion, killing it if it outlives `timeout`.
 ///
-/// This function:
-/// 1. Computes the file URI and MD5 hash.
-/// 2. Determines the cache output path using your helper (`get_thumbnail_hash_output`).
-/// 3. Checks for an existing cached thumbnail.
-/// 4. Detects the file’s MIME type and searches for an appropriate thumbnailer.
-/// 5. Substitutes tokens into the Exec command and executes the thumbnailer.
-/// 6. On failure, writes a fail marker using your helper (`get_failed_thumbnail_output`).
-pub fn generate_thumbnail(file: &Path, size: ThumbnailSize) -> Result<PathBuf, ThumbnailError> {
-    info!("Generating thumbnail for {:?} with size {:?}", file, size);
+/// Returns `None` if the process was killed because of the timeout.
+fn run_command(command: &mut Command, timeout: Option<Duration>) -> Result<Option<ExitStatus>, ThumbnailError> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(command.status()?));
+    };
 
-    // Canonicalize the file and create a file URI.
-    let abs_path = file.canonicalize()?;
-    let file_str = abs_path.to_str().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid file path")
-    })?;
-    let file_uri = get_file_uri(file)?;
+    let mut child = command.spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            warn!("Thumbnailer exceeded timeout of {:?}, killing it", timeout);
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
 
-    // Compute the MD5 hash from the file URI.
-    let hash = compute_hash(&file_uri);
+/// Classifies a failed exit status as a crash of the thumbnailer itself, as
+/// opposed to the thumbnailer cleanly rejecting the input file.
+///
+/// Death by signal and the shell's "cannot execute"/"not found" codes (126/127,
+/// also used when the dynamic loader fails) count as crashes.
+fn is_crash(status: &ExitStatus) -> bool {
+    status.signal().is_some() || matches!(status.code(), Some(126) | Some(127))
+}
 
-    // Check if the fail marker exists and is up to date
-    let fail_path = get_failed_thumbnail_output(&hash);
-    if fail_path.exists() && is_thumbnail_up_to_date(&fail_path, file) {
-        info!(
-            "A fail marker exists and is up-to-date, returning fail marker at {:?}",
-            fail_path
-        );
-        return Ok(fail_path);
+/// Creates a temporary file next to `thumb_path`, creating its directory if needed.
+///
+/// Keeping the temp file on the same filesystem lets it be persisted atomically.
+fn create_temp_thumbnail(thumb_path: &Path) -> Result<NamedTempFile, ThumbnailError> {
+    let thumb_dir = thumb_path
+        .parent()
+        .ok_or_else(|| io::Error::other("Thumbnail path has no parent directory"))?;
+    fs::create_dir_all(thumb_dir)?;
+
+    Ok(tempfile::Builder::new()
+        .prefix("thumb-")
+        .suffix(".png.tmp")
+        .tempfile_in(thumb_dir)?)
+}
+
+/// Writes an up-to-date fail marker for the source file at `abs_path`.
+fn write_fail_marker(hash: &str, abs_path: &Path) -> Result<(), ThumbnailError> {
+    let fail_marker = get_failed_thumbnail_output(hash);
+    if let Some(parent) = fail_marker.parent() {
+        fs::create_dir_all(parent)?;
     }
 
-    // Determine the expected output thumbnail path.
-    let thumb_path = get_thumbnail_hash_output(&hash, size);
+    write_failed_thumbnail(&fail_marker, abs_path)?;
+    add_thumbnail_metadata(&fail_marker, abs_path)
+}
 
-    // If the thumbnail already exists and is up to date, return it immediately.
-    if thumb_path.exists() && is_thumbnail_up_to_date(&thumb_path, file) {
-        info!(
-            "Cached thumbnail at {:?} is up-to-date, returning it",
-            thumb_path
-        );
-        return Ok(thumb_path);
+/// A failed attempt at producing a thumbnail.
+struct Failure {
+    error: ThumbnailError,
+    /// True if the failure is a property of the file itself, in which case a
+    /// fail marker is written when no other candidate succeeds.
+    permanent: bool,
+}
+
+impl From<ThumbnailError> for Failure {
+    fn from(error: ThumbnailError) -> Self {
+        Failure {
+            error,
+            permanent: false,
+        }
     }
+}
 
-    // Determine the file's MIME type.
-    let mime = mime_guess::from_path(file).first_or_octet_stream();
-    let mime_type = mime.essence_str();
-    debug!("Detected MIME type for {:?} as {}", file, mime_type);
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        ThumbnailError::from(error).into()
+    }
+}
 
-    // Look for a thumbnailer that supports this MIME type.
-    let config = match find_thumbnailer(mime_type)? {
-        Some(conf) => {
-            debug!("Using thumbnailer config: {:?}", conf);
-            conf
+/// Returns true if a generator error means the file cannot be thumbnailed,
+/// rather than the generator declining it.
+fn is_permanent_generator_error(error: &ThumbnailError) -> bool {
+    !matches!(
+        error,
+        ThumbnailError::Unsupported(_) | ThumbnailError::LimitsExceeded(_) | ThumbnailError::NoEmbeddedArt
+    )
+}
+
+/// Produces the thumbnail with an in-process generator and persists it to `thumb_path`.
+fn run_generator(
+    generator: &dyn ThumbnailGenerator,
+    abs_path: &Path,
+    mime_type: &str,
+    size: ThumbnailSize,
+    thumb_path: &Path,
+    options: &ThumbnailOptions,
+) -> Result<PathBuf, Failure> {
+    info!("Generating thumbnail for {:?} with the {} generator", abs_path, generator.name());
+
+    let mut ctx = GenerateContext::new(size, mime_type)
+        .with_limits(options.decode_limits)
+        .with_frame_policy(options.frame_policy);
+    let mut img = generator
+        .generate(Source::Path(abs_path), &mut ctx)
+        .map_err(|error| Failure {
+            permanent: is_permanent_generator_error(&error),
+            error,
+        })?;
+
+    if options.orientation != OrientationMode::Never && !ctx.is_oriented() {
+        let orientation = ctx
+            .orientation()
+            .or_else(|| generator.uses_source_orientation().then(|| read_orientation(abs_path)).flatten());
+        if let Some(orientation) = orientation {
+            img.apply_orientation(orientation);
         }
-        None => {
-            warn!("No thumbnailer found for MIME type {}", mime_type);
-            return Err(ThumbnailError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No thumbnailer found for this MIME type",
-            )));
+    }
+    let resize = options.resize.unwrap_or_else(|| ResizeOptions::for_size(size));
+    let img = shape_to_fit(img, options.shape, size.to_dimension(), &resize);
+    let mut img = DynamicImage::ImageRgba8(to_rgba8(img, &options.tone_map));
+
+    // Converting after scaling keeps the tone mapping and colour transform cheap.
+    if options.color_management {
+        if let Some(profile) = ctx.icc_profile() {
+            img = convert_to_srgb(img, profile);
         }
+    }
+    let img = apply_alpha_policy(img, options.alpha);
+
+    let named_temp = create_temp_thumbnail(thumb_path)?;
+    write_out_thumbnail_with_text(named_temp.path(), img, abs_path, ctx.text())?;
+    add_thumbnail_metadata_with_limits(named_temp.path(), abs_path, &options.decode_limits)?;
+
+    info!("{} generator succeeded; persisting thumbnail to {:?}", generator.name(), thumb_path);
+    named_temp.persist(thumb_path).map_err(ThumbnailError::from)?;
+    Ok(thumb_path.to_path_buf())
+}
+
+/// Rewrites the thumbnail at `thumb_path` with the EXIF orientation of the
+/// source applied, if it has one.
+fn apply_source_orientation(
+    thumb_path: &Path,
+    abs_path: &Path,
+    limits: &DecodeLimits,
+) -> Result<(), ThumbnailError> {
+    let orientation = match read_orientation(abs_path) {
+        Some(o) if o != Orientation::NoTransforms => o,
+        _ => return Ok(()),
     };
 
-    // If TryExec is specified, ensure that the executable exists.
-    if let Some(ref exec_name) = config.try_exec {
-        if which(exec_name).is_err() {
+    debug!("Applying {:?} to external thumbnailer output", orientation);
+    let mut reader = ImageReader::open(thumb_path)?.with_guessed_format()?;
+    reader.limits(limits.to_image_limits());
+    let mut img = reader.decode()?;
+    img.apply_orientation(orientation);
+    write_out_thumbnail(thumb_path, img, abs_path)
+}
+
+/// Rewrites the external thumbnailer output at `thumb_path` in the shape and
+/// with the alpha policy selected by `options`.
+fn apply_output_options(
+    thumb_path: &Path,
+    abs_path: &Path,
+    size: ThumbnailSize,
+    options: &ThumbnailOptions,
+) -> Result<(), ThumbnailError> {
+    debug!(
+        "Applying {:?} and {:?} to external thumbnailer output",
+        options.shape, options.alpha
+    );
+    let mut reader = ImageReader::open(thumb_path)?.with_guessed_format()?;
+    reader.limits(options.decode_limits.to_image_limits());
+    let img = reader.decode()?;
+    let resize = options.resize.unwrap_or_else(|| ResizeOptions::for_size(size));
+    let requested = external_dimension(size, options.shape);
+    let img = shape_external_output(img, options.shape, requested, size.to_dimension(), &resize);
+    write_out_thumbnail(thumb_path, apply_alpha_policy(img, options.alpha), abs_path)
+}
+
+/// The `%s` passed to external thumbnailers. Crop shapes ask for
+/// `CROP_OVERSCAN` times the dimension, so the short side of wide images
+/// still covers it after cropping.
+fn external_dimension(size: ThumbnailSize, shape: ThumbnailShape) -> u32 {
+    match shape {
+        ThumbnailShape::CenterCrop | ThumbnailShape::SmartCrop => size.to_dimension() * CROP_OVERSCAN,
+        ThumbnailShape::Fit | ThumbnailShape::Pad => size.to_dimension(),
+    }
+}
+
+/// Brings external thumbnailer output, bounded to `requested`, into `shape`.
+/// A crop that still falls short of `dimension` because the thumbnailer had
+/// scaled the image down is scaled back up; output that was small to begin
+/// with is left as is.
+fn shape_external_output(
+    img: DynamicImage,
+    shape: ThumbnailShape,
+    requested: u32,
+    dimension: u32,
+    resize: &ResizeOptions,
+) -> DynamicImage {
+    let bounded = img.width().max(img.height()) >= requested;
+    let img = shape_to_fit(img, shape, dimension, resize);
+    let cropped = matches!(shape, ThumbnailShape::CenterCrop | ThumbnailShape::SmartCrop);
+    if bounded && cropped && img.width() < dimension {
+        debug!("Scaling {}x{} crop of external output up to {}", img.width(), img.height(), dimension);
+        return scale_exact(img, dimension, dimension, resize);
+    }
+    img
+}
+
+/// Runs the external thumbnailer described by `config` and persists its output
+/// to `thumb_path`.
+fn run_external(
+    config: &ThumbnailerConfig,
+    abs_path: &Path,
+    file_uri: &str,
+    size: ThumbnailSize,
+    thumb_path: &Path,
+    options: &ThumbnailOptions,
+) -> Result<PathBuf, Failure> {
+    let file_str = abs_path.to_str().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid file path")
+    })?;
+
+    // Skip thumbnailers the circuit breaker has suspended.
+    let health = HealthTracker::global();
+    let thumbnailer_id = config.path.to_string_lossy().into_owned();
+    if options.circuit_breaker.is_some() {
+        if let Some(remaining) = health.suspended_for(&thumbnailer_id) {
             warn!(
-                "TryExec specified ({}) but could not be found on PATH.",
-                exec_name
+                "Thumbnailer {} is suspended for another {:?}",
+                thumbnailer_id, remaining
             );
-            return Err(ThumbnailError::Io(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Thumbnailer executable not found",
-            )));
+            return Err(ThumbnailError::ThumbnailerSuspended {
+                thumbnailer: thumbnailer_id,
+                remaining,
+            }
+            .into());
         }
     }
 
-    // Prepare a temporary file in the same directory as the final thumbnail.
-    // Using `tempfile_in` ensures that the temp file is on the same filesystem
-    // so that we can atomically persist (rename) it.
-    let thumb_dir = thumb_path.parent().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::Other, "Thumbnail path has no parent directory")
-    })?;
-    fs::create_dir_all(thumb_dir)?;
-
-    let named_temp = tempfile::Builder::new()
-        .prefix("thumb-")
-        .suffix(".png.tmp")
-        .tempfile_in(thumb_dir)?;
+    // If TryExec is specified, ensure that the executable exists.
+    if let Some(ref exec_name) = config.try_exec {
+        match which(exec_name) {
+            Ok(resolved) => {
+                verify_executable(options.trust_policy, &resolved).map_err(ThumbnailError::from)?
+            }
+            Err(_) => {
+                warn!(
+                    "TryExec specified ({}) but could not be found on PATH.",
+                    exec_name
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Thumbnailer executable not found",
+                )
+                .into());
+            }
+        }
+    }
 
+    // Prepare a temporary file in the same directory as the final thumbnail.
+    let named_temp = create_temp_thumbnail(thumb_path)?;
     let temp_path = named_temp.path().to_owned();
+    let thumb_dir = thumb_path
+        .parent()
+        .ok_or_else(|| io::Error::other("Thumbnail path has no parent directory"))?;
 
     // Build the command using the Exec line from the thumbnailer config.
-    let dimension = size.to_dimension();
-    let args = build_command_args(&config.exec_line, dimension, &file_uri, &file, &temp_path)?;
+    let dimension = external_dimension(size, options.shape);
+    let args = build_command_args(&config.exec_line, dimension, file_uri, abs_path, &temp_path)?;
 
     // The first token is the executable; the rest are arguments.
     let executable = args
-        .get(0)
+        .first()
         .cloned()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Empty command"))?;
+        .ok_or_else(|| io::Error::other("Empty command"))?;
     let cmd_args = &args[1..];
 
+    // Verify the binary that will actually be spawned, not just TryExec.
+    match which(&executable) {
+        Ok(resolved) => verify_executable(options.trust_policy, &resolved).map_err(ThumbnailError::from)?,
+        // An executable that cannot be resolved cannot be vetted either.
+        Err(_) if options.trust_policy != TrustPolicy::Disabled => {
+            warn!("Rejecting thumbnailer: {} could not be resolved on PATH", executable);
+            return Err(ThumbnailError::from(TrustError::Unresolved { command: executable }).into());
+        }
+        Err(_) => {}
+    }
+
     debug!("Executing thumbnailer: {:?} {:?}", executable, cmd_args);
 
     // Check if Bubblewrap ("bwrap") is available.
-    let status = if let Ok(bwrap_path) = which("bwrap") {
+    let mut command = if let Ok(bwrap_path) = which("bwrap") {
         debug!("Running thumbnail command under bubblewrap sandbox.");
         let mut command = Command::new(bwrap_path);
         // Minimal sandbox setup
-        command.args(&["--ro-bind", "/usr", "/usr"]);
-        command.args(&["--ro-bind-try", "/etc/ld.so.cache", "/etc/ld.so.cache"]);
-        command.args(&["--ro-bind-try", "/etc/alternatives", "/etc/alternatives"]);
+        command.args(["--ro-bind", "/usr", "/usr"]);
+        command.args(["--ro-bind-try", "/etc/ld.so.cache", "/etc/ld.so.cache"]);
+        command.args(["--ro-bind-try", "/etc/alternatives", "/etc/alternatives"]);
 
         let usrmerged_dirs = ["bin", "lib64", "lib", "sbin"];
         for dir in &usrmerged_dirs {
@@ -349,28 +579,28 @@ pub fn generate_thumbnail(file: &Path, size: ThumbnailSize) -> Result<PathBuf, T
                 if let Ok(meta) = fs::symlink_metadata(&absolute_dir) {
                     if meta.file_type().is_symlink() {
                         let symlink_target = format!("/usr/{}", dir);
-                        command.args(&["--symlink", &symlink_target, &absolute_dir]);
+                        command.args(["--symlink", &symlink_target, &absolute_dir]);
                     } else {
-                        command.args(&["--ro-bind", &absolute_dir, &absolute_dir]);
+                        command.args(["--ro-bind", &absolute_dir, &absolute_dir]);
                     }
                 }
             }
         }
 
-        command.args(&["--proc", "/proc"]);
-        command.args(&["--dev", "/dev"]);
-        command.args(&["--chdir", "/"]);
-        command.args(&["--setenv", "GIO_USE_VFS", "local"]);
-        command.args(&["--unshare-all", "--die-with-parent"]);
+        command.args(["--proc", "/proc"]);
+        command.args(["--dev", "/dev"]);
+        command.args(["--chdir", "/"]);
+        command.args(["--setenv", "GIO_USE_VFS", "local"]);
+        command.args(["--unshare-all", "--die-with-parent"]);
 
         // Bind the thumbnail output directory so our temporary file is visible.
-        let thumb_dir_str = thumb_dir.to_str().ok_or_else(|| {
-            io::Error::new(io::ErrorKind::Other, "Invalid thumb_dir path")
-        })?;
-        command.args(&["--bind", thumb_dir_str, thumb_dir_str]);
+        let thumb_dir_str = thumb_dir
+            .to_str()
+            .ok_or_else(|| io::Error::other("Invalid thumb_dir path"))?;
+        command.args(["--bind", thumb_dir_str, thumb_dir_str]);
 
         // **Bind the source file** so that the sandboxed process can access it.
-        command.args(&["--ro-bind", file_str, file_str]);
+        command.args(["--ro-bind", file_str, file_str]);
 
         // Append the external command.
         command.arg("--");
@@ -378,39 +608,199 @@ pub fn generate_thumbnail(file: &Path, size: ThumbnailSize) -> Result<PathBuf, T
         command.args(cmd_args);
 
         debug!("Final bubblewrap command: {:?}", command);
-        command.status()?
+        command
     } else {
         debug!("Running thumbnail command directly (no bwrap).");
-        Command::new(&executable).args(cmd_args).status()?
+        let mut command = Command::new(&executable);
+        command.args(cmd_args);
+        command
+    };
+
+    let status = match run_command(&mut command, options.timeout)? {
+        Some(status) => status,
+        None => {
+            drop(named_temp);
+            health.record_failure(&thumbnailer_id, FailureKind::TimedOut, options.circuit_breaker.as_ref());
+            return Err(ThumbnailError::ThumbnailerTimedOut(thumbnailer_id).into());
+        }
     };
 
     if status.success() {
-        add_thumbnail_metadata(&temp_path, &abs_path)?;
+        health.record_success(&thumbnailer_id);
+
+        let rotate = match options.orientation {
+            OrientationMode::Always => true,
+            OrientationMode::Never => false,
+            OrientationMode::Auto => is_non_rotating(&executable, &options.non_rotating_thumbnailers),
+        };
+        if rotate {
+            apply_source_orientation(&temp_path, abs_path, &options.decode_limits)?;
+        }
+        if options.shape != ThumbnailShape::Fit || options.alpha != AlphaPolicy::Preserve {
+            apply_output_options(&temp_path, abs_path, size, options)?;
+        }
+        add_thumbnail_metadata_with_limits(&temp_path, abs_path, &options.decode_limits)?;
 
-        info!("Thumbnail command succeeded; persisting thumbnail to {:?}", thumb_path);        
-        named_temp.persist(&thumb_path)?;
-        Ok(thumb_path)
+        info!("Thumbnail command succeeded; persisting thumbnail to {:?}", thumb_path);
+        named_temp.persist(thumb_path).map_err(ThumbnailError::from)?;
+        Ok(thumb_path.to_path_buf())
     } else {
-        warn!(
-            "Thumbnail command failed with status: {:?}. Generating fail marker.",
-            status.code()
-        );
         // Clean up temp file
         drop(named_temp);
 
-        // Write fail marker
-        let fail_marker = get_failed_thumbnail_output(&hash);
-        if let Some(parent) = fail_marker.parent() {
-            fs::create_dir_all(parent)?;
+        if is_crash(&status) {
+            health.record_failure(&thumbnailer_id, FailureKind::Crashed, options.circuit_breaker.as_ref());
+            warn!("Thumbnailer {} crashed with status {:?}", thumbnailer_id, status);
+
+            // A crashing thumbnailer says nothing about the file, so with the
+            // circuit breaker enabled no permanent fail marker is written.
+            return Err(Failure {
+                error: ThumbnailError::ThumbnailerCrashed(thumbnailer_id),
+                permanent: options.circuit_breaker.is_none(),
+            });
+        }
+
+        warn!("Thumbnail command failed with status: {:?}", status.code());
+        Err(Failure {
+            error: ThumbnailError::Io(std::io::Error::other("Thumbnailer process failed")),
+            permanent: true,
+        })
+    }
+}
+
+/// Generates a thumbnail for the given file using the GNOME thumbnailer approach.
+///
+/// This function:
+/// 1. Computes the file URI and MD5 hash.
+/// 2. Determines the cache output path using your helper (`get_thumbnail_hash_output`).
+/// 3. Checks for an existing cached thumbnail.
+/// 4. Detects the file’s MIME type and searches for an appropriate thumbnailer.
+/// 5. Substitutes tokens into the Exec command and executes the thumbnailer.
+/// 6. On failure, writes a fail marker using your helper (`get_failed_thumbnail_output`).
+pub fn generate_thumbnail(file: &Path, size: ThumbnailSize) -> Result<PathBuf, ThumbnailError> {
+    generate_thumbnail_with_options(file, size, &ThumbnailOptions::default())
+}
+
+/// Same as [`generate_thumbnail`], but with explicit [`ThumbnailOptions`].
+///
+/// Candidates are tried in order until one succeeds:
+/// 1. In-process generators registered above `EXTERNAL_PRIORITY` (all of
+///    them if `prefer_builtin` is set).
+/// 2. The matching external `.thumbnailer`, if any.
+/// 3. The remaining in-process generators, such as the built-in `image` decoder.
+///
+/// With a trust policy other than `TrustPolicy::Disabled`, the matching
+/// `.thumbnailer` file and the executables it names are verified before
+/// anything is run, and a rejection is returned as `ThumbnailError::Untrusted`.
+///
+/// A fail marker is only written when a candidate failed because of the file
+/// itself, not because a thumbnailer was suspended, untrusted or declined it.
+pub fn generate_thumbnail_with_options(
+    file: &Path,
+    size: ThumbnailSize,
+    options: &ThumbnailOptions,
+) -> Result<PathBuf, ThumbnailError> {
+    info!("Generating thumbnail for {:?} with size {:?}", file, size);
+
+    // Canonicalize the file and create a file URI.
+    let abs_path = file.canonicalize()?;
+    let file_uri = get_file_uri(file)?;
+
+    // Compute the MD5 hash from the file URI.
+    let hash = compute_hash(&file_uri);
+
+    // Check if the fail marker exists and is up to date
+    let fail_path = get_failed_thumbnail_output(&hash);
+    if fail_path.exists() && is_thumbnail_up_to_date(&fail_path, file) {
+        info!(
+            "A fail marker exists and is up-to-date, returning fail marker at {:?}",
+            fail_path
+        );
+        return Ok(fail_path);
+    }
+
+    // Determine the expected output thumbnail path.
+    let thumb_path = get_shaped_thumbnail_output(&hash, size, options.shape, options.alpha);
+
+    // If the thumbnail already exists and is up to date, return it immediately.
+    if thumb_path.exists() && is_thumbnail_up_to_date(&thumb_path, file) {
+        info!(
+            "Cached thumbnail at {:?} is up-to-date, returning it",
+            thumb_path
+        );
+        return Ok(thumb_path);
+    }
+
+    // Determine the file's MIME type.
+    let mime_type = guess_mime_type(file);
+    let mime_type = mime_type.as_str();
+
+    let (preferred, fallback): (Vec<_>, Vec<_>) = options
+        .generators
+        .lookup(mime_type)
+        .into_iter()
+        .partition(|(priority, _)| options.prefer_builtin || *priority > EXTERNAL_PRIORITY);
+
+    let mut failures = Vec::new();
+
+    for (_, generator) in &preferred {
+        match run_generator(generator.as_ref(), &abs_path, mime_type, size, &thumb_path, options) {
+            Ok(path) => return Ok(path),
+            Err(failure) => {
+                warn!("{} generator failed: {}", generator.name(), failure.error);
+                failures.push(failure);
+            }
+        }
+    }
+
+    // Look for a thumbnailer that supports this MIME type.
+    let config = match find_thumbnailer(mime_type, options.trust_policy) {
+        Err(ThumbnailError::Untrusted(e)) => {
+            warn!("No trusted thumbnailer for MIME type {}: {}", mime_type, e);
+            failures.push(ThumbnailError::Untrusted(e).into());
+            None
+        }
+        found => found?,
+    };
+    match config {
+        Some(config) => {
+            debug!("Using thumbnailer config: {:?}", config);
+            match run_external(&config, &abs_path, &file_uri, size, &thumb_path, options) {
+                Ok(path) => return Ok(path),
+                Err(failure) => failures.push(failure),
+            }
+        }
+        None => debug!("No external thumbnailer found for MIME type {}", mime_type),
+    }
+
+    for (_, generator) in &fallback {
+        match run_generator(generator.as_ref(), &abs_path, mime_type, size, &thumb_path, options) {
+            Ok(path) => return Ok(path),
+            Err(failure) => {
+                warn!("{} generator failed: {}", generator.name(), failure.error);
+                failures.push(failure);
+            }
         }
-        
-        write_failed_thumbnail(&fail_marker, &fail_path)?;
-        add_thumbnail_metadata(&fail_path, &abs_path)?;
+    }
+
+    if failures.iter().any(|failure| failure.permanent) {
+        warn!("All thumbnailers failed for {:?}. Generating fail marker.", abs_path);
+        write_fail_marker(&hash, &abs_path)?;
+    }
 
-        Err(ThumbnailError::Io(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Thumbnailer process failed",
-        )))
+    // Report the most relevant failure: a permanent one if there is any.
+    let failure = match failures.iter().rposition(|failure| failure.permanent) {
+        Some(index) => Some(failures.swap_remove(index)),
+        None => failures.pop(),
+    };
+    match failure {
+        Some(failure) => Err(failure.error),
+        None => {
+            warn!("No thumbnailer found for MIME type {}", mime_type);
+            Err(ThumbnailError::Io(std::io::Error::other(
+                "No thumbnailer found for this MIME type",
+            )))
+        }
     }
 }
 
@@ -422,9 +812,9 @@ mod tests {
     use temp_env::with_var;
     
     use crate::file::{get_failed_thumbnail_output, get_file_uri};
-    use crate::generate_thumbnail;
+    use crate::{generate_thumbnail, generate_thumbnail_with_options};
     use crate::hash::compute_hash;
-    use crate::ThumbnailSize;
+    use crate::{ThumbnailOptions, ThumbnailSize};
 
     #[test]
     #[serial] // Ensure this test runs in isolation.
@@ -478,4 +868,192 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    #[serial]
+    fn test_generate_thumbnail_builtin() {
+        let temp_dir = tempdir().expect("Failed to create temporary directory for cache");
+        let source = temp_dir.path().join("wide.png");
+        image::RgbImage::from_pixel(300, 150, image::Rgb([200, 40, 40]))
+            .save(&source)
+            .expect("Failed to write source image");
+
+        with_var("XDG_CACHE_HOME", Some(temp_dir.path()), || {
+            let options = ThumbnailOptions {
+                prefer_builtin: true,
+                ..Default::default()
+            };
+            let thumb_path = generate_thumbnail_with_options(&source, ThumbnailSize::Normal, &options)
+                .expect("Built-in thumbnail generation failed");
+
+            let thumb = image::open(&thumb_path).expect("Failed to open thumbnail");
+            assert_eq!((thumb.width(), thumb.height()), (128, 64));
+            assert!(super::is_thumbnail_up_to_date(&thumb_path, &source));
+        });
+    }
+
+    #[test]
+    fn test_shape_wide_external_output() {
+        use super::{external_dimension, shape_external_output};
+        use crate::{ResizeOptions, ThumbnailShape};
+
+        let resize = ResizeOptions::for_size(ThumbnailSize::Normal);
+        let shape = ThumbnailShape::CenterCrop;
+        let requested = external_dimension(ThumbnailSize::Normal, shape);
+        assert_eq!(requested, 512);
+
+        // A 4000x1000 source bounded to the overscanned size covers the crop.
+        let wide = image::DynamicImage::new_rgb8(512, 128);
+        let img = shape_external_output(wide, shape, requested, 128, &resize);
+        assert_eq!((img.width(), img.height()), (128, 128));
+
+        // An even wider one falls short after cropping and is scaled back up.
+        let wider = image::DynamicImage::new_rgb8(512, 32);
+        let img = shape_external_output(wider, shape, requested, 128, &resize);
+        assert_eq!((img.width(), img.height()), (128, 128));
+
+        // Output that was small to begin with is not upscaled.
+        let small = image::DynamicImage::new_rgb8(40, 20);
+        let img = shape_external_output(small, shape, requested, 128, &resize);
+        assert_eq!((img.width(), img.height()), (20, 20));
+    }
+
+    #[test]
+    #[serial]
+    fn test_generate_thumbnail_custom_generator() {
+        use std::sync::Arc;
+        use crate::error::ThumbnailError;
+        use crate::generators::{GenerateContext, GeneratorRegistry, Source, ThumbnailGenerator};
+
+        struct Solid;
+
+        impl ThumbnailGenerator for Solid {
+            fn name(&self) -> &str {
+                "solid"
+            }
+
+            fn generate(&self, _: Source<'_>, ctx: &mut GenerateContext) -> Result<image::DynamicImage, ThumbnailError> {
+                ctx.add_text("Thumb::X-Test", "solid");
+                Ok(image::DynamicImage::new_rgba8(10, 20))
+            }
+        }
+
+        let temp_dir = tempdir().expect("Failed to create temporary directory for cache");
+        let source = temp_dir.path().join("custom.png");
+        std::fs::write(&source, b"not really a png").expect("Failed to write source file");
+
+        with_var("XDG_CACHE_HOME", Some(temp_dir.path()), || {
+            let mut generators = GeneratorRegistry::default();
+            generators.register("image/png", 10, Arc::new(Solid));
+            let options = ThumbnailOptions {
+                generators,
+                ..Default::default()
+            };
+            let thumb_path = generate_thumbnail_with_options(&source, ThumbnailSize::Normal, &options)
+                .expect("Custom generator failed");
+
+            let thumb = image::open(&thumb_path).expect("Failed to open thumbnail");
+            assert_eq!((thumb.width(), thumb.height()), (10, 20));
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_find_thumbnailer_skips_untrusted_definitions() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+        use temp_env::with_vars;
+        use super::find_thumbnailer;
+        use crate::error::ThumbnailError;
+        use crate::TrustPolicy;
+
+        const MIME_TYPE: &str = "application/x-thumbnailify-trust-test";
+
+        let temp_dir = tempdir().expect("Failed to create temporary directory");
+        let user_dir = temp_dir.path().join("home/.local/share/thumbnailers");
+        let system_dir = temp_dir.path().join("data/thumbnailers");
+        let mut definitions = Vec::new();
+        for dir in [&user_dir, &system_dir] {
+            fs::create_dir_all(dir).unwrap();
+            let path = dir.join("test.thumbnailer");
+            fs::write(&path, format!("[Thumbnailer Entry]\nExec=true %i %o\nMimeType={};\n", MIME_TYPE)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            definitions.push(path);
+        }
+        let (user, system) = (&definitions[0], &definitions[1]);
+
+        let home = temp_dir.path().join("home");
+        let data = temp_dir.path().join("data");
+        with_vars([("HOME", Some(&home)), ("XDG_DATA_DIRS", Some(&data))], || {
+            let find = |policy| find_thumbnailer(MIME_TYPE, policy).map(|config| config.map(|c| c.path));
+
+            assert_eq!(find(TrustPolicy::Disabled).unwrap().as_ref(), Some(user));
+            assert_eq!(find(TrustPolicy::Verify).unwrap().as_ref(), Some(user));
+            // A user definition cannot shadow the system one.
+            assert_eq!(find(TrustPolicy::SystemOnly).unwrap().as_ref(), Some(system));
+
+            // Rejected definitions are passed over, and only reported when
+            // nothing trusted is left.
+            fs::set_permissions(user, fs::Permissions::from_mode(0o666)).unwrap();
+            assert_eq!(find(TrustPolicy::Verify).unwrap().as_ref(), Some(system));
+            fs::set_permissions(system, fs::Permissions::from_mode(0o666)).unwrap();
+            assert!(matches!(find(TrustPolicy::Verify), Err(ThumbnailError::Untrusted(_))));
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_source_orientation_only_applies_to_image_generators() {
+        use std::sync::Arc;
+        use crate::error::ThumbnailError;
+        use crate::generators::{GenerateContext, GeneratorRegistry, Source, ThumbnailGenerator};
+
+        struct Page {
+            image: bool,
+        }
+
+        impl ThumbnailGenerator for Page {
+            fn name(&self) -> &str {
+                "page"
+            }
+
+            fn generate(&self, _: Source<'_>, _: &mut GenerateContext) -> Result<image::DynamicImage, ThumbnailError> {
+                Ok(image::DynamicImage::new_rgba8(10, 20))
+            }
+
+            fn uses_source_orientation(&self) -> bool {
+                self.image
+            }
+        }
+
+        // A text file that happens to look like a TIFF with Orientation = 6.
+        let mut tiff = b"MM\0*".to_vec();
+        tiff.extend_from_slice(&8u32.to_be_bytes());
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&[0, 6, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+
+        let temp_dir = tempdir().expect("Failed to create temporary directory for cache");
+        let source = temp_dir.path().join("notes.txt");
+        std::fs::write(&source, tiff).expect("Failed to write source file");
+
+        for (image, expected) in [(false, (10, 20)), (true, (20, 10))] {
+            let cache = tempdir().expect("Failed to create temporary directory for cache");
+            with_var("XDG_CACHE_HOME", Some(cache.path()), || {
+                let mut generators = GeneratorRegistry::new();
+                generators.register("text/plain", 10, Arc::new(Page { image }));
+                let options = ThumbnailOptions {
+                    generators,
+                    ..Default::default()
+                };
+                let thumb_path = generate_thumbnail_with_options(&source, ThumbnailSize::Normal, &options)
+                    .expect("Custom generator failed");
+
+                let thumb = image::open(&thumb_path).expect("Failed to open thumbnail");
+                assert_eq!((thumb.width(), thumb.height()), expected);
+            });
+        }
+    }
 }
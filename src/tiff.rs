@@ -0,0 +1,235 @@
+//! Minimal reader for TIFF-structured metadata: EXIF blocks embedded in JPEG
+//! files, TIFF images and the TIFF-based camera RAW formats.
+
+use log::debug;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Tag numbers used by this crate.
+pub mod tags {
+    pub const IMAGE_WIDTH: u16 = 0x0100;
+    pub const IMAGE_LENGTH: u16 = 0x0101;
+    pub const COMPRESSION: u16 = 0x0103;
+    pub const STRIP_OFFSETS: u16 = 0x0111;
+    pub const ORIENTATION: u16 = 0x0112;
+    pub const STRIP_BYTE_COUNTS: u16 = 0x0117;
+    pub const SUB_IFDS: u16 = 0x014A;
+    pub const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+    pub const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+    pub const EXIF_IFD: u16 = 0x8769;
+    pub const MAKER_NOTE: u16 = 0x927C;
+    pub const PIXEL_X_DIMENSION: u16 = 0xA002;
+    pub const PIXEL_Y_DIMENSION: u16 = 0xA003;
+}
+
+/// Upper bound on entries per IFD, to reject corrupt or hostile files early.
+const MAX_IFD_ENTRIES: u16 = 1024;
+
+/// Upper bound on the number of IFDs followed in a chain.
+const MAX_IFD_CHAIN: usize = 16;
+
+/// A single IFD entry. Values of up to four bytes are stored inline.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// The raw value/offset field, still in file byte order.
+    raw: [u8; 4],
+}
+
+/// An image file directory.
+#[derive(Debug, Clone, Default)]
+pub struct Ifd {
+    pub entries: Vec<Entry>,
+    /// Offset of the next IFD in the chain, or 0.
+    pub next: u32,
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+}
+
+/// Reads TIFF structures from a stream. All offsets are relative to `base`,
+/// the position of the TIFF header in the stream.
+pub struct TiffReader<R> {
+    reader: R,
+    base: u64,
+    little_endian: bool,
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    /// Parses the TIFF header at `base` and returns the reader together with the
+    /// offset of the first IFD.
+    pub fn new(mut reader: R, base: u64) -> io::Result<(Self, u32)> {
+        reader.seek(SeekFrom::Start(base))?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+
+        let little_endian = match &header[0..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => return Err(invalid("Not a TIFF header")),
+        };
+        let tiff = TiffReader {
+            reader,
+            base,
+            little_endian,
+        };
+
+        // 42 for TIFF; RAW variants use other magic numbers (ORF: 0x4F52/0x5352,
+        // RW2: 0x55), so the value is not checked.
+        let first_ifd = tiff.u32_from(&header[4..8]);
+        Ok((tiff, first_ifd))
+    }
+
+    /// Returns a reader with the same byte order whose offsets are relative to
+    /// `base`, for structures such as maker notes that carry no TIFF header.
+    pub fn rebase(self, base: u64) -> Self {
+        TiffReader { base, ..self }
+    }
+
+    /// Returns the position of the TIFF header (or rebased origin) in the stream.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn u16_from(&self, bytes: &[u8]) -> u16 {
+        let b = [bytes[0], bytes[1]];
+        if self.little_endian { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) }
+    }
+
+    pub fn u32_from(&self, bytes: &[u8]) -> u32 {
+        let b = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    }
+
+    /// Reads the IFD at `offset`.
+    pub fn read_ifd(&mut self, offset: u32) -> io::Result<Ifd> {
+        self.reader.seek(SeekFrom::Start(self.base + offset as u64))?;
+        let mut buf = [0u8; 12];
+        self.reader.read_exact(&mut buf[..2])?;
+        let count = self.u16_from(&buf[..2]);
+        if count > MAX_IFD_ENTRIES {
+            return Err(invalid("Too many IFD entries"));
+        }
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            self.reader.read_exact(&mut buf)?;
+            entries.push(Entry {
+                tag: self.u16_from(&buf[0..2]),
+                field_type: self.u16_from(&buf[2..4]),
+                count: self.u32_from(&buf[4..8]),
+                raw: [buf[8], buf[9], buf[10], buf[11]],
+            });
+        }
+
+        self.reader.read_exact(&mut buf[..4])?;
+        let next = self.u32_from(&buf[..4]);
+        Ok(Ifd { entries, next })
+    }
+
+    /// Reads the chain of IFDs starting at `offset` (IFD0, IFD1, ...).
+    pub fn read_ifd_chain(&mut self, mut offset: u32) -> io::Result<Vec<Ifd>> {
+        let mut ifds = Vec::new();
+        while offset != 0 && ifds.len() < MAX_IFD_CHAIN {
+            let ifd = self.read_ifd(offset)?;
+            offset = ifd.next;
+            ifds.push(ifd);
+        }
+        Ok(ifds)
+    }
+
+    /// Returns the first value of a SHORT or LONG entry.
+    pub fn value_u32(&self, entry: &Entry) -> Option<u32> {
+        match entry.field_type {
+            3 | 8 => Some(self.u16_from(&entry.raw[..2]) as u32),
+            4 | 9 | 13 => Some(self.u32_from(&entry.raw)),
+            _ => None,
+        }
+    }
+
+    /// Returns the offset of the values of an entry too large to be stored inline.
+    pub fn value_offset(&self, entry: &Entry) -> u32 {
+        self.u32_from(&entry.raw)
+    }
+
+    /// Returns all values of a SHORT or LONG entry, following the offset for
+    /// values that do not fit inline.
+    pub fn values_u32(&mut self, entry: &Entry) -> io::Result<Vec<u32>> {
+        let width = match entry.field_type {
+            3 | 8 => 2,
+            4 | 9 | 13 => 4,
+            _ => return Ok(Vec::new()),
+        };
+        let count = entry.count.min(MAX_IFD_ENTRIES as u32) as usize;
+        let len = width * count;
+
+        let data = if len <= 4 {
+            entry.raw[..len].to_vec()
+        } else {
+            let offset = self.u32_from(&entry.raw);
+            self.read_at(offset as u64, len)?
+        };
+
+        Ok(data
+            .chunks_exact(width)
+            .map(|c| if width == 2 { self.u16_from(c) as u32 } else { self.u32_from(c) })
+            .collect())
+    }
+
+    /// Reads `len` bytes at `offset`, relative to the TIFF header.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.reader.seek(SeekFrom::Start(self.base + offset))?;
+        let mut data = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated TIFF data"));
+        }
+        Ok(data)
+    }
+}
+
+/// Scans the markers of a JPEG stream and returns the position of the TIFF
+/// header inside its EXIF APP1 segment, if there is one.
+pub fn find_jpeg_exif<R: Read + Seek>(reader: &mut R) -> io::Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut marker = [0u8; 4];
+    reader.read_exact(&mut marker[..2])?;
+    if marker[..2] != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+
+    loop {
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Ok(None);
+        }
+        // Start of scan or end of image: no more metadata segments follow.
+        if marker[1] == 0xDA || marker[1] == 0xD9 {
+            return Ok(None);
+        }
+
+        let len = u16::from_be_bytes([marker[2], marker[3]]) as u64;
+        if len < 2 {
+            return Ok(None);
+        }
+        let payload = reader.stream_position()?;
+
+        if marker[1] == 0xE1 && len >= 8 {
+            let mut ident = [0u8; 6];
+            reader.read_exact(&mut ident)?;
+            if &ident == b"Exif\0\0" {
+                debug!("Found EXIF APP1 segment at offset {}", payload);
+                return Ok(Some(payload + 6));
+            }
+        }
+        reader.seek(SeekFrom::Start(payload + len - 2))?;
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
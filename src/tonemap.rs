@@ -0,0 +1,165 @@
+use image::{ColorType, DynamicImage, Rgba, RgbaImage};
+use log::debug;
+
+/// Middle grey that the log-average luminance is mapped to when normalising
+/// exposure.
+const KEY_VALUE: f32 = 0.18;
+
+/// 4x4 Bayer matrix used for ordered dithering.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The curve used to compress linear HDR values into the displayable range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    /// Reinhard's `L / (1 + L)` on luminance; preserves hue, soft highlights.
+    Reinhard,
+    /// The fitted ACES filmic curve, applied per channel; more contrast.
+    #[default]
+    Aces,
+}
+
+/// Controls how 16-bit and floating point images are reduced to the 8 bits
+/// per channel stored in thumbnails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapOptions {
+    /// Tone-mapping curve for floating point (linear, possibly HDR) images.
+    pub operator: ToneMapOperator,
+    /// Scale floating point images so their log-average luminance becomes
+    /// middle grey, which rescues scenes stored with arbitrary exposure.
+    pub normalize_exposure: bool,
+    /// Apply ordered dithering when dropping to 8 bits, avoiding banding in
+    /// smooth gradients.
+    pub dither: bool,
+}
+
+impl Default for ToneMapOptions {
+    fn default() -> Self {
+        ToneMapOptions {
+            operator: ToneMapOperator::default(),
+            normalize_exposure: true,
+            dither: true,
+        }
+    }
+}
+
+/// Converts `img` to 8-bit RGBA. 8-bit images are converted directly, 16-bit
+/// images are (optionally dithered) down-converted, and floating point images
+/// are treated as linear light and tone-mapped.
+pub fn to_rgba8(img: DynamicImage, options: &ToneMapOptions) -> RgbaImage {
+    match img.color() {
+        ColorType::Rgb32F | ColorType::Rgba32F => tone_map(img.into_rgba32f(), options),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 if options.dither => {
+            let src = img.into_rgba16();
+            RgbaImage::from_fn(src.width(), src.height(), |x, y| {
+                let p = src.get_pixel(x, y);
+                let channel = |c: usize| quantize(p[c] as f32 / 65535.0, x, y, true);
+                Rgba([channel(0), channel(1), channel(2), quantize(p[3] as f32 / 65535.0, x, y, false)])
+            })
+        }
+        _ => img.into_rgba8(),
+    }
+}
+
+fn tone_map(src: image::Rgba32FImage, options: &ToneMapOptions) -> RgbaImage {
+    let exposure = if options.normalize_exposure {
+        exposure_scale(&src)
+    } else {
+        1.0
+    };
+    debug!("Tone mapping float image with {:?}, exposure x{}", options.operator, exposure);
+
+    RgbaImage::from_fn(src.width(), src.height(), |x, y| {
+        let p = src.get_pixel(x, y);
+        let rgb = [0, 1, 2].map(|c| sanitize(p[c]) * exposure);
+        let mapped = match options.operator {
+            ToneMapOperator::Reinhard => {
+                let luma = luminance(rgb);
+                let scale = if luma > 0.0 { 1.0 / (1.0 + luma) } else { 1.0 };
+                rgb.map(|v| v * scale)
+            }
+            ToneMapOperator::Aces => rgb.map(aces),
+        };
+        let channel = |c: usize| quantize(linear_to_srgb(mapped[c].clamp(0.0, 1.0)), x, y, options.dither);
+        Rgba([channel(0), channel(1), channel(2), quantize(sanitize(p[3]).min(1.0), x, y, false)])
+    })
+}
+
+/// Returns the factor mapping the log-average luminance of `src` to middle grey.
+fn exposure_scale(src: &image::Rgba32FImage) -> f32 {
+    let (sum, count) = src
+        .pixels()
+        .map(|p| luminance([sanitize(p[0]), sanitize(p[1]), sanitize(p[2])]))
+        .fold((0.0f64, 0u64), |(sum, count), l| (sum + (1e-4 + l as f64).ln(), count + 1));
+    if count == 0 {
+        return 1.0;
+    }
+    let average = (sum / count as f64).exp() as f32;
+    if average > 0.0 { KEY_VALUE / average } else { 1.0 }
+}
+
+/// Replaces NaN and negative values, which some HDR files contain, with zero.
+fn sanitize(v: f32) -> f32 {
+    if v.is_nan() { 0.0 } else { v.max(0.0) }
+}
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Narkowicz's fit of the ACES filmic reference curve.
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Quantizes `v` in 0.0..=1.0 to 8 bits, optionally with ordered dithering.
+fn quantize(v: f32, x: u32, y: u32, dither: bool) -> u8 {
+    let offset = if dither {
+        (BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as f32 + 0.5) / 16.0 - 0.5
+    } else {
+        0.0
+    };
+    (v * 255.0 + offset).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+
+    use super::{to_rgba8, ToneMapOperator, ToneMapOptions};
+
+    #[test]
+    fn test_hdr_is_not_clipped() {
+        // A dim scene with a very bright highlight: naive conversion clips the
+        // highlight and leaves the rest nearly black.
+        let img = ImageBuffer::from_fn(32, 32, |x, _| {
+            let v = if x == 31 { 500.0 } else { 0.002 * (x + 1) as f32 };
+            Rgba([v, v, v, 1.0f32])
+        });
+        for operator in [ToneMapOperator::Reinhard, ToneMapOperator::Aces] {
+            let options = ToneMapOptions {
+                operator,
+                ..ToneMapOptions::default()
+            };
+            let out = to_rgba8(DynamicImage::ImageRgba32F(img.clone()), &options);
+            let mid = out.get_pixel(16, 0)[0];
+            assert!((60..=220).contains(&mid), "{:?} mapped mid-tones to {}", operator, mid);
+            assert!(out.get_pixel(31, 0)[0] > mid);
+        }
+    }
+
+    #[test]
+    fn test_16bit_dither_preserves_average() {
+        // 0x7FFF lies halfway between two 8-bit levels; dithering spreads it
+        // across both so the average is kept.
+        let img = ImageBuffer::from_pixel(16, 16, Rgba([0x7FFFu16, 0x7FFF, 0x7FFF, 0xFFFF]));
+        let out = to_rgba8(DynamicImage::ImageRgba16(img), &ToneMapOptions::default());
+        let values: Vec<u32> = out.pixels().map(|p| p[0] as u32).collect();
+        let average = values.iter().sum::<u32>() as f32 / values.len() as f32;
+        assert!(values.iter().any(|&v| v != values[0]));
+        assert!((average - 127.5).abs() < 0.1, "average {}", average);
+    }
+}
@@ -0,0 +1,131 @@
+use log::{debug, warn};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Controls how strictly `.thumbnailer` definitions and the executables they
+/// reference are vetted before anything is run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrustPolicy {
+    /// Run any thumbnailer that is found (the historical behaviour).
+    #[default]
+    Disabled,
+    /// Require the definition file and the resolved executable to be owned by
+    /// root or the current user and not be group- or world-writable.
+    Verify,
+    /// Same checks as `Verify`, but definitions from the user's
+    /// `~/.local/share/thumbnailers` directory are refused outright.
+    SystemOnly,
+}
+
+/// The reason a thumbnailer definition or executable was rejected.
+#[derive(Error, Debug)]
+pub enum TrustError {
+    /// The file is owned by someone other than root or the current user.
+    #[error("{path:?} is owned by uid {uid}, expected root or the current user")]
+    UntrustedOwner { path: PathBuf, uid: u32 },
+
+    /// The file can be modified by users other than its owner.
+    #[error("{path:?} is group- or world-writable (mode {mode:o})")]
+    Writable { path: PathBuf, mode: u32 },
+
+    /// The definition lives in the user's data directory and the policy forbids that.
+    #[error("{path:?} is a user thumbnailer definition, which the trust policy refuses")]
+    UserDefinition { path: PathBuf },
+
+    /// The command in a definition's `Exec` line could not be found, so it
+    /// could not be checked.
+    #[error("{command:?} could not be resolved on PATH")]
+    Unresolved { command: String },
+}
+
+/// Checks that `path` is owned by root or the current user and is not writable
+/// by group or others. Symlinks are followed, so the file that would actually
+/// be read or executed is the one inspected.
+pub fn verify_file(path: &Path) -> Result<(), TrustError> {
+    // A missing file is not a trust problem; the caller fails on it later.
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let metadata = match fs::metadata(&resolved) {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("Could not stat {:?} for trust check: {}", resolved, e);
+            return Ok(());
+        }
+    };
+
+    let uid = metadata.uid();
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let current_uid = unsafe { libc::geteuid() };
+    if uid != 0 && uid != current_uid {
+        warn!("Rejecting {:?}: owned by uid {}", resolved, uid);
+        return Err(TrustError::UntrustedOwner { path: resolved, uid });
+    }
+
+    let mode = metadata.mode() & 0o7777;
+    if mode & 0o022 != 0 {
+        warn!("Rejecting {:?}: mode {:o} is group- or world-writable", resolved, mode);
+        return Err(TrustError::Writable { path: resolved, mode });
+    }
+
+    debug!("Trust check passed for {:?}", resolved);
+    Ok(())
+}
+
+/// Applies `policy` to a thumbnailer definition file found at `path`.
+/// `user_dir` tells whether it came from the user's own thumbnailers directory.
+pub fn verify_definition(policy: TrustPolicy, path: &Path, user_dir: bool) -> Result<(), TrustError> {
+    match policy {
+        TrustPolicy::Disabled => Ok(()),
+        TrustPolicy::SystemOnly if user_dir => Err(TrustError::UserDefinition {
+            path: path.to_path_buf(),
+        }),
+        TrustPolicy::Verify | TrustPolicy::SystemOnly => verify_file(path),
+    }
+}
+
+/// Applies `policy` to an executable already resolved on `PATH`.
+pub fn verify_executable(policy: TrustPolicy, path: &Path) -> Result<(), TrustError> {
+    match policy {
+        TrustPolicy::Disabled => Ok(()),
+        TrustPolicy::Verify | TrustPolicy::SystemOnly => verify_file(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tempfile::tempdir;
+
+    use super::{verify_definition, verify_file, TrustError, TrustPolicy};
+
+    #[test]
+    fn test_verify_file_rejects_world_writable() {
+        let dir = tempdir().expect("Failed to create temporary directory");
+        let path = dir.path().join("evil.thumbnailer");
+        fs::write(&path, "[Thumbnailer Entry]\n").unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(verify_file(&path).is_ok());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(matches!(verify_file(&path), Err(TrustError::Writable { .. })));
+    }
+
+    #[test]
+    fn test_system_only_refuses_user_definitions() {
+        let dir = tempdir().expect("Failed to create temporary directory");
+        let path = dir.path().join("user.thumbnailer");
+        fs::write(&path, "[Thumbnailer Entry]\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(verify_definition(TrustPolicy::Verify, &path, true).is_ok());
+        assert!(matches!(
+            verify_definition(TrustPolicy::SystemOnly, &path, true),
+            Err(TrustError::UserDefinition { .. })
+        ));
+        assert!(verify_definition(TrustPolicy::SystemOnly, &path, false).is_ok());
+    }
+}
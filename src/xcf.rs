@@ -0,0 +1,420 @@
+//! Flattener for GIMP XCF files. XCF stores no composite, so the visible
+//! layers are blended together with the normal mode at their opacity and
+//! offsets. Blend modes, layer masks and effects are not applied, which is
+//! close enough for a thumbnail of most images.
+
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{error::ThumbnailError, limits::DecodeLimits};
+
+/// Side length of the square tiles layer pixels are stored in.
+const TILE_SIZE: u32 = 64;
+
+/// Files with more layers than this are rejected.
+const MAX_LAYERS: usize = 4096;
+
+const PROP_END: u32 = 0;
+const PROP_COLORMAP: u32 = 1;
+const PROP_OPACITY: u32 = 6;
+const PROP_VISIBLE: u32 = 8;
+const PROP_OFFSETS: u32 = 15;
+const PROP_COMPRESSION: u32 = 17;
+const PROP_GROUP_ITEM: u32 = 29;
+const PROP_ITEM_PATH: u32 = 30;
+const PROP_FLOAT_OPACITY: u32 = 33;
+
+/// Properties larger than this are skipped without being read.
+const MAX_PROPERTY_BYTES: u32 = 1024 * 1024;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_RLE: u8 = 1;
+const COMPRESSION_ZLIB: u8 = 2;
+
+/// A layer's properties and the location of its pixels.
+#[derive(Debug, Default)]
+struct Layer {
+    width: u32,
+    height: u32,
+    kind: u32,
+    opacity: f32,
+    visible: bool,
+    offsets: (i32, i32),
+    is_group: bool,
+    path: Vec<u32>,
+    hierarchy: u64,
+}
+
+/// Reads an XCF file, flattening its visible layers onto a transparent canvas.
+pub fn flatten<R: Read + Seek>(reader: &mut R, limits: &DecodeLimits) -> Result<DynamicImage, ThumbnailError> {
+    let mut magic = [0u8; 14];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+    if &magic[..9] != b"gimp xcf " || magic[13] != 0 {
+        return Err(invalid("Not an XCF file").into());
+    }
+    let version = match &magic[9..13] {
+        b"file" => 0,
+        [b'v', digits @ ..] => std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| d.parse::<u32>().ok())
+            .ok_or_else(|| invalid("Invalid XCF version"))?,
+        _ => return Err(invalid("Invalid XCF version").into()),
+    };
+    let wide_pointers = version >= 11;
+
+    let width = read_u32(reader)?;
+    let height = read_u32(reader)?;
+    let _base_type = read_u32(reader)?;
+    if version >= 4 {
+        // Only 8 bit integer precision is supported: 0 in version 4, 100
+        // (linear) or 150 (gamma) from version 5.
+        let precision = read_u32(reader)?;
+        let is_8bit = if version == 4 { precision == 0 } else { precision == 100 || precision == 150 };
+        if !is_8bit {
+            return Err(ThumbnailError::Unsupported(format!("XCF precision {}", precision)));
+        }
+    }
+    limits.check(width, height, 4)?;
+
+    let mut compression = COMPRESSION_RLE;
+    let mut colormap = Vec::new();
+    read_properties(reader, |kind, data| match kind {
+        PROP_COMPRESSION => compression = data.first().copied().unwrap_or(COMPRESSION_RLE),
+        PROP_COLORMAP if data.len() >= 4 => {
+            let colors = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            colormap = data[4..].chunks_exact(3).take(colors).map(|c| [c[0], c[1], c[2]]).collect();
+        }
+        _ => {}
+    })?;
+
+    let mut pointers = Vec::new();
+    loop {
+        let pointer = read_pointer(reader, wide_pointers)?;
+        if pointer == 0 {
+            break;
+        }
+        if pointers.len() >= MAX_LAYERS {
+            return Err(ThumbnailError::LimitsExceeded(format!("More than {} XCF layers", MAX_LAYERS)));
+        }
+        pointers.push(pointer);
+    }
+
+    let mut layers = Vec::with_capacity(pointers.len());
+    for pointer in pointers {
+        layers.push(read_layer(reader, pointer, wide_pointers)?);
+    }
+
+    // Layers are listed top first, each group before its children.
+    let hidden_groups: Vec<&[u32]> = layers
+        .iter()
+        .filter(|l| l.is_group && !l.visible)
+        .map(|l| l.path.as_slice())
+        .collect();
+    let mut canvas = RgbaImage::new(width, height);
+    for layer in layers.iter().rev() {
+        let in_hidden_group = !layer.path.is_empty()
+            && hidden_groups
+                .iter()
+                .any(|group| layer.path.len() > group.len() && layer.path.starts_with(group));
+        if !layer.visible || layer.is_group || in_hidden_group || layer.opacity <= 0.0 {
+            continue;
+        }
+        limits.check(layer.width, layer.height, 4)?;
+        let pixels = read_layer_pixels(reader, layer, compression, &colormap, wide_pointers)?;
+        composite(&mut canvas, &pixels, layer.offsets, layer.opacity);
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+fn read_layer<R: Read + Seek>(reader: &mut R, pointer: u64, wide_pointers: bool) -> io::Result<Layer> {
+    reader.seek(SeekFrom::Start(pointer))?;
+    let mut layer = Layer {
+        width: read_u32(reader)?,
+        height: read_u32(reader)?,
+        kind: read_u32(reader)?,
+        opacity: 1.0,
+        visible: true,
+        ..Default::default()
+    };
+    let name_len = read_u32(reader)?;
+    reader.seek(SeekFrom::Current(name_len as i64))?;
+
+    read_properties(reader, |kind, data| {
+        let word = |i: usize| {
+            data.get(i * 4..i * 4 + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        match kind {
+            PROP_OPACITY => layer.opacity = word(0).map_or(1.0, |o| o.min(255) as f32 / 255.0),
+            PROP_FLOAT_OPACITY => layer.opacity = word(0).map_or(1.0, |o| f32::from_bits(o).clamp(0.0, 1.0)),
+            PROP_VISIBLE => layer.visible = word(0) != Some(0),
+            PROP_OFFSETS => layer.offsets = (word(0).unwrap_or(0) as i32, word(1).unwrap_or(0) as i32),
+            PROP_GROUP_ITEM => layer.is_group = true,
+            PROP_ITEM_PATH => layer.path = (0..data.len() / 4).filter_map(word).collect(),
+            _ => {}
+        }
+    })?;
+    layer.hierarchy = read_pointer(reader, wide_pointers)?;
+    Ok(layer)
+}
+
+/// Reads a property list up to `PROP_END`, passing every property to `visit`.
+fn read_properties<R: Read + Seek>(reader: &mut R, mut visit: impl FnMut(u32, &[u8])) -> io::Result<()> {
+    let mut data = Vec::new();
+    loop {
+        let kind = read_u32(reader)?;
+        let len = read_u32(reader)?;
+        if kind == PROP_END {
+            return Ok(());
+        }
+        if len > MAX_PROPERTY_BYTES {
+            reader.seek(SeekFrom::Current(len as i64))?;
+            continue;
+        }
+        data.resize(len as usize, 0);
+        reader.read_exact(&mut data)?;
+        visit(kind, &data);
+    }
+}
+
+/// Decodes the full resolution level of a layer to RGBA.
+fn read_layer_pixels<R: Read + Seek>(
+    reader: &mut R,
+    layer: &Layer,
+    compression: u8,
+    colormap: &[[u8; 3]],
+    wide_pointers: bool,
+) -> Result<RgbaImage, ThumbnailError> {
+    reader.seek(SeekFrom::Start(layer.hierarchy))?;
+    let _width = read_u32(reader)?;
+    let _height = read_u32(reader)?;
+    let bpp = read_u32(reader)? as usize;
+    let expected_bpp = match layer.kind {
+        0 => 3,
+        1 => 4,
+        2 | 4 => 1,
+        3 | 5 => 2,
+        kind => return Err(ThumbnailError::Unsupported(format!("XCF layer type {}", kind))),
+    };
+    if bpp != expected_bpp {
+        return Err(invalid("XCF layer bytes per pixel do not match its type").into());
+    }
+    let level = read_pointer(reader, wide_pointers)?;
+
+    reader.seek(SeekFrom::Start(level))?;
+    let (width, height) = (read_u32(reader)?, read_u32(reader)?);
+    if (width, height) != (layer.width, layer.height) {
+        return Err(invalid("XCF level size does not match its layer").into());
+    }
+    let columns = width.div_ceil(TILE_SIZE);
+    let rows = height.div_ceil(TILE_SIZE);
+    let tile_count = (columns * rows) as usize;
+    let mut tiles = Vec::with_capacity(tile_count);
+    for _ in 0..tile_count {
+        tiles.push(read_pointer(reader, wide_pointers)?);
+    }
+
+    let mut image = RgbaImage::new(width, height);
+    let mut tile = Vec::new();
+    for (index, &pointer) in tiles.iter().enumerate() {
+        let tile_x = (index as u32 % columns) * TILE_SIZE;
+        let tile_y = (index as u32 / columns) * TILE_SIZE;
+        let tile_width = TILE_SIZE.min(width - tile_x);
+        let tile_height = TILE_SIZE.min(height - tile_y);
+        let pixels = (tile_width * tile_height) as usize;
+
+        reader.seek(SeekFrom::Start(pointer))?;
+        tile.clear();
+        match compression {
+            COMPRESSION_NONE => {
+                tile.resize(pixels * bpp, 0);
+                reader.read_exact(&mut tile)?;
+            }
+            COMPRESSION_RLE => read_rle_tile(reader, pixels, bpp, &mut tile)?,
+            COMPRESSION_ZLIB => {
+                ZlibDecoder::new(&mut *reader)
+                    .take((pixels * bpp) as u64)
+                    .read_to_end(&mut tile)?;
+                tile.resize(pixels * bpp, 0);
+            }
+            other => return Err(ThumbnailError::Unsupported(format!("XCF compression {}", other))),
+        }
+
+        for (i, px) in tile.chunks_exact(bpp).enumerate() {
+            let x = tile_x + i as u32 % tile_width;
+            let y = tile_y + i as u32 / tile_width;
+            let indexed = |i: u8| colormap.get(i as usize).copied().unwrap_or([0; 3]);
+            let rgba = match layer.kind {
+                0 => [px[0], px[1], px[2], 255],
+                1 => [px[0], px[1], px[2], px[3]],
+                2 => [px[0], px[0], px[0], 255],
+                3 => [px[0], px[0], px[0], px[1]],
+                4 => {
+                    let [r, g, b] = indexed(px[0]);
+                    [r, g, b, 255]
+                }
+                _ => {
+                    let [r, g, b] = indexed(px[0]);
+                    [r, g, b, px[1]]
+                }
+            };
+            image.put_pixel(x, y, Rgba(rgba));
+        }
+    }
+    Ok(image)
+}
+
+/// Decodes an RLE tile, which stores each byte of the pixels as a separate
+/// run-length encoded stream, into interleaved pixels.
+fn read_rle_tile<R: Read>(reader: &mut R, pixels: usize, bpp: usize, out: &mut Vec<u8>) -> io::Result<()> {
+    out.resize(pixels * bpp, 0);
+    for channel in 0..bpp {
+        let mut i = 0;
+        while i < pixels {
+            let op = read_u8(reader)?;
+            let (count, repeat) = match op {
+                0..=126 => (op as usize + 1, true),
+                127 => (read_u16(reader)? as usize, true),
+                128 => (read_u16(reader)? as usize, false),
+                _ => (256 - op as usize, false),
+            };
+            if count > pixels - i {
+                return Err(invalid("XCF RLE run exceeds its tile"));
+            }
+            if repeat {
+                let value = read_u8(reader)?;
+                for j in i..i + count {
+                    out[j * bpp + channel] = value;
+                }
+            } else {
+                for j in i..i + count {
+                    out[j * bpp + channel] = read_u8(reader)?;
+                }
+            }
+            i += count;
+        }
+    }
+    Ok(())
+}
+
+/// Blends `layer` over `canvas` at `offsets` with the normal mode.
+fn composite(canvas: &mut RgbaImage, layer: &RgbaImage, offsets: (i32, i32), opacity: f32) {
+    for (x, y, src) in layer.enumerate_pixels() {
+        let cx = x as i64 + offsets.0 as i64;
+        let cy = y as i64 + offsets.1 as i64;
+        if cx < 0 || cy < 0 || cx >= canvas.width() as i64 || cy >= canvas.height() as i64 {
+            continue;
+        }
+        let dst = canvas.get_pixel_mut(cx as u32, cy as u32);
+        let src_a = src[3] as f32 / 255.0 * opacity;
+        let dst_a = dst[3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        if out_a <= 0.0 {
+            continue;
+        }
+        for c in 0..3 {
+            let value = (src[c] as f32 * src_a + dst[c] as f32 * dst_a * (1.0 - src_a)) / out_a;
+            dst[c] = value.round() as u8;
+        }
+        dst[3] = (out_a * 255.0).round() as u8;
+    }
+}
+
+fn read_pointer<R: Read>(reader: &mut R, wide: bool) -> io::Result<u64> {
+    if wide {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    } else {
+        read_u32(reader).map(u64::from)
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::flatten;
+    use crate::limits::DecodeLimits;
+
+    fn prop(kind: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend((payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// Appends a 2x1 RGBA layer filled with `color` through RLE runs.
+    fn push_layer(file: &mut Vec<u8>, color: [u8; 4], offset_x: i32, visible: bool) -> u32 {
+        let layer = file.len() as u32;
+        let hierarchy = layer + 12 + 5 + 40 + 8 + 4 + 4;
+        file.extend([2u32, 1, 1].iter().flat_map(|v| v.to_be_bytes()));
+        file.extend(1u32.to_be_bytes());
+        file.push(0);
+        file.extend(prop(8, &(visible as u32).to_be_bytes()));
+        file.extend(prop(6, &255u32.to_be_bytes()));
+        let mut offsets = offset_x.to_be_bytes().to_vec();
+        offsets.extend(0i32.to_be_bytes());
+        file.extend(prop(15, &offsets));
+        file.extend(prop(0, &[]));
+        file.extend(hierarchy.to_be_bytes());
+        file.extend(0u32.to_be_bytes());
+        assert_eq!(file.len() as u32, hierarchy);
+
+        let level = hierarchy + 16;
+        file.extend([2u32, 1, 4, level].iter().flat_map(|v| v.to_be_bytes()));
+        let tile = level + 16;
+        file.extend([2u32, 1, tile, 0].iter().flat_map(|v| v.to_be_bytes()));
+        for value in color {
+            file.extend([1, value]);
+        }
+        layer
+    }
+
+    #[test]
+    fn test_flatten_layers() {
+        let mut file = b"gimp xcf v003\0".to_vec();
+        file.extend([3u32, 1, 0].iter().flat_map(|v| v.to_be_bytes()));
+        file.extend(prop(17, &[1]));
+        file.extend(prop(0, &[]));
+        let pointers = file.len();
+        file.extend([0u8; 16]);
+
+        let top = push_layer(&mut file, [0, 0, 255, 128], 1, true);
+        let hidden = push_layer(&mut file, [0, 255, 0, 255], 0, false);
+        let bottom = push_layer(&mut file, [255, 0, 0, 255], 0, true);
+        for (i, pointer) in [top, hidden, bottom].iter().enumerate() {
+            file[pointers + i * 4..pointers + i * 4 + 4].copy_from_slice(&pointer.to_be_bytes());
+        }
+
+        let img = flatten(&mut Cursor::new(file), &DecodeLimits::default()).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (3, 1));
+        assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(1, 0).0, [127, 0, 128, 255]);
+        assert_eq!(img.get_pixel(2, 0).0, [0, 0, 255, 128]);
+    }
+}